@@ -0,0 +1,33 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use kiss_ntpd::{NtpPacket, NtpTimestamp};
+
+fuzz_target!(|data: &[u8]| {
+    let remote_addr = "192.0.2.1:40000".parse().unwrap();
+    if let Ok(packet) = NtpPacket::parse(data, remote_addr, NtpTimestamp::zero()) {
+        // anything we accept must survive a round trip, and anything we don't understand after the header is dropped
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes[..], data[..bytes.len()]);
+        assert_eq!(NtpPacket::parse(&bytes, remote_addr, NtpTimestamp::zero()).unwrap(), packet);
+    }
+});
@@ -0,0 +1,260 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    children: [Option<u32>; 2],
+    /// set when a rule ends here, true to allow
+    rule: Option<bool>,
+}
+
+/// a binary trie over address bits, so a lookup walks at most one node per prefix bit no matter how many rules there are
+#[derive(Debug, Clone)]
+struct Trie {
+    nodes: Vec<Node>,
+}
+
+impl Trie {
+    fn new() -> Trie {
+        Trie {
+            nodes: vec![Node { children: [None, None], rule: None }],
+        }
+    }
+
+    fn insert(&mut self, addr: u128, bits: u32, prefix_len: u32, allow: bool) {
+        let mut node = 0;
+        for i in 0..prefix_len {
+            let bit = ((addr >> (bits - 1 - i)) & 1) as usize;
+            node = match self.nodes[node].children[bit] {
+                Some(child) => child as usize,
+                None => {
+                    self.nodes.push(Node { children: [None, None], rule: None });
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = Some(child as u32);
+                    child
+                }
+            };
+        }
+        // deny wins if the same prefix is both allowed and denied
+        let rule = &mut self.nodes[node].rule;
+        *rule = Some(rule.unwrap_or(true) && allow);
+    }
+
+    /// the rule with the longest prefix matching addr
+    fn lookup(&self, addr: u128, bits: u32) -> Option<bool> {
+        let mut node = &self.nodes[0];
+        let mut ret = node.rule;
+        for i in 0..bits {
+            let bit = ((addr >> (bits - 1 - i)) & 1) as usize;
+            match node.children[bit] {
+                Some(child) => node = &self.nodes[child as usize],
+                None => break,
+            }
+            ret = node.rule.or(ret);
+        }
+        ret
+    }
+}
+
+/// --allow and --deny client subnets, the most specific matching rule wins
+#[derive(Debug, Clone)]
+pub struct Acl {
+    v4: Trie,
+    v6: Trie,
+    /// what happens to addresses no rule matches, deny if there are any allow rules
+    default_allow: bool,
+}
+
+/// an address or CIDR block like 10.0.0.0/8 or 2001:db8::/32, host bits are ignored
+fn parse_cidr(cidr: &str) -> Result<(IpAddr, u32), String> {
+    let (addr, prefix_len) = match cidr.split_once('/') {
+        Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+        None => (cidr, None),
+    };
+    let addr: IpAddr = addr.parse().map_err(|e| format!("invalid address in '{}': {}", cidr, e))?;
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix_len {
+        Some(prefix_len) => prefix_len
+            .parse()
+            .ok()
+            .filter(|p| *p <= bits)
+            .ok_or_else(|| format!("invalid prefix length in '{}', must be 0-{}", cidr, bits))?,
+        None => bits,
+    };
+    Ok((addr, prefix_len))
+}
+
+impl Acl {
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Acl, String> {
+        let mut acl = Acl {
+            v4: Trie::new(),
+            v6: Trie::new(),
+            default_allow: allow.is_empty(),
+        };
+        for (rules, allow) in [(allow, true), (deny, false)] {
+            for cidr in rules {
+                match parse_cidr(cidr)? {
+                    (IpAddr::V4(addr), prefix_len) => acl.v4.insert(u32::from(addr) as u128, 32, prefix_len, allow),
+                    (IpAddr::V6(addr), prefix_len) => acl.v6.insert(u128::from(addr), 128, prefix_len, allow),
+                }
+            }
+        }
+        Ok(acl)
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        // clients on a dual stack socket show up as ::ffff:a.b.c.d, match those against the IPv4 rules
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        let rule = match ip {
+            IpAddr::V4(addr) => self.v4.lookup(u32::from(addr) as u128, 32),
+            IpAddr::V6(addr) => self.v6.lookup(u128::from(addr), 128),
+        };
+        rule.unwrap_or(self.default_allow)
+    }
+}
+
+/// --client-port-filter, the source ports we answer
+#[derive(Debug, Clone)]
+pub struct PortFilter {
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl PortFilter {
+    pub fn allows(&self, port: u16) -> bool {
+        self.ranges.iter().any(|range| range.contains(&port))
+    }
+}
+
+/// comma separated ports and inclusive ranges, like 123,1024-65535
+impl FromStr for PortFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PortFilter, String> {
+        let port = |port: &str| port.trim().parse::<u16>().map_err(|e| format!("invalid port '{}': {}", port.trim(), e));
+        let mut ranges = Vec::new();
+        for range in s.split(',').filter(|r| !r.trim().is_empty()) {
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (port(start)?, port(end)?),
+                None => (port(range)?, port(range)?),
+            };
+            if start > end {
+                return Err(format!("invalid port range '{}', it ends before it starts", range.trim()));
+            }
+            ranges.push(start..=end);
+        }
+        if ranges.is_empty() {
+            return Err("no ports given".to_owned());
+        }
+        Ok(PortFilter { ranges })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(allow: &[&str], deny: &[&str]) -> Result<Acl, String> {
+        let strings = |rules: &[&str]| rules.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        Acl::new(&strings(allow), &strings(deny))
+    }
+
+    fn allows(acl: &Acl, ip: &str) -> bool {
+        acl.allows(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let acl = rules(&["10.0.0.0/8", "10.1.2.0/24"], &["10.1.0.0/16"]).unwrap();
+        assert!(allows(&acl, "10.2.3.4"));
+        assert!(!allows(&acl, "10.1.3.4"));
+        assert!(allows(&acl, "10.1.2.3"));
+        let acl = rules(&["2001:db8::/32"], &["2001:db8:1::/48"]).unwrap();
+        assert!(allows(&acl, "2001:db8::1"));
+        assert!(!allows(&acl, "2001:db8:1::1"));
+    }
+
+    #[test]
+    fn deny_wins_a_tie() {
+        let acl = rules(&["192.0.2.0/24", "0.0.0.0/0"], &["192.0.2.0/24"]).unwrap();
+        assert!(!allows(&acl, "192.0.2.1"));
+        assert!(allows(&acl, "198.51.100.1"));
+    }
+
+    #[test]
+    fn default() {
+        // everything until there's an --allow, then only what it allows
+        let open = rules(&[], &["192.0.2.1"]).unwrap();
+        assert!(allows(&open, "192.0.2.2"));
+        assert!(allows(&open, "::1"));
+        assert!(!allows(&open, "192.0.2.1"));
+        let closed = rules(&["192.0.2.1"], &[]).unwrap();
+        assert!(allows(&closed, "192.0.2.1"));
+        assert!(!allows(&closed, "192.0.2.2"));
+        assert!(!allows(&closed, "::1"));
+    }
+
+    #[test]
+    fn mapped_addresses_match_ipv4_rules() {
+        let acl = rules(&["192.0.2.0/24"], &[]).unwrap();
+        assert!(allows(&acl, "::ffff:192.0.2.1"));
+        assert!(!allows(&acl, "::ffff:198.51.100.1"));
+    }
+
+    #[test]
+    fn whole_address_space() {
+        let acl = rules(&["0.0.0.0/0"], &["::/0"]).unwrap();
+        assert!(allows(&acl, "203.0.113.7"));
+        assert!(!allows(&acl, "2001:db8::1"));
+        // host bits are ignored
+        assert!(allows(&rules(&["192.0.2.77/24"], &[]).unwrap(), "192.0.2.1"));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert!(rules(&["10.0.0.0/33"], &[]).is_err());
+        assert!(rules(&[], &["::/129"]).is_err());
+        assert!(rules(&["10.0.0.0/x"], &[]).is_err());
+        assert!(rules(&["not-an-address"], &[]).is_err());
+        assert!(rules(&[], &["10.0.0/8"]).is_err());
+        assert!(rules(&["10.0.0.0/32", "::/128"], &[]).is_ok());
+    }
+
+    #[test]
+    fn port_filter() {
+        let filter: PortFilter = "123, 1024-65535".parse().unwrap();
+        assert!(filter.allows(123));
+        assert!(filter.allows(1024));
+        assert!(filter.allows(65535));
+        assert!(!filter.allows(122));
+        assert!(!filter.allows(1023));
+        assert!(!filter.allows(0));
+        assert_eq!("2000-1000".parse::<PortFilter>().unwrap_err(), "invalid port range '2000-1000', it ends before it starts");
+        assert_eq!("".parse::<PortFilter>().unwrap_err(), "no ports given");
+        assert_eq!(" , ".parse::<PortFilter>().unwrap_err(), "no ports given");
+        assert!("ntp".parse::<PortFilter>().unwrap_err().starts_with("invalid port 'ntp'"));
+        assert!("1-x".parse::<PortFilter>().is_err());
+        assert!("65536".parse::<PortFilter>().is_err());
+    }
+}
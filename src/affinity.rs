@@ -0,0 +1,70 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! pins workers to their own cores for --cpu-affinity, so a busy server's threads stop bouncing caches between them
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// the next worker's index, counting across every bind address
+static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+
+/// the cores the process may run on, which a taskset or cgroup can have narrowed
+fn allowed_cores() -> io::Result<Vec<usize>> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((0..libc::CPU_SETSIZE as usize).filter(|&core| unsafe { libc::CPU_ISSET(core, &set) }).collect())
+}
+
+/// pins the calling thread to the Nth allowed core, N being how many workers came before it, modulo the core count,
+/// returning the core
+pub fn pin_next_worker() -> io::Result<usize> {
+    let cores = allowed_cores()?;
+    if cores.is_empty() {
+        return Err(io::Error::other("no cores to run on"));
+    }
+    let core = cores[NEXT_WORKER.fetch_add(1, Ordering::Relaxed) % cores.len()];
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(core, &mut set) };
+    if unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(core)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_one_core() {
+        let before = allowed_cores().unwrap();
+        let core = std::thread::spawn(|| {
+            let core = pin_next_worker().unwrap();
+            assert_eq!(allowed_cores().unwrap(), [core]);
+            core
+        })
+        .join()
+        .unwrap();
+        assert!(before.contains(&core));
+        // only the worker thread was pinned
+        assert_eq!(allowed_cores().unwrap(), before);
+    }
+}
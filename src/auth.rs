@@ -0,0 +1,176 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! NTP symmetric key authentication, a key ID and a digest of the key followed by the packet appended after the header
+
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestType {
+    Md5,
+    Sha1,
+}
+
+impl DigestType {
+    fn digest(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            DigestType::Md5 => Md5::new().chain_update(key).chain_update(data).finalize().to_vec(),
+            DigestType::Sha1 => Sha1::new().chain_update(key).chain_update(data).finalize().to_vec(),
+        }
+    }
+}
+
+impl FromStr for DigestType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DigestType, String> {
+        match s.to_ascii_uppercase().as_str() {
+            "M" | "MD5" => Ok(DigestType::Md5),
+            "SHA1" => Ok(DigestType::Sha1),
+            _ => Err(format!("unsupported key type '{}', must be MD5 or SHA1", s)),
+        }
+    }
+}
+
+/// keys from an ntp.keys style file
+#[derive(Debug, Clone, Default)]
+pub struct Keys {
+    keys: HashMap<u32, (DigestType, Vec<u8>)>,
+}
+
+/// like ntpd, keys of up to 20 characters are ascii and longer ones hex
+fn parse_key(key: &str) -> Result<Vec<u8>, String> {
+    if key.len() <= 20 {
+        return Ok(key.as_bytes().to_vec());
+    }
+    if !key.len().is_multiple_of(2) {
+        return Err("hex key must have an even number of digits".to_owned());
+    }
+    (0..key.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(key.get(i..i + 2).unwrap_or("x"), 16).map_err(|_| format!("invalid hex key '{}'", key)))
+        .collect()
+}
+
+impl Keys {
+    /// lines of `keyid type key`, # starts a comment
+    pub fn parse(contents: &str) -> Result<Keys, String> {
+        let mut keys = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields[..] {
+                [id, digest_type, key] => id
+                    .parse::<u32>()
+                    .map_err(|e| format!("invalid key id '{}': {}", id, e))
+                    .and_then(|id| if id == 0 { Err("key id 0 is reserved".to_owned()) } else { Ok(id) })
+                    .and_then(|id| Ok((id, digest_type.parse()?, parse_key(key)?))),
+                _ => Err("expected keyid type key".to_owned()),
+            };
+            let (id, digest_type, key) = parsed.map_err(|e| format!("line {}: {}", i + 1, e))?;
+            keys.insert(id, (digest_type, key));
+        }
+        Ok(Keys { keys })
+    }
+
+    pub fn load(path: &str) -> Result<Keys, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("could not read key file {}: {}", path, e))?;
+        Keys::parse(&contents).map_err(|e| format!("invalid key file {}: {}", path, e))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// the digest of data with this key, None if we don't have it
+    pub fn mac(&self, key_id: u32, data: &[u8]) -> Option<Vec<u8>> {
+        self.keys.get(&key_id).map(|(digest_type, key)| digest_type.digest(key, data))
+    }
+
+    /// whether digest is right for data, it must also be the right length for the key's type
+    pub fn verify(&self, key_id: u32, data: &[u8], digest: &[u8]) -> bool {
+        match self.mac(key_id, data) {
+            // compare every byte so the time taken doesn't leak how much matched
+            Some(expected) if expected.len() == digest.len() => expected.iter().zip(digest).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(keys: &Keys, id: u32) -> &[u8] {
+        &keys.keys[&id].1
+    }
+
+    #[test]
+    fn parse() {
+        let keys = Keys::parse(
+            "# ntp.keys\n\
+             1 M password # a comment\n\
+             \n\
+             2 SHA1 abcdefghijklmnopqrst\n\
+             3 sha1 0102030405060708090a0b\n",
+        )
+        .unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys.keys[&1].0, DigestType::Md5);
+        assert_eq!(key(&keys, 1), b"password");
+        // 20 characters is still ascii, 22 is hex
+        assert_eq!(keys.keys[&2].0, DigestType::Sha1);
+        assert_eq!(key(&keys, 2), b"abcdefghijklmnopqrst");
+        assert_eq!(key(&keys, 3), [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(Keys::parse("1 SHA1 0102030405060708090a0").unwrap_err(), "line 1: hex key must have an even number of digits");
+        assert!(Keys::parse("1 SHA1 zz02030405060708090a0b").is_err());
+        assert_eq!(Keys::parse("\n0 MD5 password").unwrap_err(), "line 2: key id 0 is reserved");
+        assert!(Keys::parse("x MD5 password").is_err());
+        assert!(Keys::parse("1 SHA256 password").is_err());
+        assert!(Keys::parse("1 MD5").is_err());
+        assert!(Keys::parse("1 MD5 pass word").is_err());
+    }
+
+    #[test]
+    fn verify() {
+        let keys = Keys::parse("1 MD5 password\n2 SHA1 password").unwrap();
+        for (id, len) in [(1, 16), (2, 20)] {
+            let mut digest = keys.mac(id, b"packet").unwrap();
+            assert_eq!(digest.len(), len);
+            assert!(keys.verify(id, b"packet", &digest));
+            assert!(!keys.verify(id, b"packets", &digest));
+            assert!(!keys.verify(id, b"packet", &digest[..len - 1]));
+            digest.push(0);
+            assert!(!keys.verify(id, b"packet", &digest));
+            digest.pop();
+            digest[len / 2] ^= 1;
+            assert!(!keys.verify(id, b"packet", &digest));
+        }
+        assert!(!keys.verify(3, b"packet", &[0; 16]));
+    }
+}
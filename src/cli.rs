@@ -0,0 +1,648 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2017  Miroslav Lichvar
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! the kiss-ntpd command line, everything main does, kept in the library so the binary is only fn main
+
+use std::fmt::Display;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, RwLock};
+#[cfg(unix)]
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use crate::{clock, packet, pps};
+use crate::{cookie, health, logger, metrics, privileges, query, record, signal, socket, upstream};
+#[cfg(unix)]
+use crate::{daemon, gps, Reference};
+use crate::{Args, Clock, Config, Metrics, NtpServer, Source, TokenBucket};
+
+fn exit_error(msg: impl Display) -> ! {
+    error!("{}", msg);
+    std::process::exit(1);
+}
+
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:123";
+
+/// the positional bind addresses or the config file's, with default ports filled in
+fn parse_bind_addresses(args: Args) -> Result<(Vec<String>, Source), String> {
+    let mut source = if args.has_positional() { Source::CommandLine } else { Source::ConfigFile };
+    let mut bind_addresses: Vec<String> = args.remaining()?.iter().map(|a| socket::with_default_port(a)).collect();
+    if bind_addresses.is_empty() {
+        bind_addresses.push(DEFAULT_BIND_ADDRESS.to_owned());
+        source = Source::Default;
+    }
+    Ok((bind_addresses, source))
+}
+
+/// on SIGHUP, parses everything again the way startup did and swaps the result in for the servers to pick up
+#[cfg(unix)]
+fn reload(argv: &[String], bind_addresses: &[String], live_config: &RwLock<Arc<Config>>, reference: &Mutex<Reference>) -> Result<(), String> {
+    let mut args = Args::new(argv.to_vec());
+    let mut config = Config::from_args(&mut args)?;
+    if parse_bind_addresses(args)?.0 != bind_addresses {
+        warn!("bind addresses can't be changed without a restart, ignoring");
+    }
+    if !socket::REUSEPORT_SUPPORTED {
+        config.reuseport = false;
+    }
+    let old = live_config.read().expect("config poisoned").clone();
+    config.keep_startup_settings(&old);
+    // upstream owns these once it's running, and PPS or GPS while they have a signal, falling back to whatever is configured then
+    {
+        let mut reference = reference.lock().expect("reference poisoned");
+        let primary = (config.pps.is_some() || config.gps.is_some()) && reference.synced;
+        if config.upstream.is_none() && !primary {
+            reference.stratum = config.stratum;
+            reference.ref_id = config.ref_id;
+            reference.root_delay = config.root_delay;
+            reference.root_dispersion = config.root_dispersion;
+        }
+    }
+    logger::set_quiet(config.quiet);
+    logger::set_level(config.log_level);
+    *live_config.write().expect("config poisoned") = Arc::new(config);
+    Ok(())
+}
+
+/// everything kiss-ntpd does, reading std::env::args
+pub fn main() {
+    let argv: Vec<String> = std::env::args().skip(1).collect();
+    if argv.first().map(String::as_str) == Some("query") {
+        match &argv[1..] {
+            [server] => query::run(server).unwrap_or_else(|e| exit_error(e)),
+            _ => exit_error("usage: kiss-ntpd query <host[:port]>"),
+        }
+        return;
+    }
+    let mut args = Args::new(argv.clone());
+
+    if args.flag(&["-h", "--help"]).unwrap_or_default() {
+        println!(
+            r#"usage: kiss-ntpd [options...] [bind_addresses...]
+       kiss-ntpd query <host[:port]>    ask a server the time once and print its answer and our offset from it
+ -h, --help                      print this usage text
+ -V, -v, --version               Show version number then quit
+ -c, --config <path>             TOML file of options keyed by their long names, like stratum = 2 or bind = ["[::]:123"],
+                                 command line options override it and it overrides environment variables
+                                 env: KISS_NTPD_CONFIG
+ --check                         parse the configuration, load every file it names and bind every address as startup would,
+                                 then exit, 0 if the daemon would start, for CI or ExecStartPre=
+ -d, --debug                     print debug output, same as --log-level debug
+ --log-level <level>             error, warn, info, debug or trace, trace adds a dump of every request and response,
+                                 default info
+                                 env: KISS_NTPD_LOG_LEVEL
+ -q, --quiet                     only print warnings and errors, plus debug output with --debug
+ --log-dropped                   print the source of every dropped mode 6/7 control query and request refused by --allow/--deny
+                                 --min-version/--max-version or --allow-modes
+ --verbose-errors                warn about every datagram we couldn't parse or answer, with the address it came from
+ --idle-timeout <secs>           exit once no request has arrived for this long, for socket activated or test servers,
+                                 default never
+                                 env: KISS_NTPD_IDLE_TIMEOUT
+ --stats <secs>                  print p50/p95/p99 of the time from receiving a request to sending its response every secs,
+                                 each report covers only the responses since the last, default every 60 with --debug
+                                 env: KISS_NTPD_STATS
+ --stats-interval <secs>         print the requests, responses and errors since the last summary and the request rate every secs
+                                 env: KISS_NTPD_STATS_INTERVAL
+ --log-format <text|json>        format of the per request debug output, json prints one object per line, default text
+                                 env: KISS_NTPD_LOG_FORMAT
+ --syslog                        log to syslog with the daemon facility instead of stdout/stderr
+ --stratum <1-15>                stratum to advertise to clients, default 8
+                                 env: KISS_NTPD_STRATUM
+ --refid <refid>                 reference id to advertise, at stratum 1 up to 4 ascii characters naming the source like GPS,
+                                 above it the IPv4 address of our upstream like 192.0.2.1, which --upstream fills in itself
+                                 env: KISS_NTPD_REFID
+ --force-refid                   allow an ascii --refid above stratum 1, like LOCL for an undisciplined local clock
+ --root-delay <secs>             round trip delay to the primary reference to advertise when serving the system clock, like
+                                 0.01 if it's kept by a server 10ms away, default 0
+                                 env: KISS_NTPD_ROOT_DELAY
+ --root-dispersion <secs>        how far off the system clock could be to advertise when serving it, default 0
+                                 env: KISS_NTPD_ROOT_DISPERSION
+ --precision <log2 seconds>      clock precision to advertise, like -20 for roughly a microsecond, default measured at startup
+                                 env: KISS_NTPD_PRECISION
+ --leap <none|add|del>           leap second to announce, default none
+                                 env: KISS_NTPD_LEAP
+ --leap-file <path>              leap-seconds.list to announce leap seconds from in the 24 hours before they happen,
+                                 like /usr/share/zoneinfo/leap-seconds.list, --leap overrides it
+                                 env: KISS_NTPD_LEAP_FILE
+ --leap-smear <secs>             instead of announcing leap seconds from --leap-file, spread each one over a window this
+                                 long centered on it, like 86400 for noon to noon, so clients never see a step, the leap
+                                 indicator stays 0 so it can't be used with --leap, don't mix smeared and unsmeared servers
+                                 env: KISS_NTPD_LEAP_SMEAR
+ --clock <realtime|tai>          clock to serve time from, tai reads CLOCK_TAI and takes off the kernel's TAI - UTC offset,
+                                 which ntpd or chrony set from a leap seconds file, so the time served follows that table
+                                 rather than however the system clock is stepped through a leap, falls back to realtime
+                                 with a warning where CLOCK_TAI or the offset isn't available, linux only, default realtime
+                                 env: KISS_NTPD_CLOCK
+ --poll <log2 seconds>           poll interval we suggest to clients, within --min-poll and --max-poll, default 6
+                                 env: KISS_NTPD_POLL
+ --echo-poll                     send clients back their own poll interval, clamped to --min-poll and --max-poll, instead of --poll
+ --min-poll <log2 seconds>       lowest poll interval we suggest to clients, default 4
+                                 env: KISS_NTPD_MIN_POLL
+ --max-poll <log2 seconds>       highest poll interval we suggest to clients, default 17
+                                 env: KISS_NTPD_MAX_POLL
+ --require-cookie                only answer clients that echo a cookie we sent their address, against blind spoofing, a client
+                                 sends a zeroed 28 byte extension field of type 0xF00C, gets a CKIE Kiss-o'-Death with the
+                                 cookie in that field, then includes it in every request, without the field only a bare CKIE
+ --allow-modes <modes>           comma separated request modes to answer, 1 for symmetric active peers and 3 for clients,
+                                 others are dropped, default 1,3
+                                 env: KISS_NTPD_ALLOW_MODES
+ --min-version <1-4>             drop requests from older NTP versions, like 3 to refuse legacy v1/v2 clients, default 1
+                                 env: KISS_NTPD_MIN_VERSION
+ --max-version <1-4>             drop requests from newer NTP versions, default 4
+                                 env: KISS_NTPD_MAX_VERSION
+ --force-mode <0-7>              send every reply with this mode instead of 4, or 2 to symmetric peers, for testing how clients
+                                 handle unexpected modes or for legacy clients that insist on one, never point it at another server
+                                 env: KISS_NTPD_FORCE_MODE
+ --rate-limit <packets/sec>      per client IP limit, clients over it get a RATE Kiss-o'-Death reply, default 0 (unlimited)
+                                 env: KISS_NTPD_RATE_LIMIT
+ --rate-limit-table-size <n>     most clients --rate-limit tracks at once, the one heard from least recently is forgotten to
+                                 make room, so spoofed source addresses can't use up memory, default 100000
+                                 env: KISS_NTPD_RATE_LIMIT_TABLE_SIZE
+ --max-packet-rate <packets/sec> most responses sent per second across every client and bind address, the rest are
+                                 dropped unanswered, a coarse safety valve for the network, default 0 (unlimited)
+                                 env: KISS_NTPD_MAX_PACKET_RATE
+ --allow <cidr>                  only answer clients in this subnet, like 10.0.0.0/8 or 2001:db8::/32, repeatable
+                                 env: KISS_NTPD_ALLOW, comma separated
+ --deny <cidr>                   never answer clients in this subnet, repeatable, the most specific --allow or --deny wins
+                                 env: KISS_NTPD_DENY, comma separated
+ --client-port-filter <ports>    only answer requests from these source ports, like 1024-65535 to refuse packets spoofed to
+                                 reflect off us from port 123, comma separated ports and ranges, default any, checked
+                                 before and apart from the rule that NTPv1 mode 0 requests are never answered from port 123,
+                                 symmetric peers send from 123 so allow it too if you have them
+                                 env: KISS_NTPD_CLIENT_PORT_FILTER
+ --keyfile <path>                ntp.keys style file of "keyid MD5|SHA1 key" lines, requests with a MAC from one of these
+                                 keys get a signed response, others a crypto-NAK, unauthenticated requests are still answered
+                                 env: KISS_NTPD_KEYFILE
+ --nts-key <path>                file of 64 hex digits, the AES-SIV-CMAC-256 master key for NTS cookies, requests carrying
+                                 a cookie sealed with it are authenticated and get fresh cookies back, others an NTS NAK,
+                                 NTS-KE is not built in, cookies must come from a key exchange server sharing this key
+                                 env: KISS_NTPD_NTS_KEY
+ -u, --user <user>               user name or uid to switch to after binding, unix only
+                                 env: KISS_NTPD_USER
+ -g, --group <group>             group name or gid to switch to after binding, default the user's primary group, unix only
+                                 env: KISS_NTPD_GROUP
+ --chroot <dir>                  chroot into this directory after binding and before switching user, nothing is read from
+                                 the filesystem after startup so an empty directory will do, unix only
+                                 env: KISS_NTPD_CHROOT
+ --daemonize                     fork into the background after binding, in a new session with output to /dev/null, unix only
+ --logfile <path>                append a daemon's output to this file instead, with --daemonize
+                                 env: KISS_NTPD_LOGFILE
+ --pidfile <path>                write our pid to this file, refusing to start if it names a running process, and remove it on
+                                 clean shutdown if --chroot and --user leave it reachable, unix only
+                                 env: KISS_NTPD_PIDFILE
+ -t, --threads <threads>         threads receiving on each bind address, default the number of CPUs
+                                 env: KISS_NTPD_THREADS
+ --reuseport                     give each thread its own SO_REUSEPORT socket rather than sharing one, where supported
+ --bind-device <interface>       only hear from and answer on this network interface, like eth0, whatever the bind address,
+                                 with SO_BINDTODEVICE, linux only
+                                 env: KISS_NTPD_BIND_DEVICE
+ --cpu-affinity                  pin each worker thread to its own CPU core, round robin over the cores we may run on, linux only
+ --queue-depth <n>               one thread only receives, handing requests to --threads responders through a queue of up to
+                                 n so one slow response can't hold up the rest, the oldest is dropped when it's full,
+                                 default 0 for every thread receiving its own
+                                 env: KISS_NTPD_QUEUE_DEPTH
+ --rcvbuf <bytes>                SO_RCVBUF for each socket, raise it if bursts overflow the kernel's queue, the size granted is
+                                 logged, linux doubles it and caps it at net.core.rmem_max
+                                 env: KISS_NTPD_RCVBUF
+ --sndbuf <bytes>                SO_SNDBUF for each socket, capped at net.core.wmem_max on linux
+                                 env: KISS_NTPD_SNDBUF
+ --dscp <value>                  DSCP to mark replies with so QoS doesn't queue them behind bulk traffic, 0-63 or a name like
+                                 EF, CS6 or AF41, sets IP_TOS or IPV6_TCLASS, default the system's, usually 0
+                                 env: KISS_NTPD_DSCP
+ --batch-size <packets>          datagrams to receive and send per syscall with recvmmsg/sendmmsg, 1 disables, default 32 on linux
+                                 env: KISS_NTPD_BATCH_SIZE
+ --kernel-timestamps             use kernel SO_TIMESTAMPING receive timestamps rather than reading the clock after recv, linux only
+ --interleaved                   answer interleaved mode requests with when the previous response was actually sent, as chrony
+                                 clients with xleave ask for, this remembers two timestamps per client
+ --record <path>                 write every datagram received, with its source and receive timestamp, to this file for --replay
+                                 env: KISS_NTPD_RECORD
+ --replay <path>                 answer the datagrams in a --record file with the clock set to when each arrived, printing what
+                                 we'd have sent instead of serving, to reproduce a problem offline
+                                 env: KISS_NTPD_REPLAY
+ --metrics-addr <host:port>      serve Prometheus metrics over HTTP at /metrics on this address
+                                 env: KISS_NTPD_METRICS_ADDR
+ --metrics-format <prometheus|cbor>
+                                 what /metrics answers with unless the scraper's Accept header asks for text/plain or
+                                 application/cbor, cbor is a map of the same counter names for small collectors, default prometheus
+                                 env: KISS_NTPD_METRICS_FORMAT
+ --health-addr <host:port>       for load balancers, answer every TCP connection on this address with OK while serving synchronized
+                                 time, DEGRADED while refid is INIT or STEP, the clock is unsynchronized, --max-ref-age has passed
+                                 or a socket has failed
+                                 env: KISS_NTPD_HEALTH_ADDR
+ --freeze-time <rfc3339>         for testing clients only, serve this instant, like 2038-01-19T03:14:08Z, instead of the system
+                                 clock, to see how a client copes with a server that's wildly off
+                                 env: KISS_NTPD_FREEZE_TIME
+ --freeze-advance                with --freeze-time, let the frozen time move on as real time passes rather than stand still
+ --upstream <host:port>          NTP server to poll, at the first address it resolves to, the best recent offset from it is applied to the times we serve
+                                 and our stratum and refid follow it, until its first reply, or after a jump of more than 128ms,
+                                 we send stratum 0 with refid INIT or STEP and leap 3 like ntpd
+                                 env: KISS_NTPD_UPSTREAM
+ --peer <host:port>              machine to poll in symmetric active mode, we serve the average of our clock and every peer
+                                 heard from in the last 4 polls, so peers doing the same meet in the middle, repeatable
+                                 env: KISS_NTPD_PEER, comma separated
+ --upstream-interval <secs>      seconds between upstream or peer polls, default 64
+                                 env: KISS_NTPD_UPSTREAM_INTERVAL
+ --max-ref-age <secs>            once --upstream, --peer, --pps or --gps hasn't updated our time for this long, advertise leap
+                                 indicator 3 and a 16 second dispersion so clients stop trusting us, default 0 (never)
+                                 env: KISS_NTPD_MAX_REF_AGE
+ --check-sync                    every 16 seconds ask the kernel with adjtimex whether the system clock is synchronized, and while
+                                 it isn't advertise leap indicator 3 and a 16 second dispersion so clients ignore us, linux only
+ --pps <device>                  PPS device like /dev/pps0, each pulse puts the served time on the top of the second and makes
+                                 us stratum 1 with refid PPS, the clock must already be within half a second, we fall back to
+                                 the system clock if it can't be opened or stops pulsing, linux only
+                                 env: KISS_NTPD_PPS
+ --gps <device>                  serial GPS receiver like /dev/ttyUSB0 sending NMEA RMC sentences, alone it makes us stratum 1
+                                 with refid GPS to within about half a second, with --pps it numbers the pulses so the clock
+                                 needn't be close already, we fall back to the system clock without a fix, unix only
+                                 env: KISS_NTPD_GPS
+ --gps-baud <rate>               serial speed for --gps, default 9600
+                                 env: KISS_NTPD_GPS_BAUD
+ --broadcast <addr[:port]>       periodically send mode 5 broadcasts to this IPv4 address, like 192.168.1.255, port default 123
+                                 env: KISS_NTPD_BROADCAST
+ --multicast                     join the NTP multicast group, 224.0.1.1 or ff05::101, on each bind address so manycast clients
+                                 find us, and periodically announce to it, bind to 0.0.0.0 or [::] to receive group traffic
+ --broadcast-interval <secs>     seconds between broadcast and multicast announcements, default 64
+                                 env: KISS_NTPD_BROADCAST_INTERVAL
+
+ bind_addresses are host:port, the port defaults to 123 when left off, like 192.168.1.5 or [::1],
+ a hostname is bound at every address it resolves to
+ SIGHUP reloads the config file, environment and the files they name, settings only used at startup like bind_addresses,
+ --threads or --user keep their old values, and after --chroot the files must be found inside it
+ If no bind_addresses supplied, defaults to {}
+        "#,
+            DEFAULT_BIND_ADDRESS
+        );
+        return;
+    } else if args.flag(&["-V", "-v", "--version"]).unwrap_or_default() {
+        println!("kiss-ntpd {} ", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    let mut config = Config::from_args(&mut args).unwrap_or_else(|e| exit_error(e));
+    logger::set_quiet(config.quiet);
+    logger::set_level(config.log_level);
+    if config.syslog {
+        if let Err(e) = logger::init_syslog() {
+            exit_error(format!("could not connect to syslog: {}", e));
+        }
+    }
+    if config.reuseport && !socket::REUSEPORT_SUPPORTED {
+        warn!("--reuseport is not supported on this platform, sharing one socket between threads");
+        config.reuseport = false;
+    }
+    if !config.test_delay.is_zero() {
+        warn!("delaying every response by {:?} with --test-delay-ms, this is only for testing clients", config.test_delay);
+    }
+    if let Some(freeze_time) = config.freeze_time {
+        warn!("serving {} instead of the system clock with --freeze-time, this is only for testing clients", freeze_time);
+    }
+    let (bind_addresses, bind_source) = parse_bind_addresses(args).unwrap_or_else(|e| exit_error(e));
+
+    // offline, nothing is bound
+    if let Some(path) = config.replay.clone() {
+        if let Err(e) = record::replay(&path, config) {
+            exit_error(format!("could not replay {}: {}", path, e));
+        }
+        return;
+    }
+
+    info!(
+        "binding {} ({}), stratum {} ({}), refid {} ({}), rate limit {} ({}), threads {} ({})",
+        bind_addresses.join(", "),
+        bind_source,
+        config.stratum,
+        config.source("stratum"),
+        query::ref_id(config.stratum, u32::from_be_bytes(config.ref_id)),
+        config.source("refid"),
+        config.rate_limit,
+        config.source("rate-limit"),
+        config.threads,
+        config.source("threads")
+    );
+
+    if config.debug {
+        for bind_address in &bind_addresses {
+            debug!("bind: {} ({})", bind_address, bind_source);
+        }
+        debug!("log level: {:?} ({})", config.log_level, config.source("log-level"));
+        debug!("stratum: {} ({})", config.stratum, config.source("stratum"));
+        debug!("refid: {} ({})", query::ref_id(config.stratum, u32::from_be_bytes(config.ref_id)), config.source("refid"));
+        debug!("root delay: {} ({})", config.root_delay, config.source("root-delay"));
+        debug!("root dispersion: {} ({})", config.root_dispersion, config.source("root-dispersion"));
+        debug!("precision: {} ({})", config.precision, config.source("precision"));
+        debug!("leap: {:?} ({})", config.leap, config.source("leap"));
+        debug!("clock: {:?} ({})", config.clock, config.source("clock"));
+        if let Some(leap_smear) = config.leap_smear {
+            debug!("leap smear: {} seconds ({})", leap_smear, config.source("leap-smear"));
+        }
+        if let Some(leap_seconds) = &config.leap_seconds {
+            debug!("leap seconds file expires: {:?} ({})", leap_seconds.expires, config.source("leap-file"));
+        }
+        debug!("poll: {} ({}), echo poll: {} ({})", config.poll, config.source("poll"), config.echo_poll, config.source("echo-poll"));
+        debug!("min poll: {} ({})", config.min_poll, config.source("min-poll"));
+        debug!("max poll: {} ({})", config.max_poll, config.source("max-poll"));
+        debug!("require cookie: {} ({})", config.require_cookie, config.source("require-cookie"));
+        debug!("allow modes: {:?} ({})", config.allow_modes, config.source("allow-modes"));
+        debug!("min version: {} ({})", config.min_version, config.source("min-version"));
+        debug!("max version: {} ({})", config.max_version, config.source("max-version"));
+        debug!("force mode: {:?} ({})", config.force_mode, config.source("force-mode"));
+        debug!(
+            "rate limit: {} ({}) for up to {} clients ({})",
+            config.rate_limit,
+            config.source("rate-limit"),
+            config.rate_limit_table_size,
+            config.source("rate-limit-table-size")
+        );
+        debug!("max packet rate: {} ({})", config.max_packet_rate, config.source("max-packet-rate"));
+        if let Some(port_filter) = &config.port_filter {
+            debug!("client port filter: {:?} ({})", port_filter, config.source("client-port-filter"));
+        }
+        if let Some(keys) = &config.keys {
+            debug!("symmetric keys: {} ({})", keys.len(), config.source("keyfile"));
+        }
+        debug!("nts: {} ({})", config.nts.is_some(), config.source("nts-key"));
+        debug!("threads: {} ({})", config.threads, config.source("threads"));
+        debug!("reuseport: {} ({})", config.reuseport, config.source("reuseport"));
+        debug!("bind device: {:?} ({})", config.bind_device, config.source("bind-device"));
+        debug!("cpu affinity: {} ({})", config.cpu_affinity, config.source("cpu-affinity"));
+        debug!("queue depth: {} ({})", config.queue_depth, config.source("queue-depth"));
+        debug!("rcvbuf: {:?} ({}), sndbuf: {:?} ({})", config.rcvbuf, config.source("rcvbuf"), config.sndbuf, config.source("sndbuf"));
+        debug!("dscp: {:?} ({})", config.dscp, config.source("dscp"));
+        debug!("batch size: {} ({})", config.batch_size, config.source("batch-size"));
+        debug!("kernel timestamps: {} ({})", config.kernel_timestamps, config.source("kernel-timestamps"));
+        debug!("interleaved: {} ({})", config.interleaved, config.source("interleaved"));
+        debug!("record: {:?} ({})", config.record, config.source("record"));
+        if let Some(broadcast) = config.broadcast {
+            debug!(
+                "broadcast: {} ({}) every {:?} ({})",
+                broadcast,
+                config.source("broadcast"),
+                config.broadcast_interval,
+                config.source("broadcast-interval")
+            );
+        }
+        debug!("multicast: {} ({})", config.multicast, config.source("multicast"));
+        if let Some(upstream) = config.upstream {
+            debug!(
+                "upstream: {} ({}) every {:?} ({})",
+                upstream,
+                config.source("upstream"),
+                config.upstream_interval,
+                config.source("upstream-interval")
+            );
+        }
+        for peer in &config.peers {
+            debug!(
+                "peer: {} ({}) every {:?} ({})",
+                peer,
+                config.source("peer"),
+                config.upstream_interval,
+                config.source("upstream-interval")
+            );
+        }
+        debug!("max reference age: {:?} ({})", config.max_ref_age, config.source("max-ref-age"));
+        debug!("check sync: {} ({})", config.check_sync, config.source("check-sync"));
+        debug!("pps: {:?} ({})", config.pps, config.source("pps"));
+        debug!("gps: {:?} ({}) at {} baud ({})", config.gps, config.source("gps"), config.gps_baud, config.source("gps-baud"));
+        debug!("daemonize: {} ({})", config.daemonize, config.source("daemonize"));
+        debug!("log file: {:?} ({})", config.logfile, config.source("logfile"));
+        debug!("pid file: {:?} ({})", config.pidfile, config.source("pidfile"));
+    }
+
+    if config.clock == Clock::Tai {
+        #[cfg(target_os = "linux")]
+        match clock::tai_utc() {
+            Ok(_) => packet::set_clock(Clock::Tai),
+            Err(e) => warn!("can't serve time from CLOCK_TAI, using CLOCK_REALTIME: {}", e),
+        }
+        #[cfg(not(target_os = "linux"))]
+        warn!("--clock tai is only supported on linux, using the realtime clock");
+    }
+
+    // before binding, so a second instance doesn't get as far as sharing a SO_REUSEPORT address with the first
+    #[cfg(unix)]
+    let pidfile = config
+        .pidfile
+        .as_ref()
+        .filter(|_| !config.check)
+        .map(|path| daemon::Pidfile::create(path).unwrap_or_else(|e| exit_error(e)));
+
+    // bind everything before dropping privileges, we might need root for port 123
+    let metrics = Arc::new(Metrics::default());
+    let reference = Arc::new(Mutex::new(config.system_reference()));
+    // a hostname gets a server for every address it resolves to
+    let live_config = Arc::new(RwLock::new(Arc::new(config.clone())));
+    let mut servers: Vec<NtpServer> = Vec::new();
+    let throttle = Arc::new(Mutex::new(TokenBucket::new(config.max_packet_rate)));
+    for addr in &bind_addresses {
+        for resolved in socket::resolve_all(addr).unwrap_or_else(|e| exit_error(format!("failed to resolve {}: {}", addr, e))) {
+            let server = NtpServer::with_state(&resolved, live_config.clone(), metrics.clone(), reference.clone(), throttle.clone())
+                .unwrap_or_else(|e| exit_error(format!("failed to bind {}: {}", resolved, e)));
+            servers.push(server);
+        }
+    }
+    // one secret for every server, so a client's cookie is good whichever address it asks
+    if config.require_cookie {
+        let cookies = Arc::new(cookie::Cookies::new().unwrap_or_else(|e| exit_error(e)));
+        for server in &mut servers {
+            server.cookies = Some(cookies.clone());
+        }
+    }
+    let mut announcers = Vec::new();
+    // broadcasts go out from the first IPv4 bind address, so clients see them come from its port
+    if let Some(addr) = config.broadcast {
+        let server = servers
+            .iter()
+            .find(|s| s.local_addr().is_ipv4())
+            .unwrap_or_else(|| exit_error("--broadcast needs an IPv4 bind address"));
+        let socket = server.socket().try_clone().unwrap_or_else(|e| exit_error(format!("could not clone socket for broadcast: {}", e)));
+        if let Err(e) = socket.set_broadcast(true) {
+            exit_error(format!("could not enable broadcast: {}", e));
+        }
+        announcers.push((server.with_socket(socket), addr));
+    }
+    if config.multicast {
+        for server in &servers {
+            let group = server.join_multicast().unwrap_or_else(|e| exit_error(format!("could not join multicast group: {}", e)));
+            let socket = server.socket().try_clone().unwrap_or_else(|e| exit_error(format!("could not clone socket for multicast: {}", e)));
+            announcers.push((server.with_socket(socket), group));
+        }
+    }
+    let metrics_listener = config
+        .metrics_addr
+        .as_ref()
+        .map(|addr| TcpListener::bind(addr).unwrap_or_else(|e| exit_error(format!("failed to bind metrics address {}: {}", addr, e))));
+    let health_listener = config
+        .health_addr
+        .as_ref()
+        .map(|addr| TcpListener::bind(addr).unwrap_or_else(|e| exit_error(format!("failed to bind health address {}: {}", addr, e))));
+
+    // past here only daemonizing and dropping privileges can fail, and those for reasons this covers
+    if config.check {
+        if let Err(e) = privileges::check_privileges(config.user.as_deref(), config.group.as_deref(), config.chroot.as_deref()) {
+            exit_error(format!("failed to drop privileges: {}", e));
+        }
+        info!("configuration ok");
+        return;
+    }
+
+    // before the chroot so the recording is where it was asked for
+    if let Some(path) = &config.record {
+        let recorder = Arc::new(record::Recorder::create(path).unwrap_or_else(|e| exit_error(e)));
+        warn!("recording every datagram received to {}", path);
+        for server in &mut servers {
+            server.recorder = Some(recorder.clone());
+        }
+    }
+
+    // still single threaded, and before the chroot so the log file is where it was asked for
+    #[cfg(unix)]
+    if config.daemonize {
+        if let Err(e) = daemon::daemonize(config.logfile.as_deref()) {
+            exit_error(format!("failed to daemonize: {}", e));
+        }
+    }
+    #[cfg(unix)]
+    let pidfile = pidfile.map(|mut pidfile| {
+        pidfile.write_pid().unwrap_or_else(|e| exit_error(e));
+        pidfile
+    });
+
+    // the device is likely only readable by root
+    #[cfg(target_os = "linux")]
+    let pps = config.pps.as_ref().and_then(|path| match pps::Pps::open(path) {
+        Ok(pps) => Some(pps),
+        Err(e) => {
+            warn!("{}, serving the system clock", e);
+            None
+        }
+    });
+
+    #[cfg(unix)]
+    let gps = config.gps.as_ref().and_then(|path| match gps::Gps::open(path, config.gps_baud) {
+        Ok(gps) => Some(gps),
+        Err(e) => {
+            warn!("{}, serving the system clock", e);
+            None
+        }
+    });
+
+    if let Err(e) = privileges::drop_privileges(config.user.as_deref(), config.group.as_deref(), config.chroot.as_deref()) {
+        exit_error(format!("failed to drop privileges: {}", e));
+    }
+
+    if let Err(e) = signal::install_handlers() {
+        exit_error(format!("failed to install signal handlers: {}", e));
+    }
+
+    #[cfg(unix)]
+    {
+        let (live_config, reference) = (live_config.clone(), reference.clone());
+        std::thread::spawn(move || {
+            while !signal::shutdown_requested() {
+                std::thread::sleep(Duration::from_secs(1));
+                if signal::take_reload_request() {
+                    match reload(&argv, &bind_addresses, &live_config, &reference) {
+                        Ok(()) => info!("reloaded configuration"),
+                        Err(e) => error!("could not reload configuration, keeping the old one: {}", e),
+                    }
+                }
+            }
+        });
+    }
+
+    if let Some(timeout) = config.idle_timeout {
+        let metrics = metrics.clone();
+        std::thread::spawn(move || metrics::exit_when_idle(metrics, timeout));
+    }
+
+    if let Some(interval) = config.stats {
+        let metrics = metrics.clone();
+        std::thread::spawn(move || metrics::report_latency(metrics, interval));
+    }
+
+    if let Some(interval) = config.stats_interval {
+        let metrics = metrics.clone();
+        std::thread::spawn(move || metrics::report_summary(metrics, interval));
+    }
+
+    if let Some(listener) = health_listener {
+        let (live_config, metrics, reference, clock) = (live_config.clone(), metrics.clone(), reference.clone(), config.time_source());
+        std::thread::spawn(move || health::serve(listener, live_config, metrics, reference, clock));
+    }
+
+    if let Some(listener) = metrics_listener {
+        let format = config.metrics_format;
+        std::thread::spawn(move || metrics::serve(listener, metrics, format));
+    }
+
+    if let Some(upstream) = config.upstream {
+        info!("polling upstream {}", upstream);
+        let (interval, reference, precision, debug) = (config.upstream_interval, reference.clone(), config.precision, config.debug);
+        std::thread::spawn(move || upstream::run(upstream, interval, reference, precision, debug));
+    }
+
+    if !config.peers.is_empty() {
+        info!("polling peers {}", config.peers.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", "));
+        let (peers, interval, reference, precision, debug) = (config.peers.clone(), config.upstream_interval, reference.clone(), config.precision, config.debug);
+        std::thread::spawn(move || upstream::run_peers(peers, interval, reference, precision, debug));
+    }
+
+    // with PPS the GPS only numbers the pulses and the PPS thread owns the reference
+    #[cfg(unix)]
+    let gps_fix = gps.map(|gps| {
+        let shared = gps::SharedFix::default();
+        #[cfg(target_os = "linux")]
+        let gps_reference = if pps.is_some() { None } else { Some(reference.clone()) };
+        #[cfg(not(target_os = "linux"))]
+        let gps_reference = Some(reference.clone());
+        let (fix, live_config, debug) = (shared.clone(), live_config.clone(), config.debug);
+        std::thread::spawn(move || gps::run(gps, fix, gps_reference, live_config, debug));
+        shared
+    });
+    // only PPS reads the fix, elsewhere the GPS thread updates the reference itself
+    #[cfg(all(unix, not(target_os = "linux")))]
+    let _gps_fix = gps_fix;
+
+    #[cfg(target_os = "linux")]
+    if let Some(pps) = pps {
+        let (live_config, precision, debug) = (live_config.clone(), config.precision, config.debug);
+        let reference = reference.clone();
+        std::thread::spawn(move || pps::run(pps, gps_fix, reference, precision, live_config, debug));
+    }
+
+    #[cfg(target_os = "linux")]
+    if config.check_sync {
+        let reference = reference.clone();
+        std::thread::spawn(move || clock::run(reference));
+    }
+
+    let announcers: Vec<_> = announcers.into_iter().map(|(server, addr)| std::thread::spawn(move || server.announce(addr))).collect();
+
+    let threads: Vec<_> = servers.into_iter().map(|server| std::thread::spawn(|| server.run())).collect();
+    for thread in threads.into_iter().chain(announcers) {
+        thread.join().expect("server thread panicked");
+    }
+
+    info!("shutting down");
+    #[cfg(unix)]
+    if let Some(pidfile) = pidfile {
+        pidfile.remove();
+    }
+}
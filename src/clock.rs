@@ -0,0 +1,86 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! asks the kernel whether the system clock is synchronized, so we don't confidently serve time nobody has set,
+//! and reads CLOCK_TAI for --clock tai
+
+use crate::signal;
+use crate::upstream::Reference;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// how often the clock state is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(16);
+
+/// whether adjtimex says nothing is disciplining the clock, it reports TIME_ERROR with STA_UNSYNC until something like
+/// ntpd or chrony sets it, and again if they stop
+pub fn unsynchronized() -> io::Result<bool> {
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    // modes 0 only reads the state
+    let state = unsafe { libc::adjtimex(&mut timex) };
+    if state == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(state == libc::TIME_ERROR || timex.status & libc::STA_UNSYNC != 0)
+}
+
+/// UTC worked out from CLOCK_TAI less the kernel's TAI - UTC offset, an error if the offset was never set,
+/// it stays 0 until something like ntpd or chrony with a leap seconds file tells the kernel
+pub fn tai_utc() -> io::Result<Duration> {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    if unsafe { libc::clock_gettime(libc::CLOCK_TAI, &mut ts) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    if unsafe { libc::adjtimex(&mut timex) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if timex.tai <= 0 {
+        return Err(io::Error::other("the kernel's TAI offset isn't set"));
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+        .checked_sub(Duration::from_secs(timex.tai as u64))
+        .ok_or_else(|| io::Error::other("CLOCK_TAI is before 1970"))
+}
+
+/// checks the clock state every CHECK_INTERVAL until shutdown, marking reference unsynchronized when the kernel says so
+pub fn run(reference: Arc<Mutex<Reference>>) {
+    let mut last = None;
+    while !signal::shutdown_requested() {
+        match unsynchronized() {
+            Ok(unsynchronized) => {
+                if last != Some(unsynchronized) {
+                    if unsynchronized {
+                        warn!("system clock is not synchronized, advertising leap indicator 3 until it is");
+                    } else {
+                        info!("system clock is synchronized");
+                    }
+                    last = Some(unsynchronized);
+                }
+                reference.lock().expect("reference poisoned").clock_unsynchronized = unsynchronized;
+            }
+            Err(e) => warn!("could not check clock state: {}", e),
+        }
+        // sleep in short steps so we notice shutdown requests
+        let next = Instant::now() + CHECK_INTERVAL;
+        while !signal::shutdown_requested() && Instant::now() < next {
+            std::thread::sleep(Duration::from_secs(1).min(next.saturating_duration_since(Instant::now())));
+        }
+    }
+}
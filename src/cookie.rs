@@ -0,0 +1,131 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! --require-cookie, a client shows it can receive at its address by echoing a cookie we sent there, an HMAC of the
+//! address under a secret only we know, so blind spoofers get no time out of us and we keep nothing per client
+//!
+//! a client sends a zeroed placeholder field first, as big as the cookie so the reply carrying it is no bigger than
+//! the request, gets a CKIE Kiss-o'-Death with the cookie in the same field, then sends that back in every request,
+//! a request with no room for the cookie only gets a bare CKIE
+
+use crate::packet::{ExtensionField, NtpPacket};
+use sha1::{Digest, Sha1};
+use std::io;
+use std::net::IpAddr;
+
+/// from the extension field types nobody has assigned
+pub const FIELD_TYPE: u16 = 0xF00C;
+/// the HMAC-SHA1 then 4 zero bytes, a field of 28 bytes in all, RFC 7822's minimum for the last one in a packet with no
+/// MAC, anything shorter at the end would read as a MAC
+pub const COOKIE_LEN: usize = 24;
+
+const BLOCK_LEN: usize = 64;
+
+/// RFC 2104 HMAC, with SHA1 which we already have for symmetric keys
+fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; 20] {
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..20].copy_from_slice(&Sha1::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let inner = Sha1::new().chain_update(pad(0x36)).chain_update(data).finalize();
+    Sha1::new().chain_update(pad(0x5c)).chain_update(inner).finalize().into()
+}
+
+/// what a request's cookie field says
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    Valid,
+    /// no field, or a zeroed one too short to send the cookie back in
+    Missing,
+    /// the zeroed placeholder, a client asking for its cookie
+    Placeholder,
+    /// a cookie we never sent this address
+    Invalid,
+}
+
+/// the secret cookies are made with, new every run so a restart sends every client back for a fresh one
+pub struct Cookies {
+    secret: [u8; 20],
+}
+
+impl Cookies {
+    pub fn new() -> io::Result<Cookies> {
+        let mut secret = [0u8; 20];
+        getrandom::getrandom(&mut secret).map_err(|e| io::Error::other(format!("could not generate cookie secret: {}", e)))?;
+        Ok(Cookies { secret })
+    }
+
+    /// an all zero secret for --replay, which should print the same every time and never talks to real clients
+    pub fn fixed() -> Cookies {
+        Cookies { secret: [0; 20] }
+    }
+
+    /// only the address, clients pick a new source port as often as they like
+    fn cookie(&self, ip: IpAddr) -> [u8; COOKIE_LEN] {
+        let octets = match ip {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        let mut cookie = [0u8; COOKIE_LEN];
+        cookie[..20].copy_from_slice(&hmac_sha1(&self.secret, &octets));
+        cookie
+    }
+
+    /// the field carrying the cookie for ip
+    pub fn field(&self, ip: IpAddr) -> ExtensionField {
+        ExtensionField::new(FIELD_TYPE, self.cookie(ip).to_vec())
+    }
+
+    pub fn check(&self, request: &NtpPacket) -> Check {
+        let body = match request.extensions.iter().find(|e| e.field_type == FIELD_TYPE) {
+            Some(field) => &field.body,
+            None => return Check::Missing,
+        };
+        if body.iter().all(|&b| b == 0) {
+            return if body.len() >= COOKIE_LEN { Check::Placeholder } else { Check::Missing };
+        }
+        let cookie = self.cookie(request.remote_addr.ip());
+        // compare every byte whatever the first mismatch, so timing doesn't give the cookie away
+        if body.len() == COOKIE_LEN && body.iter().zip(cookie.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0 {
+            Check::Valid
+        } else {
+            Check::Invalid
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc_2202_vectors() {
+        assert_eq!(
+            hmac_sha1(&[0x0b; 20], b"Hi There")[..],
+            [0xb6, 0x17, 0x31, 0x86, 0x55, 0x05, 0x72, 0x64, 0xe2, 0x8b, 0xc0, 0xb6, 0xfb, 0x37, 0x8c, 0x8e, 0xf1, 0x46, 0xbe, 0x00]
+        );
+        // a key longer than the block is hashed first
+        assert_eq!(
+            hmac_sha1(&[0xaa; 80], b"Test Using Larger Than Block-Size Key - Hash Key First")[..],
+            [0xaa, 0x4a, 0xe5, 0xe1, 0x52, 0x72, 0xd0, 0x0e, 0x95, 0x70, 0x56, 0x37, 0xce, 0x8a, 0x3b, 0x55, 0xed, 0x40, 0x21, 0x12]
+        );
+    }
+}
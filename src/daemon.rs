@@ -0,0 +1,94 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Result, Write};
+use std::os::unix::io::AsRawFd;
+
+fn check(ret: libc::c_int, call: &str) -> Result<()> {
+    if ret == -1 {
+        let e = Error::last_os_error();
+        return Err(Error::new(e.kind(), format!("{} failed: {}", call, e)));
+    }
+    Ok(())
+}
+
+/// a pid file we created and remove again on a clean shutdown
+pub struct Pidfile {
+    path: String,
+    file: File,
+}
+
+impl Pidfile {
+    /// creates the file, refusing to if it names a process that is still running
+    pub fn create(path: &str) -> Result<Pidfile> {
+        if let Ok(contents) = std::fs::read_to_string(path) {
+            if let Ok(pid) = contents.trim().parse::<libc::pid_t>() {
+                // signal 0 only checks the process exists, EPERM means it does but isn't ours
+                let alive = pid > 0 && pid as u32 != std::process::id() && (unsafe { libc::kill(pid, 0) } == 0 || Error::last_os_error().raw_os_error() == Some(libc::EPERM));
+                if alive {
+                    return Err(Error::other(format!("pid file {} belongs to running process {}, is kiss-ntpd already running?", path, pid)));
+                }
+            }
+        }
+        let file = File::create(path).map_err(|e| Error::new(e.kind(), format!("could not create pid file {}: {}", path, e)))?;
+        Ok(Pidfile { path: path.to_owned(), file })
+    }
+
+    /// writes our pid, after daemonizing so it's the daemon's
+    pub fn write_pid(&mut self) -> Result<()> {
+        writeln!(self.file, "{}", std::process::id()).map_err(|e| Error::new(e.kind(), format!("could not write pid file {}: {}", self.path, e)))
+    }
+
+    /// best effort, after --chroot or --user we might not be able to reach it anymore
+    pub fn remove(self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("could not remove pid file {}: {}", self.path, e);
+        }
+    }
+}
+
+/// forks into the background in a new session with stdin on /dev/null and stdout and stderr on logfile or /dev/null,
+/// the parent exits here. Everything that can fail is opened before forking so errors still reach the terminal, and
+/// this must be called before any threads are started
+pub fn daemonize(logfile: Option<&str>) -> Result<()> {
+    let null = File::open("/dev/null").map_err(|e| Error::new(e.kind(), format!("could not open /dev/null: {}", e)))?;
+    let output = match logfile {
+        Some(path) => OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| Error::new(e.kind(), format!("could not open log file {}: {}", path, e)))?,
+        None => OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .map_err(|e| Error::new(e.kind(), format!("could not open /dev/null: {}", e)))?,
+    };
+
+    let pid = unsafe { libc::fork() };
+    check(pid, "fork()")?;
+    if pid != 0 {
+        std::process::exit(0);
+    }
+    check(unsafe { libc::setsid() }, "setsid()")?;
+
+    check(unsafe { libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) }, "dup2(stdin)")?;
+    check(unsafe { libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO) }, "dup2(stdout)")?;
+    check(unsafe { libc::dup2(output.as_raw_fd(), libc::STDERR_FILENO) }, "dup2(stderr)")?;
+    Ok(())
+}
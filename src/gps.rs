@@ -0,0 +1,280 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! NMEA sentences from a GPS receiver on a serial port, which name the UTC second they were sent in but arrive
+//! some hundreds of milliseconds into it, so alone they are only good to about a second, PPS does the rest
+
+use crate::packet::{days_from_civil, NtpTimestamp};
+use crate::signal;
+use crate::upstream::{Reference, SourceState};
+use crate::Config;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// seconds, how late after the second it names a sentence might arrive
+const NMEA_DISPERSION: f64 = 0.5;
+/// how long without a locked fix before we stop trusting the last one
+const MAX_SILENCE: Duration = Duration::from_secs(10);
+
+/// the latest UTC second from the receiver and when, by the system clock since the unix epoch, we read it
+#[derive(Debug, Clone, Copy)]
+pub struct Fix {
+    pub utc: u64,
+    pub received: Duration,
+}
+
+/// written by the GPS thread, read by the PPS one
+pub type SharedFix = Arc<Mutex<Option<Fix>>>;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Sentence {
+    /// recommended minimum, seconds since the unix epoch if the receiver says the data is valid
+    Rmc(Option<u64>),
+    /// fix data, whether there is a fix at all
+    Gga(bool),
+}
+
+/// the XOR of everything between $ and *, which must match the two hex digits after
+fn checksum_ok(line: &str) -> bool {
+    let (body, sum) = match line.strip_prefix('$').and_then(|l| l.split_once('*')) {
+        Some(split) => split,
+        None => return false,
+    };
+    u8::from_str_radix(sum.trim_end(), 16).ok() == Some(body.bytes().fold(0, |acc, b| acc ^ b))
+}
+
+/// two digit decimal field at offset in s
+fn digits(s: &str, offset: usize) -> Option<i64> {
+    s.get(offset..offset + 2)?.parse().ok()
+}
+
+/// hhmmss[.ss] and ddmmyy to seconds since the unix epoch, NMEA years are two digits so this assumes 2000-2099
+fn utc(time: &str, date: &str) -> Option<u64> {
+    let (hour, minute, second) = (digits(time, 0)?, digits(time, 2)?, digits(time, 4)?);
+    let (day, month, year) = (digits(date, 0)?, digits(date, 2)?, digits(date, 4)?);
+    if hour > 23 || minute > 59 || second > 60 || !(1..=31).contains(&day) || !(1..=12).contains(&month) {
+        return None;
+    }
+    let days = days_from_civil(2000 + year, month, day);
+    Some((days * 86400 + hour * 3600 + minute * 60 + second) as u64)
+}
+
+/// RMC and GGA from any talker, GP, GN, GL and so on, everything else is None
+fn parse(line: &str) -> Option<Sentence> {
+    let line = line.trim_end();
+    if !checksum_ok(line) {
+        return None;
+    }
+    let fields: Vec<&str> = line[1..line.find('*')?].split(',').collect();
+    match fields[0].get(2..)? {
+        "RMC" if fields.len() > 9 => Some(Sentence::Rmc(if fields[2] == "A" { utc(fields[1], fields[9]) } else { None })),
+        "GGA" if fields.len() > 6 => Some(Sentence::Gga(!matches!(fields[6], "" | "0"))),
+        _ => None,
+    }
+}
+
+fn baud_rate(baud: u32) -> Result<libc::speed_t> {
+    Ok(match baud {
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        _ => return Err(Error::new(ErrorKind::InvalidInput, format!("unsupported baud rate {}", baud))),
+    })
+}
+
+pub struct Gps {
+    path: String,
+    reader: BufReader<File>,
+}
+
+impl Gps {
+    /// opens the serial device in raw mode at baud, reads time out after a second so we notice shutdown,
+    /// something that isn't a terminal, like a pipe, is read as is
+    pub fn open(path: &str, baud: u32) -> Result<Gps> {
+        let speed = baud_rate(baud)?;
+        let device = OpenOptions::new()
+            .read(true)
+            // non-blocking so a port without carrier detect doesn't hang the open, cleared again below
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(path)
+            .map_err(|e| Error::new(e.kind(), format!("could not open {}: {}", path, e)))?;
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(device.as_raw_fd(), &mut termios) } == 0 {
+            unsafe {
+                libc::cfmakeraw(&mut termios);
+                libc::cfsetispeed(&mut termios, speed);
+                libc::cfsetospeed(&mut termios, speed);
+            }
+            termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+            termios.c_cc[libc::VMIN] = 0;
+            termios.c_cc[libc::VTIME] = 10;
+            if unsafe { libc::tcsetattr(device.as_raw_fd(), libc::TCSANOW, &termios) } != 0 {
+                let e = Error::last_os_error();
+                return Err(Error::new(e.kind(), format!("could not configure {}: {}", path, e)));
+            }
+        }
+        let flags = unsafe { libc::fcntl(device.as_raw_fd(), libc::F_GETFL) };
+        if flags == -1 || unsafe { libc::fcntl(device.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK) } == -1 {
+            let e = Error::last_os_error();
+            return Err(Error::new(e.kind(), format!("could not configure {}: {}", path, e)));
+        }
+        Ok(Gps {
+            path: path.to_owned(),
+            reader: BufReader::new(device),
+        })
+    }
+}
+
+/// 2^-32 seconds to add to the system clock at received to get utc
+fn offset(fix: &Fix) -> i64 {
+    let nanos = fix.utc as i128 * 1_000_000_000 - fix.received.as_nanos() as i128;
+    ((nanos << 32) / 1_000_000_000) as i64
+}
+
+/// reads sentences until shutdown, publishing each locked fix to shared, and when there is no PPS to refine it,
+/// pointing reference at it directly, back at the plain system clock as live_config has it when the fix is lost
+pub fn run(mut gps: Gps, shared: SharedFix, reference: Option<Arc<Mutex<Reference>>>, live_config: Arc<RwLock<Arc<Config>>>, debug: bool) {
+    let mut line = String::new();
+    let mut gga_fix = true;
+    let mut last_fix: Option<Instant> = None;
+    if let Some(reference) = &reference {
+        reference.lock().expect("reference poisoned").state = SourceState::Init;
+    }
+    while !signal::shutdown_requested() {
+        match gps.reader.read_line(&mut line) {
+            // timed out, or the other end of a pipe went away
+            Ok(0) => std::thread::sleep(Duration::from_millis(100)),
+            // partial line, keep it for the next read
+            Ok(_) if !line.ends_with('\n') => continue,
+            Ok(_) => {
+                let received = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+                match parse(&line) {
+                    Some(Sentence::Gga(fix)) => gga_fix = fix,
+                    Some(Sentence::Rmc(Some(utc))) if gga_fix => {
+                        let fix = Fix { utc, received };
+                        if debug {
+                            debug!("gps {} fix {} offset {:.6}s", gps.path, utc, offset(&fix) as f64 / 4294967296.0);
+                        }
+                        if last_fix.is_none() {
+                            info!("gps {} locked", gps.path);
+                        }
+                        last_fix = Some(Instant::now());
+                        *shared.lock().expect("gps fix poisoned") = Some(fix);
+                        if let Some(reference) = &reference {
+                            let offset = offset(&fix);
+                            reference
+                                .lock()
+                                .expect("reference poisoned")
+                                .set_primary(*b"GPS\0", offset, NtpTimestamp::from_unix(received).offset(offset), NMEA_DISPERSION);
+                        }
+                    }
+                    Some(_) => {}
+                    None if debug => debug!("gps {} ignoring {:?}", gps.path, line.trim_end()),
+                    None => {}
+                }
+                line.clear();
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            // line noise, which read_line has already thrown away
+            Err(e) if e.kind() == ErrorKind::InvalidData => line.clear(),
+            Err(e) => {
+                warn!("gps {}: {}", gps.path, e);
+                line.clear();
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+        if last_fix.is_some_and(|t| t.elapsed() > MAX_SILENCE) {
+            warn!("no fix from gps {} for {:?}, serving the system clock", gps.path, MAX_SILENCE);
+            last_fix = None;
+            *shared.lock().expect("gps fix poisoned") = None;
+            if let Some(reference) = &reference {
+                // as configured now, a reload may have changed it since startup
+                let system = live_config.read().expect("config poisoned").system_reference();
+                reference.lock().expect("reference poisoned").set_system_clock(&system);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        assert!(checksum_ok("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47"));
+        assert!(checksum_ok("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n"));
+        assert!(!checksum_ok("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*48"));
+        assert!(!checksum_ok("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,"));
+        assert!(!checksum_ok("GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47"));
+    }
+
+    #[test]
+    fn rmc() {
+        // 2024-12-31 23:59:59
+        assert_eq!(parse("$GNRMC,235959.00,A,4807.038,N,01131.000,E,0.0,0.0,311224,,,A*44\r\n"), Some(Sentence::Rmc(Some(1735689599))));
+        // two digit years are 2000-2099
+        assert_eq!(
+            parse("$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A"),
+            Some(Sentence::Rmc(Some(days_from_civil(2094, 3, 23) as u64 * 86400 + 12 * 3600 + 35 * 60 + 19)))
+        );
+        // the receiver says the data is not valid
+        assert_eq!(parse("$GPRMC,123519,V,,,,,,,230394,,,N*51"), Some(Sentence::Rmc(None)));
+    }
+
+    #[test]
+    fn gga() {
+        assert_eq!(parse("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47"), Some(Sentence::Gga(true)));
+        assert_eq!(parse("$GPGGA,123519,,,,,0,00,,,M,,M,,*6B"), Some(Sentence::Gga(false)));
+    }
+
+    #[test]
+    fn ignored() {
+        // bad checksum
+        assert_eq!(parse("$GNRMC,235959.00,A,4807.038,N,01131.000,E,0.0,0.0,311224,,,A*45"), None);
+        // truncated, with and without a checksum
+        assert_eq!(parse("$GNRMC,235959.00,A,4807.038,N,01131"), None);
+        assert_eq!(parse("$GPRMC,123519,A*07"), None);
+        assert_eq!(parse("$GPGSV,3,1,11,03,03,111,00*4A"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn out_of_range() {
+        // hour 24, day 32 and month 13
+        assert_eq!(parse("$GPRMC,246000,A,4807.038,N,01131.000,E,0.0,0.0,230394,,,A*7D"), Some(Sentence::Rmc(None)));
+        assert_eq!(parse("$GPRMC,123519,A,4807.038,N,01131.000,E,0.0,0.0,321394,,,A*71"), Some(Sentence::Rmc(None)));
+        assert_eq!(parse("$GPRMC,123519,A,4807.038,N,01131.000,E,0.0,0.0,231394,,,A*71"), Some(Sentence::Rmc(None)));
+        assert_eq!(utc("236000", "311224"), None);
+        assert_eq!(utc("235961", "311224"), None);
+        assert_eq!(utc("235959", "001224"), None);
+        assert_eq!(utc("235959", "310024"), None);
+        assert_eq!(utc("2359", "311224"), None);
+        assert_eq!(utc("235959", "3112"), None);
+        assert_eq!(utc("23x959", "311224"), None);
+        // a leap second is 60
+        assert_eq!(utc("235960", "311216"), Some(days_from_civil(2017, 1, 1) as u64 * 86400));
+    }
+}
@@ -0,0 +1,102 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! a TCP port for load balancers, every connection gets OK or DEGRADED and is closed, no HTTP or NTP to parse
+
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::metrics::Metrics;
+use crate::packet::{NtpTimestamp, TimeSource};
+use crate::upstream::{Reference, SourceState};
+use crate::Config;
+
+/// whether clients are told our time is good, our source is ready, the kernel hasn't said the clock is unsynchronized
+/// and at now the reference isn't older than max_age
+pub fn synchronized(reference: &Reference, now: NtpTimestamp, max_age: Duration) -> bool {
+    reference.state == SourceState::Ready && !reference.clock_unsynchronized && !reference.stale(now, max_age)
+}
+
+/// OK while every serving thread is still running and we're synchronized
+fn status(metrics: &Metrics, reference: &Reference, clock: &dyn TimeSource, max_age: Duration) -> &'static str {
+    let synced = clock.now().is_ok_and(|now| synchronized(reference, now.offset(reference.offset), max_age));
+    if metrics.stopped.load(Ordering::Relaxed) == 0 && synced {
+        "OK\n"
+    } else {
+        "DEGRADED\n"
+    }
+}
+
+fn handle(mut stream: TcpStream, status: &str) -> std::io::Result<()> {
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(status.as_bytes())
+}
+
+/// answers health checks forever, one connection at a time, reading the same reference the servers do
+pub fn serve(listener: TcpListener, live_config: Arc<RwLock<Arc<Config>>>, metrics: Arc<Metrics>, reference: Arc<Mutex<Reference>>, clock: Arc<dyn TimeSource>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let max_age = live_config.read().expect("config poisoned").max_ref_age;
+                let status = status(&metrics, &reference.lock().expect("reference poisoned"), clock.as_ref(), max_age);
+                if let Err(e) = handle(stream, status) {
+                    error!("health connection: {}", e);
+                }
+            }
+            Err(e) => error!("health accept: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::MockClock;
+
+    #[test]
+    fn health_status() {
+        let metrics = Metrics::default();
+        let clock = MockClock::new(NtpTimestamp::from_rfc3339("2021-06-01T12:00:00Z").unwrap());
+        let mut reference = Reference {
+            ref_ts: clock.now().unwrap(),
+            offset: 0,
+            stratum: 1,
+            ref_id: *b"GPS\0",
+            root_delay: 0.0,
+            root_dispersion: 0.0,
+            synced: true,
+            clock_unsynchronized: false,
+            state: SourceState::Init,
+        };
+        let max_age = Duration::from_secs(60);
+        assert_eq!(status(&metrics, &reference, &clock, max_age), "DEGRADED\n");
+        reference.state = SourceState::Ready;
+        assert_eq!(status(&metrics, &reference, &clock, max_age), "OK\n");
+        clock.advance(61 << 32);
+        assert_eq!(status(&metrics, &reference, &clock, max_age), "DEGRADED\n");
+        assert_eq!(status(&metrics, &reference, &clock, Duration::ZERO), "OK\n");
+        reference.clock_unsynchronized = true;
+        assert_eq!(status(&metrics, &reference, &clock, Duration::ZERO), "DEGRADED\n");
+        reference.clock_unsynchronized = false;
+        Metrics::inc(&metrics.stopped);
+        assert_eq!(status(&metrics, &reference, &clock, Duration::ZERO), "DEGRADED\n");
+    }
+}
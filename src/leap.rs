@@ -0,0 +1,200 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::str::FromStr;
+
+/// the leap indicator, the discriminants are the values sent on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Leap {
+    None = 0,
+    /// last minute of the day has 61 seconds
+    Add = 1,
+    /// last minute of the day has 59 seconds
+    Delete = 2,
+    /// clock unsynchronized, only ever set by --check-sync
+    Alarm = 3,
+}
+
+impl FromStr for Leap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Leap, String> {
+        match s {
+            "none" => Ok(Leap::None),
+            "add" => Ok(Leap::Add),
+            "del" => Ok(Leap::Delete),
+            _ => Err("must be one of none, add or del".to_owned()),
+        }
+    }
+}
+
+/// how long before a leap second we start announcing it
+const ANNOUNCE_SECS: u64 = 86400;
+
+/// the IERS leap-seconds.list, as shipped in /usr/share/zoneinfo
+#[derive(Debug, Clone)]
+pub struct LeapSeconds {
+    /// NTP seconds the new offset takes effect, and the new TAI - UTC offset
+    leaps: Vec<(u64, i64)>,
+    /// NTP seconds after which the file shouldn't be trusted
+    pub expires: Option<u64>,
+}
+
+impl LeapSeconds {
+    pub fn parse(contents: &str) -> Result<LeapSeconds, String> {
+        let mut leaps = Vec::new();
+        let mut expires = None;
+        for line in contents.lines() {
+            if let Some(expiry) = line.strip_prefix("#@") {
+                expires = Some(expiry.trim().parse().map_err(|_| format!("invalid expiry line: {}", line))?);
+                continue;
+            }
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (time, offset) = (fields.next().map(str::parse), fields.next().map(str::parse));
+            match (time, offset) {
+                (Some(Ok(time)), Some(Ok(offset))) => leaps.push((time, offset)),
+                _ => return Err(format!("invalid line: {}", line)),
+            }
+        }
+        leaps.sort_unstable();
+        Ok(LeapSeconds { leaps, expires })
+    }
+
+    pub fn load(path: &str) -> Result<LeapSeconds, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        LeapSeconds::parse(&contents).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// what to announce at now, in NTP seconds
+    pub fn indicator(&self, now: u64) -> Leap {
+        // only the leap that hasn't happened yet but will within the announce window matters
+        let next = self.leaps.iter().position(|&(time, _)| time > now);
+        match next {
+            Some(idx) if idx > 0 && self.leaps[idx].0 - now <= ANNOUNCE_SECS => {
+                if self.leaps[idx].1 > self.leaps[idx - 1].1 {
+                    Leap::Add
+                } else {
+                    Leap::Delete
+                }
+            }
+            _ => Leap::None,
+        }
+    }
+    /// seconds to add to the system clock, now in NTP seconds by that clock, so a leap second is spread evenly over window
+    /// seconds centered on it, the system clock steps by the leap in the middle and this steps back the other way
+    pub fn smear(&self, now: f64, window: f64) -> f64 {
+        for pair in self.leaps.windows(2) {
+            let (time, delta) = (pair[1].0 as f64, (pair[1].1 - pair[0].1) as f64);
+            let start = time - window / 2.0;
+            if now < start || now >= time + window / 2.0 {
+                continue;
+            }
+            // ahead of the stepped system clock by the whole leap once it's happened
+            let stepped = if now >= time { delta } else { 0.0 };
+            let smeared = ((now + stepped - start) / window).clamp(0.0, 1.0);
+            return stepped - delta * smeared;
+        }
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2017-01-01, the last leap second so far
+    const LEAP: u64 = 3692217600;
+    const WINDOW: f64 = 86400.0;
+
+    fn leaps(offset: i64) -> LeapSeconds {
+        LeapSeconds::parse(&format!("#@\t3944332800\n3644697600\t36\t# 1 Jul 2015\n{}\t{}\t# 1 Jan 2017\n", LEAP, offset)).unwrap()
+    }
+
+    fn assert_near(a: f64, b: f64) {
+        assert!((a - b).abs() < 0.0001, "{} is not {}", a, b);
+    }
+
+    #[test]
+    fn parse() {
+        let leaps = leaps(37);
+        assert_eq!(leaps.leaps, [(3644697600, 36), (LEAP, 37)]);
+        assert_eq!(leaps.expires, Some(3944332800));
+        assert!(LeapSeconds::parse("3644697600 x\n").is_err());
+        assert!(LeapSeconds::parse("#@ never\n").is_err());
+    }
+
+    #[test]
+    fn smear() {
+        let leaps = leaps(37);
+        let now = LEAP as f64;
+        assert_eq!(leaps.smear(now - WINDOW / 2.0 - 1.0, WINDOW), 0.0);
+        assert_eq!(leaps.smear(now + WINDOW / 2.0, WINDOW), 0.0);
+        assert_near(leaps.smear(now - WINDOW / 2.0, WINDOW), 0.0);
+        assert_near(leaps.smear(now + WINDOW / 2.0 - 0.001, WINDOW), 0.0);
+        // half the leap either side of the step the system clock takes
+        assert_near(leaps.smear(now - 0.001, WINDOW), -0.5);
+        assert_near(leaps.smear(now, WINDOW), 0.5);
+        assert_near(leaps.smear(now + 3600.0, WINDOW), -leaps.smear(now - 3600.0, WINDOW));
+    }
+
+    #[test]
+    fn smear_is_monotonic() {
+        for offset in [37, 35] {
+            let (leaps, delta) = (leaps(offset), (offset - 36) as f64);
+            let start = LEAP as f64 - WINDOW / 2.0;
+            let mut last = 0.0;
+            for i in 0..=86400 {
+                let now = start + i as f64;
+                let smear = leaps.smear(now, WINDOW);
+                // moving against the leap a little every second, bar the step back at the leap itself
+                let change = if now == LEAP as f64 { smear - last - delta } else { smear - last };
+                assert!(change * delta <= 0.0 && change.abs() < 0.0001, "{} at {}", change, now);
+                last = smear;
+            }
+        }
+    }
+
+    #[test]
+    fn negative_leap() {
+        let leaps = leaps(35);
+        let now = LEAP as f64;
+        assert_near(leaps.smear(now - 0.001, WINDOW), 0.5);
+        assert_near(leaps.smear(now, WINDOW), -0.5);
+        assert_eq!(leaps.smear(now + WINDOW, WINDOW), 0.0);
+    }
+
+    #[test]
+    fn indicator() {
+        let leaps = leaps(37);
+        assert_eq!(leaps.indicator(LEAP - ANNOUNCE_SECS - 1), Leap::None);
+        assert_eq!(leaps.indicator(LEAP - ANNOUNCE_SECS), Leap::Add);
+        assert_eq!(leaps.indicator(LEAP - 1), Leap::Add);
+        assert_eq!(leaps.indicator(LEAP), Leap::None);
+        assert_eq!(leaps.indicator(LEAP + ANNOUNCE_SECS), Leap::None);
+        // the first entry is where the list starts, not a leap
+        assert_eq!(leaps.indicator(3644697600 - 1), Leap::None);
+        let leaps = self::leaps(35);
+        assert_eq!(leaps.indicator(LEAP - ANNOUNCE_SECS - 1), Leap::None);
+        assert_eq!(leaps.indicator(LEAP - 1), Leap::Delete);
+        assert_eq!(leaps.indicator(LEAP), Leap::None);
+    }
+}
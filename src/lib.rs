@@ -0,0 +1,2775 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2017  Miroslav Lichvar
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! an NTP server that Keeps It Simple, Stupid, serving the system clock to anyone who asks
+//!
+//! the kiss-ntpd binary is a thin wrapper around [`cli::main`], to embed a responder instead build an [`NtpServer`]
+//! with [`NtpServer::builder`], which takes the same options the command line does, and [`NtpServer::run`] it
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+#[macro_use]
+mod logger;
+
+mod acl;
+#[cfg(target_os = "linux")]
+mod affinity;
+mod auth;
+pub mod cli;
+#[cfg(target_os = "linux")]
+mod clock;
+mod cookie;
+#[cfg(unix)]
+mod daemon;
+#[cfg(unix)]
+mod gps;
+mod health;
+mod leap;
+mod metrics;
+#[cfg(target_os = "linux")]
+mod mmsg;
+mod nts;
+mod packet;
+#[cfg(target_os = "linux")]
+mod pps;
+mod privileges;
+mod query;
+mod queue;
+mod record;
+mod signal;
+mod socket;
+mod toml;
+mod upstream;
+
+use acl::{Acl, PortFilter};
+use auth::Keys;
+use leap::{Leap, LeapSeconds};
+use metrics::Metrics;
+use nts::Nts;
+use packet::Clock;
+use upstream::{Reference, SourceState};
+
+pub use packet::{ExtensionField, FrozenClock, MockClock, NtpError, NtpFracValue, NtpPacket, NtpTimestamp, RequestKind, SystemClock, TimeSource};
+pub use signal::request_shutdown;
+
+/// log2 of the smallest non-zero step SystemTime::now() takes, in seconds
+fn measure_precision() -> i8 {
+    let mut min = std::time::Duration::MAX;
+    let mut last = SystemTime::now();
+    for _ in 0..1000 {
+        let now = SystemTime::now();
+        if let Ok(delta) = now.duration_since(last) {
+            if !delta.is_zero() && delta < min {
+                min = delta;
+            }
+        }
+        last = now;
+    }
+    if min == std::time::Duration::MAX {
+        return 0; // clock never moved, claim 1 second
+    }
+    min.as_secs_f64().log2().round().clamp(i8::MIN as f64, 0.0) as i8
+}
+
+/// where the value of an option came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    CommandLine,
+    ConfigFile,
+    Environment,
+    Default,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Source::CommandLine => "command line",
+            Source::ConfigFile => "config file",
+            Source::Environment => "environment",
+            Source::Default => "default",
+        })
+    }
+}
+
+/// minimal command line parser, every option can also be supplied by an environment variable
+/// command line options layered over an optional config file, then environment variables, then defaults
+pub struct Args {
+    args: Vec<String>,
+    used: Vec<bool>,
+    /// the letters of a clustered short flag like -dv that have been claimed so far
+    clustered: Vec<HashSet<char>>,
+    /// where each option looked up so far was found, by long option name
+    sources: HashMap<&'static str, Source>,
+    /// from --config, keyed by long option name without the leading dashes
+    config: HashMap<String, toml::Value>,
+    config_used: HashSet<String>,
+    /// whether to fall back to the environment variables
+    env: bool,
+}
+
+impl Args {
+    pub fn new(args: Vec<String>) -> Args {
+        let used = vec![false; args.len()];
+        let clustered = vec![HashSet::new(); args.len()];
+        Args {
+            args,
+            used,
+            clustered,
+            sources: HashMap::new(),
+            config: HashMap::new(),
+            config_used: HashSet::new(),
+            env: true,
+        }
+    }
+
+    /// never reads environment variables, so only args and the config file it names are used
+    pub fn without_env(mut self) -> Args {
+        self.env = false;
+        self
+    }
+
+    fn env_var(&self, env: &str) -> Option<String> {
+        if self.env {
+            std::env::var(env).ok()
+        } else {
+            None
+        }
+    }
+
+    fn load_config(&mut self) -> Result<(), String> {
+        if let Some(path) = self.get_option(&["-c", "--config"], "KISS_NTPD_CONFIG")? {
+            let contents = std::fs::read_to_string(&path).map_err(|e| format!("could not read config file {}: {}", path, e))?;
+            self.config = toml::parse(&contents).map_err(|e| format!("invalid config file {}: {}", path, e))?;
+        }
+        Ok(())
+    }
+
+    /// the value for these flags in the config file, if any, which is looked up by the long option name
+    fn config_value(&mut self, flags: &[&'static str]) -> Option<(&'static str, toml::Value)> {
+        let key = flags.iter().find_map(|f| f.strip_prefix("--"))?;
+        let value = self.config.get(key)?.clone();
+        self.config_used.insert(key.to_owned());
+        Some((key, value))
+    }
+
+    /// remembers the first place a value was found for these flags
+    fn record(&mut self, flags: &[&'static str], source: Source) {
+        let name = flags.iter().find_map(|f| f.strip_prefix("--")).unwrap_or(flags[0]);
+        self.sources.entry(name).or_insert(source);
+    }
+
+    pub fn flag(&mut self, flags: &[&'static str]) -> Result<bool, String> {
+        let mut found = false;
+        for (idx, arg) in self.args.iter().enumerate() {
+            if flags.contains(&arg.as_str()) {
+                self.used[idx] = true;
+                found = true;
+            } else if let Some(letters) = cluster(arg).filter(|_| !self.used[idx]) {
+                let clustered = &mut self.clustered[idx];
+                for letter in letters.chars() {
+                    if flags.iter().any(|f| f.len() == 2 && f.starts_with('-') && f.ends_with(letter)) {
+                        clustered.insert(letter);
+                        found = true;
+                    }
+                }
+                self.used[idx] = letters.chars().all(|l| clustered.contains(&l));
+            }
+        }
+        let config = self.config_value(flags);
+        self.record(
+            flags,
+            match config {
+                _ if found => Source::CommandLine,
+                Some(_) => Source::ConfigFile,
+                None => Source::Default,
+            },
+        );
+        match config {
+            Some((_, toml::Value::Boolean(b))) => Ok(found || b),
+            Some((key, value)) => Err(format!("invalid value '{}' for {} in config file: expected true or false", value, key)),
+            None => Ok(found),
+        }
+    }
+
+    /// the first value given for these flags at or after args[start], either as the next argument or after an = like --flag=value,
+    /// marking everything it took as used and returning where to continue looking
+    fn take_value(&mut self, start: usize, flags: &[&'static str]) -> Result<Option<(String, usize)>, String> {
+        for idx in start..self.args.len() {
+            let arg = &self.args[idx];
+            if flags.contains(&arg.as_str()) {
+                let value = self.args.get(idx + 1).ok_or_else(|| format!("{} requires a value", arg))?.clone();
+                self.used[idx] = true;
+                self.used[idx + 1] = true;
+                return Ok(Some((value, idx + 2)));
+            }
+            if let Some((flag, value)) = arg.split_once('=') {
+                if flags.contains(&flag) {
+                    let value = value.to_owned();
+                    self.used[idx] = true;
+                    return Ok(Some((value, idx + 1)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn get_option(&mut self, flags: &[&'static str], env: &str) -> Result<Option<String>, String> {
+        let config = self.config_value(flags);
+        if let Some((value, _)) = self.take_value(0, flags)? {
+            self.record(flags, Source::CommandLine);
+            return Ok(Some(value));
+        }
+        if let Some((_, value)) = config {
+            self.record(flags, Source::ConfigFile);
+            return Ok(Some(value.to_string()));
+        }
+        let value = self.env_var(env);
+        self.record(flags, if value.is_some() { Source::Environment } else { Source::Default });
+        Ok(value)
+    }
+
+    /// every value of a repeatable option, falling back to a config file list or a comma separated environment variable
+    pub fn get_all(&mut self, flags: &[&'static str], env: &str) -> Result<Vec<String>, String> {
+        let config = self.config_value(flags);
+        let mut ret = Vec::new();
+        let mut idx = 0;
+        while let Some((value, next)) = self.take_value(idx, flags)? {
+            ret.push(value);
+            idx = next;
+        }
+        if !ret.is_empty() {
+            self.record(flags, Source::CommandLine);
+            return Ok(ret);
+        }
+        match config {
+            Some((_, toml::Value::Array(values))) => {
+                self.record(flags, Source::ConfigFile);
+                return Ok(values.iter().map(|v| v.to_string()).collect());
+            }
+            Some((_, value)) => {
+                self.record(flags, Source::ConfigFile);
+                return Ok(vec![value.to_string()]);
+            }
+            None => {}
+        }
+        let values: Vec<String> = self
+            .env_var(env)
+            .map(|v| v.split(',').map(|s| s.trim().to_owned()).filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+        self.record(flags, if values.is_empty() { Source::Default } else { Source::Environment });
+        Ok(values)
+    }
+
+    pub fn get_parsed<T: FromStr>(&mut self, flags: &[&'static str], env: &str) -> Result<Option<T>, String>
+    where
+        T::Err: Display,
+    {
+        match self.get_option(flags, env)? {
+            Some(value) => value.parse().map(Some).map_err(|e| format!("invalid value '{}' for {}: {}", value, flags[0], e)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get<T: FromStr>(&mut self, flags: &[&'static str], env: &str, default: T) -> Result<T, String>
+    where
+        T::Err: Display,
+    {
+        Ok(self.get_parsed(flags, env)?.unwrap_or(default))
+    }
+
+    /// whether anything on the command line isn't an option or an option's value yet, so must be a bind address
+    pub fn has_positional(&self) -> bool {
+        self.args.iter().zip(&self.used).any(|(arg, used)| !used && !arg.starts_with('-'))
+    }
+
+    /// everything not consumed as a flag or option value, rejecting unknown options,
+    /// with the config file's bind list used when there is nothing on the command line
+    pub fn remaining(mut self) -> Result<Vec<String>, String> {
+        let mut ret = Vec::new();
+        for ((arg, used), clustered) in self.args.into_iter().zip(self.used).zip(self.clustered) {
+            if used {
+                continue;
+            }
+            if let Some(letter) = cluster(&arg).and_then(|letters| letters.chars().find(|l| !clustered.contains(l))) {
+                return Err(format!("unknown option -{} in {}", letter, arg));
+            }
+            if arg.starts_with('-') {
+                return Err(format!("unknown option {}", arg));
+            }
+            ret.push(arg);
+        }
+        match self.config.remove("bind") {
+            Some(toml::Value::Array(binds)) if ret.is_empty() => ret.extend(binds.iter().map(|b| b.to_string())),
+            Some(toml::Value::String(bind)) if ret.is_empty() => ret.push(bind),
+            Some(toml::Value::Array(_) | toml::Value::String(_)) | None => {}
+            Some(value) => return Err(format!("invalid value '{}' for bind in config file: expected a list of addresses", value)),
+        }
+        let config_used = self.config_used;
+        if let Some(key) = self.config.keys().find(|k| !config_used.contains(*k)) {
+            return Err(format!("unknown config file key {}", key));
+        }
+        Ok(ret)
+    }
+}
+
+/// the letters of several single letter flags given together like -dv
+fn cluster(arg: &str) -> Option<&str> {
+    let letters = arg.strip_prefix('-')?;
+    if letters.len() > 1 && letters.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(letters)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    /// one object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("must be text or json".to_owned()),
+        }
+    }
+}
+
+/// every setting, parsed from Args, a snapshot a reload swaps out whole
+#[derive(Clone)]
+pub struct Config {
+    /// only validate everything, then exit
+    check: bool,
+    /// log_level is debug or trace
+    debug: bool,
+    /// from --log-level, or debug with --debug
+    log_level: logger::Severity,
+    /// drop info messages
+    quiet: bool,
+    /// log the source of every mode 6/7 control query, --allow/--deny, --client-port-filter and version refusal we drop
+    log_dropped: bool,
+    /// warn about every datagram we couldn't answer, with where it came from
+    verbose_errors: bool,
+    /// between latency percentile reports, if they're wanted
+    stats: Option<Duration>,
+    /// how often to print a summary of requests, responses and errors
+    stats_interval: Option<Duration>,
+    /// exit after this long without a request
+    idle_timeout: Option<Duration>,
+    /// format of the per request debug output
+    log_format: LogFormat,
+    /// log to /dev/log instead of stdout/stderr
+    syslog: bool,
+    stratum: u8,
+    ref_id: [u8; 4],
+    /// seconds, advertised when serving the system clock
+    root_delay: f64,
+    root_dispersion: f64,
+    /// log2 seconds
+    precision: i8,
+    leap: Leap,
+    /// when present, announces leap seconds automatically as they approach
+    leap_seconds: Option<LeapSeconds>,
+    /// what the time we serve is read from
+    clock: Clock,
+    /// seconds each leap second in leap_seconds is smeared over instead of announced
+    leap_smear: Option<u64>,
+    /// once upstream, a peer, PPS or GPS hasn't updated the reference for this long we advertise the maximum dispersion
+    /// and an unsynchronized leap indicator, 0 for never
+    max_ref_age: Duration,
+    /// log2 seconds, the poll interval we suggest to clients, within min_poll and max_poll
+    poll: i8,
+    /// with --echo-poll, send back the client's own poll clamped to min_poll and max_poll instead
+    echo_poll: bool,
+    min_poll: i8,
+    max_poll: i8,
+    /// request modes we answer, 1 symmetric active and 3 client
+    allow_modes: Vec<u8>,
+    /// only answer clients echoing the cookie we sent their address
+    require_cookie: bool,
+    /// requests from versions outside this range are dropped
+    min_version: u8,
+    max_version: u8,
+    /// from --force-mode, the mode every reply goes out with instead of 4 to clients and 2 to symmetric peers
+    force_mode: Option<u8>,
+    /// packets per second allowed per client IP, 0 disables rate limiting
+    rate_limit: f64,
+    /// most clients the rate limiter remembers
+    rate_limit_table_size: usize,
+    /// responses per second sent in total, 0 for no limit
+    max_packet_rate: f64,
+    /// from the hidden --test-delay-ms, how long to sleep before sending each response, so client developers can see
+    /// how their timeouts and retries cope with a slow or distant server, never meant for a server anyone relies on
+    test_delay: Duration,
+    /// from --freeze-time, serve this instant instead of the system clock, for testing clients against a server that's off
+    freeze_time: Option<NtpTimestamp>,
+    /// with --freeze-advance the frozen time moves on as real time passes
+    freeze_advance: bool,
+    /// client subnets we answer
+    acl: Acl,
+    /// client source ports we answer, any if not set
+    port_filter: Option<PortFilter>,
+    /// symmetric keys to authenticate requests and sign responses with
+    keys: Option<Keys>,
+    /// master key for NTS cookies
+    nts: Option<Nts>,
+    user: Option<String>,
+    group: Option<String>,
+    /// directory to chroot into after binding, before switching user
+    chroot: Option<String>,
+    /// fork into the background after binding
+    daemonize: bool,
+    /// where a daemon's output goes, /dev/null without one
+    logfile: Option<String>,
+    pidfile: Option<String>,
+    metrics_addr: Option<String>,
+    /// what /metrics answers with unless the scraper's Accept header asks for the other
+    metrics_format: metrics::Format,
+    /// where load balancers can ask whether we're serving good time
+    health_addr: Option<String>,
+    /// workers per bind address
+    threads: usize,
+    /// give each worker its own SO_REUSEPORT socket instead of sharing one
+    reuseport: bool,
+    /// the interface every socket only hears from, with SO_BINDTODEVICE
+    bind_device: Option<String>,
+    /// pin each worker to its own core with sched_setaffinity
+    cpu_affinity: bool,
+    /// hand requests from one receiver to the workers through a queue this long, 0 to have every worker receive
+    queue_depth: usize,
+    /// SO_RCVBUF and SO_SNDBUF for every socket, the kernel default if not set
+    rcvbuf: Option<usize>,
+    sndbuf: Option<usize>,
+    /// the differentiated services code point to mark every socket's packets with
+    dscp: Option<u8>,
+    /// datagrams to receive per recvmmsg call, 1 means plain recv_from
+    batch_size: usize,
+    /// use the kernel's SO_TIMESTAMPING receive timestamps instead of reading the clock ourselves
+    kernel_timestamps: bool,
+    /// answer interleaved requests with the transmit timestamp of the previous response
+    interleaved: bool,
+    /// write every datagram received to this file
+    record: Option<String>,
+    /// answer the datagrams in this recording instead of serving
+    replay: Option<String>,
+    /// server to take our time from rather than trusting the system clock
+    upstream: Option<SocketAddr>,
+    /// machines to poll in symmetric active mode and meet halfway, every upstream_interval
+    peers: Vec<SocketAddr>,
+    upstream_interval: Duration,
+    /// periodically ask the kernel whether the system clock is synchronized and advertise it when it isn't
+    check_sync: bool,
+    /// PPS device to align the served time with
+    pps: Option<String>,
+    /// serial GPS receiver to take the time from, alone or numbering the PPS pulses
+    gps: Option<String>,
+    gps_baud: u32,
+    /// where to periodically send mode 5 broadcasts, if anywhere
+    broadcast: Option<SocketAddr>,
+    /// join the NTP multicast groups and periodically announce to them
+    multicast: bool,
+    /// between broadcast and multicast announcements
+    broadcast_interval: Duration,
+    /// where each option's value came from, by long option name
+    sources: HashMap<&'static str, Source>,
+}
+
+impl Config {
+    /// the system clock, or --freeze-time
+    fn time_source(&self) -> Arc<dyn TimeSource> {
+        match self.freeze_time {
+            Some(at) => Arc::new(FrozenClock::new(at, self.freeze_advance)),
+            None => Arc::new(SystemClock),
+        }
+    }
+
+    /// settings that were only used at startup, so a reload can't change them
+    #[cfg(unix)]
+    fn keep_startup_settings(&mut self, old: &Config) {
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field != old.$field {
+                        warn!("{} can't be changed without a restart, ignoring", stringify!($field));
+                        self.$field = old.$field.clone();
+                    }
+                )*
+            };
+        }
+        keep!(
+            syslog,
+            stats,
+            stats_interval,
+            idle_timeout,
+            clock,
+            rate_limit_table_size,
+            user,
+            group,
+            chroot,
+            daemonize,
+            logfile,
+            pidfile,
+            metrics_addr,
+            metrics_format,
+            health_addr,
+            threads,
+            reuseport,
+            bind_device,
+            cpu_affinity,
+            queue_depth,
+            rcvbuf,
+            sndbuf,
+            dscp,
+            batch_size,
+            kernel_timestamps,
+            record,
+            replay,
+            require_cookie,
+            freeze_time,
+            freeze_advance,
+            upstream,
+            peers,
+            upstream_interval,
+            check_sync,
+            pps,
+            gps,
+            gps_baud,
+            broadcast,
+            multicast,
+            broadcast_interval
+        );
+    }
+
+    /// reads every option, loading any files they name, leaving bind addresses and unknown options in args
+    pub fn from_args(args: &mut Args) -> Result<Config, String> {
+        args.load_config()?;
+        let check = args.flag(&["--check"])?;
+        let default_level = if args.flag(&["-d", "--debug"])? { logger::Severity::Debug } else { logger::Severity::Info };
+        let log_level = args.get(&["--log-level"], "KISS_NTPD_LOG_LEVEL", default_level)?;
+        let debug = log_level >= logger::Severity::Debug;
+        let quiet = args.flag(&["-q", "--quiet"])?;
+        let log_dropped = args.flag(&["--log-dropped"])?;
+        let verbose_errors = args.flag(&["--verbose-errors"])?;
+        let stats = match args.get_parsed::<u64>(&["--stats"], "KISS_NTPD_STATS")? {
+            Some(0) => return Err("stats interval must be at least 1 second".to_owned()),
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None if debug => Some(Duration::from_secs(60)),
+            None => None,
+        };
+        let stats_interval = match args.get_parsed::<u64>(&["--stats-interval"], "KISS_NTPD_STATS_INTERVAL")? {
+            Some(0) => return Err("stats interval must be at least 1 second".to_owned()),
+            secs => secs.map(Duration::from_secs),
+        };
+        let idle_timeout = match args.get_parsed::<u64>(&["--idle-timeout"], "KISS_NTPD_IDLE_TIMEOUT")? {
+            Some(0) => return Err("idle timeout must be at least 1 second".to_owned()),
+            secs => secs.map(Duration::from_secs),
+        };
+        let log_format = args.get(&["--log-format"], "KISS_NTPD_LOG_FORMAT", LogFormat::Text)?;
+        let syslog = args.flag(&["--syslog"])?;
+
+        let stratum = args.get(&["--stratum"], "KISS_NTPD_STRATUM", 8u8)?;
+        if !(1..=15).contains(&stratum) {
+            return Err(format!("stratum must be between 1 and 15, got {}", stratum));
+        }
+
+        let ref_id = args.get(&["--refid"], "KISS_NTPD_REFID", String::new())?;
+        let force_ref_id = args.flag(&["--force-refid"])?;
+        let ref_id = parse_ref_id(&ref_id, stratum, force_ref_id)?;
+
+        let root_delay = parse_root_value(args.get(&["--root-delay"], "KISS_NTPD_ROOT_DELAY", 0.0)?, "root delay")?;
+        let root_dispersion = parse_root_value(args.get(&["--root-dispersion"], "KISS_NTPD_ROOT_DISPERSION", 0.0)?, "root dispersion")?;
+
+        let precision = match args.get_parsed(&["--precision"], "KISS_NTPD_PRECISION")? {
+            Some(precision) => precision,
+            None => measure_precision(),
+        };
+
+        let leap = args.get(&["--leap"], "KISS_NTPD_LEAP", Leap::None)?;
+        let clock = args.get(&["--clock"], "KISS_NTPD_CLOCK", Clock::Realtime)?;
+        let leap_seconds = match args.get_option(&["--leap-file"], "KISS_NTPD_LEAP_FILE")? {
+            Some(path) => Some(LeapSeconds::load(&path)?),
+            None => None,
+        };
+        let leap_smear = args.get_parsed::<u64>(&["--leap-smear"], "KISS_NTPD_LEAP_SMEAR")?;
+        if leap_smear.is_some() {
+            if leap_smear == Some(0) {
+                return Err("leap smear window must be at least 1 second".to_owned());
+            }
+            if leap != Leap::None {
+                return Err("--leap-smear and --leap can't be used together, smeared time never announces a leap second".to_owned());
+            }
+            if leap_seconds.is_none() {
+                return Err("--leap-smear needs --leap-file to know when leap seconds happen".to_owned());
+            }
+        }
+        if let Some(expires) = leap_seconds.as_ref().and_then(|l| l.expires) {
+            if NtpTimestamp::now().is_ok_and(|now| expires < now.secs()) {
+                warn!("leap seconds file has expired, it should be updated");
+            }
+        }
+
+        let max_ref_age = args.get(&["--max-ref-age"], "KISS_NTPD_MAX_REF_AGE", 0u64)?;
+
+        let min_poll = args.get(&["--min-poll"], "KISS_NTPD_MIN_POLL", 4i8)?;
+        let max_poll = args.get(&["--max-poll"], "KISS_NTPD_MAX_POLL", 17i8)?;
+        if min_poll > max_poll {
+            return Err(format!("min poll {} is greater than max poll {}", min_poll, max_poll));
+        }
+        let poll = args.get(&["--poll"], "KISS_NTPD_POLL", 6i8)?.clamp(min_poll, max_poll);
+        let echo_poll = args.flag(&["--echo-poll"])?;
+
+        let require_cookie = args.flag(&["--require-cookie"])?;
+        let mut allow_modes = Vec::new();
+        for mode in args
+            .get_all(&["--allow-modes"], "KISS_NTPD_ALLOW_MODES")?
+            .iter()
+            .flat_map(|m| m.split(','))
+            .filter(|m| !m.trim().is_empty())
+        {
+            match mode.trim().parse() {
+                Ok(mode @ (1 | 3)) => allow_modes.push(mode),
+                _ => return Err(format!("--allow-modes must be a list of 1 and 3, got {}", mode)),
+            }
+        }
+        if allow_modes.is_empty() {
+            allow_modes = vec![1, 3];
+        }
+        let min_version = args.get(&["--min-version"], "KISS_NTPD_MIN_VERSION", 1u8)?;
+        let max_version = args.get(&["--max-version"], "KISS_NTPD_MAX_VERSION", 4u8)?;
+        if !(1..=4).contains(&min_version) || !(1..=4).contains(&max_version) || min_version > max_version {
+            return Err(format!("version range must be within 1..=4, got {}..={}", min_version, max_version));
+        }
+
+        let force_mode = args.get_parsed::<u8>(&["--force-mode"], "KISS_NTPD_FORCE_MODE")?;
+        if let Some(mode @ 8..) = force_mode {
+            return Err(format!("mode must be within 0..=7, got {}", mode));
+        }
+
+        let rate_limit = args.get(&["--rate-limit"], "KISS_NTPD_RATE_LIMIT", 0.0f64)?;
+        if !(rate_limit >= 0.0 && rate_limit.is_finite()) {
+            return Err(format!("rate limit must be a non-negative number, got {}", rate_limit));
+        }
+        let rate_limit_table_size = args.get(&["--rate-limit-table-size"], "KISS_NTPD_RATE_LIMIT_TABLE_SIZE", 100_000)?;
+        if rate_limit_table_size == 0 {
+            return Err("rate limit table size must be at least 1".to_owned());
+        }
+        let max_packet_rate = args.get(&["--max-packet-rate"], "KISS_NTPD_MAX_PACKET_RATE", 0.0f64)?;
+        if !(max_packet_rate >= 0.0 && max_packet_rate.is_finite()) {
+            return Err(format!("max packet rate must be a non-negative number, got {}", max_packet_rate));
+        }
+        let test_delay = args.get(&["--test-delay-ms"], "KISS_NTPD_TEST_DELAY_MS", 0u64)?;
+        let freeze_time = match args.get_option(&["--freeze-time"], "KISS_NTPD_FREEZE_TIME")? {
+            Some(time) => Some(NtpTimestamp::from_rfc3339(&time).ok_or_else(|| format!("freeze time must be RFC 3339 like 2021-06-01T12:34:56Z, got '{}'", time))?),
+            None => None,
+        };
+        let freeze_advance = args.flag(&["--freeze-advance"])?;
+        if freeze_advance && freeze_time.is_none() {
+            return Err("--freeze-advance only applies with --freeze-time".to_owned());
+        }
+        let keys = match args.get_option(&["--keyfile"], "KISS_NTPD_KEYFILE")? {
+            Some(path) => Some(Keys::load(&path)?),
+            None => None,
+        };
+        let nts = match args.get_option(&["--nts-key"], "KISS_NTPD_NTS_KEY")? {
+            Some(path) => Some(Nts::load(&path)?),
+            None => None,
+        };
+        let port_filter = args.get_parsed(&["--client-port-filter"], "KISS_NTPD_CLIENT_PORT_FILTER")?;
+        let acl = Acl::new(&args.get_all(&["--allow"], "KISS_NTPD_ALLOW")?, &args.get_all(&["--deny"], "KISS_NTPD_DENY")?)?;
+
+        let user = args.get_option(&["-u", "--user"], "KISS_NTPD_USER")?;
+        let group = args.get_option(&["-g", "--group"], "KISS_NTPD_GROUP")?;
+        let chroot = args.get_option(&["--chroot"], "KISS_NTPD_CHROOT")?;
+        let daemonize = args.flag(&["--daemonize"])?;
+        if daemonize && !cfg!(unix) {
+            return Err("--daemonize is only supported on unix".to_owned());
+        }
+        let logfile = args.get_option(&["--logfile"], "KISS_NTPD_LOGFILE")?;
+        if !daemonize && logfile.is_some() {
+            return Err("--logfile only applies with --daemonize".to_owned());
+        }
+        let pidfile = args.get_option(&["--pidfile"], "KISS_NTPD_PIDFILE")?;
+        if pidfile.is_some() && !cfg!(unix) {
+            return Err("--pidfile is only supported on unix".to_owned());
+        }
+
+        let threads = args.get(&["-t", "--threads"], "KISS_NTPD_THREADS", std::thread::available_parallelism().map_or(1, |n| n.get()))?;
+        if threads == 0 {
+            return Err("threads must be at least 1".to_owned());
+        }
+
+        let reuseport = args.flag(&["--reuseport"])?;
+        let bind_device = args.get_option(&["--bind-device"], "KISS_NTPD_BIND_DEVICE")?;
+        if bind_device.is_some() && !cfg!(target_os = "linux") {
+            return Err("--bind-device is only supported on linux".to_owned());
+        }
+        let cpu_affinity = args.flag(&["--cpu-affinity"])?;
+        if cpu_affinity && !cfg!(target_os = "linux") {
+            return Err("--cpu-affinity is only supported on linux".to_owned());
+        }
+        let queue_depth = args.get(&["--queue-depth"], "KISS_NTPD_QUEUE_DEPTH", 0)?;
+        if queue_depth > 0 && reuseport {
+            return Err("--queue-depth and --reuseport can't be used together".to_owned());
+        }
+
+        let batch_size = args.get(&["--batch-size"], "KISS_NTPD_BATCH_SIZE", if cfg!(target_os = "linux") { 32 } else { 1 })?;
+        if batch_size == 0 {
+            return Err("batch size must be at least 1".to_owned());
+        }
+
+        let rcvbuf = args.get_parsed(&["--rcvbuf"], "KISS_NTPD_RCVBUF")?;
+        let sndbuf = args.get_parsed(&["--sndbuf"], "KISS_NTPD_SNDBUF")?;
+        let dscp = args.get_option(&["--dscp"], "KISS_NTPD_DSCP")?.map(|dscp| parse_dscp(&dscp)).transpose()?;
+
+        let kernel_timestamps = args.flag(&["--kernel-timestamps"])?;
+        if kernel_timestamps && !cfg!(target_os = "linux") {
+            return Err("--kernel-timestamps is only supported on linux".to_owned());
+        }
+
+        if kernel_timestamps && freeze_time.is_some() {
+            return Err("--freeze-time and --kernel-timestamps can't be used together".to_owned());
+        }
+
+        let interleaved = args.flag(&["--interleaved"])?;
+
+        let record = args.get_option(&["--record"], "KISS_NTPD_RECORD")?;
+        let replay = args.get_option(&["--replay"], "KISS_NTPD_REPLAY")?;
+        if record.is_some() && replay.is_some() {
+            return Err("--record and --replay can't be used together".to_owned());
+        }
+
+        let metrics_addr = args.get_option(&["--metrics-addr"], "KISS_NTPD_METRICS_ADDR")?;
+        let metrics_format = args.get(&["--metrics-format"], "KISS_NTPD_METRICS_FORMAT", metrics::Format::Prometheus)?;
+        let health_addr = args.get_option(&["--health-addr"], "KISS_NTPD_HEALTH_ADDR")?;
+
+        let upstream = match args.get_option(&["--upstream"], "KISS_NTPD_UPSTREAM")? {
+            Some(addr) => Some(socket::resolve(&addr).map_err(|e| format!("could not resolve upstream {}: {}", addr, e))?),
+            None => None,
+        };
+        let peers = args
+            .get_all(&["--peer"], "KISS_NTPD_PEER")?
+            .iter()
+            .map(|addr| socket::resolve(addr).map_err(|e| format!("could not resolve peer {}: {}", addr, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !peers.is_empty() && upstream.is_some() {
+            return Err("--peer and --upstream can't be used together".to_owned());
+        }
+        let check_sync = args.flag(&["--check-sync"])?;
+        if check_sync && !cfg!(target_os = "linux") {
+            return Err("--check-sync is only supported on linux".to_owned());
+        }
+
+        let pps = args.get_option(&["--pps"], "KISS_NTPD_PPS")?;
+        if pps.is_some() && !cfg!(target_os = "linux") {
+            return Err("--pps is only supported on linux".to_owned());
+        }
+        if pps.is_some() && upstream.is_some() {
+            return Err("--pps and --upstream can't be used together".to_owned());
+        }
+        if pps.is_some() && !peers.is_empty() {
+            return Err("--pps and --peer can't be used together".to_owned());
+        }
+        let gps = args.get_option(&["--gps"], "KISS_NTPD_GPS")?;
+        if gps.is_some() && !cfg!(unix) {
+            return Err("--gps is only supported on unix".to_owned());
+        }
+        if gps.is_some() && upstream.is_some() {
+            return Err("--gps and --upstream can't be used together".to_owned());
+        }
+        if gps.is_some() && !peers.is_empty() {
+            return Err("--gps and --peer can't be used together".to_owned());
+        }
+        let gps_baud = args.get(&["--gps-baud"], "KISS_NTPD_GPS_BAUD", 9600)?;
+
+        let upstream_interval = args.get(&["--upstream-interval"], "KISS_NTPD_UPSTREAM_INTERVAL", 64u64)?;
+        if upstream_interval == 0 {
+            return Err("upstream interval must be at least 1 second".to_owned());
+        }
+
+        let broadcast = match args.get_option(&["--broadcast"], "KISS_NTPD_BROADCAST")? {
+            Some(addr) => Some(parse_broadcast_addr(&addr)?),
+            None => None,
+        };
+        let multicast = args.flag(&["--multicast"])?;
+        let broadcast_interval = args.get(&["--broadcast-interval"], "KISS_NTPD_BROADCAST_INTERVAL", 64u64)?;
+        if broadcast_interval == 0 {
+            return Err("broadcast interval must be at least 1 second".to_owned());
+        }
+
+        Ok(Config {
+            check,
+            debug,
+            log_level,
+            quiet,
+            log_dropped,
+            verbose_errors,
+            stats,
+            stats_interval,
+            idle_timeout,
+            log_format,
+            syslog,
+            stratum,
+            ref_id,
+            root_delay,
+            root_dispersion,
+            precision,
+            leap,
+            leap_seconds,
+            clock,
+            leap_smear,
+            max_ref_age: Duration::from_secs(max_ref_age),
+            poll,
+            echo_poll,
+            min_poll,
+            max_poll,
+            require_cookie,
+            allow_modes,
+            min_version,
+            max_version,
+            force_mode,
+            rate_limit,
+            rate_limit_table_size,
+            max_packet_rate,
+            test_delay: Duration::from_millis(test_delay),
+            freeze_time,
+            freeze_advance,
+            acl,
+            port_filter,
+            keys,
+            nts,
+            user,
+            group,
+            chroot,
+            daemonize,
+            logfile,
+            pidfile,
+            metrics_addr,
+            metrics_format,
+            health_addr,
+            threads,
+            reuseport,
+            bind_device,
+            cpu_affinity,
+            queue_depth,
+            rcvbuf,
+            sndbuf,
+            dscp,
+            batch_size,
+            kernel_timestamps,
+            interleaved,
+            record,
+            replay,
+            upstream,
+            peers,
+            upstream_interval: Duration::from_secs(upstream_interval),
+            check_sync,
+            pps,
+            gps,
+            gps_baud,
+            broadcast,
+            multicast,
+            broadcast_interval: Duration::from_secs(broadcast_interval),
+            sources: args.sources.clone(),
+        })
+    }
+
+    /// where the value of the option with this long name came from
+    pub fn source(&self, name: &str) -> Source {
+        self.sources.get(name).copied().unwrap_or(Source::Default)
+    }
+
+    /// serving the system clock as configured, until upstream, PPS or GPS take over
+    fn system_reference(&self) -> Reference {
+        Reference {
+            ref_ts: self.freeze_time.unwrap_or_else(|| NtpTimestamp::now().unwrap_or_else(|_| NtpTimestamp::zero())),
+            offset: 0,
+            stratum: self.stratum,
+            ref_id: self.ref_id,
+            root_delay: self.root_delay,
+            root_dispersion: self.root_dispersion,
+            synced: false,
+            clock_unsynchronized: false,
+            state: SourceState::Ready,
+        }
+    }
+}
+
+/// an unauthenticated request never gets back more than it sent, so nobody can spoof one and use us as an amplifier
+fn check_amplification(request: &NtpPacket, response: &NtpPacket) -> Result<(), NtpError> {
+    let (request_len, response_len) = (request.wire_len(), response.wire_len());
+    if response_len > request_len {
+        return Err(NtpError::Amplification(response_len, request_len, request.remote_addr));
+    }
+    Ok(())
+}
+
+/// root delay and dispersion go out as unsigned 16.16 fixed point seconds
+fn parse_root_value(secs: f64, name: &str) -> Result<f64, String> {
+    if !(0.0..65536.0).contains(&secs) {
+        return Err(format!("{} must be at least 0 and less than 65536 seconds, got {}", name, secs));
+    }
+    Ok(secs)
+}
+
+/// 0 to 63, or one of the RFC 2474, 2597 and 3246 names, class selectors CS0-CS7, assured forwarding AF11-AF43 and
+/// expedited forwarding EF
+fn parse_dscp(dscp: &str) -> Result<u8, String> {
+    let upper = dscp.to_ascii_uppercase();
+    let digits = |s: &str| s.parse::<u8>().ok();
+    let value = match upper.as_str() {
+        "EF" => Some(46),
+        name if name.starts_with("CS") => digits(&name[2..]).filter(|class| *class <= 7).map(|class| class << 3),
+        name if name.starts_with("AF") && name.len() == 4 => match (digits(&name[2..3]), digits(&name[3..])) {
+            (Some(class @ 1..=4), Some(drop @ 1..=3)) => Some(class << 3 | drop << 1),
+            _ => None,
+        },
+        number => digits(number).filter(|dscp| *dscp <= 63),
+    };
+    value.ok_or_else(|| format!("DSCP must be 0-63 or a name like EF, CS6 or AF41, got {}", dscp))
+}
+
+/// up to 4 ascii characters, right padded with zero bytes
+/// above stratum 1 the refid is the IPv4 address of the server we follow, so an ascii one there needs force
+fn parse_ref_id(ref_id: &str, stratum: u8, force: bool) -> Result<[u8; 4], String> {
+    if stratum > 1 && !ref_id.is_empty() {
+        if let Ok(ip) = ref_id.parse::<Ipv4Addr>() {
+            return Ok(ip.octets());
+        }
+        if !force {
+            return Err(format!(
+                "refid at stratum {} must be the IPv4 address of our upstream, got '{}', use --force-refid to send it anyway",
+                stratum, ref_id
+            ));
+        }
+    }
+    if ref_id.len() > 4 || !ref_id.is_ascii() {
+        return Err(format!("refid must be at most 4 ascii characters, got '{}'", ref_id));
+    }
+    let mut ret = [0u8; 4];
+    ret[..ref_id.len()].copy_from_slice(ref_id.as_bytes());
+    Ok(ret)
+}
+
+/// an IPv4 broadcast address like 192.168.1.255, the port defaults to 123
+fn parse_broadcast_addr(addr: &str) -> Result<SocketAddr, String> {
+    let addr = addr
+        .parse::<SocketAddr>()
+        .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, socket::DEFAULT_PORT)))
+        .map_err(|e| format!("invalid broadcast address '{}': {}", addr, e))?;
+    if !addr.is_ipv4() {
+        return Err(format!("broadcast address must be IPv4, got {}", addr));
+    }
+    Ok(addr)
+}
+
+/// allows `rate` packets per second with bursts of the same size
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> TokenBucket {
+        TokenBucket {
+            tokens: rate.max(1.0),
+            last: Instant::now(),
+        }
+    }
+
+    /// now from NtpServer::instant, so a replay runs on the recorded times
+    fn take(&mut self, rate: f64, now: Instant) -> bool {
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * rate).min(rate.max(1.0));
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// responses that failed to send, usually to a full send buffer, summarized at most once a second
+/// because under a flood logging every one would only make things worse
+#[derive(Default)]
+struct SendDrops {
+    /// dropped since the last summary, and when that was
+    state: Mutex<(u64, Option<Instant>)>,
+}
+
+impl SendDrops {
+    /// counts n more, returning how many to report if a second has passed since the last summary
+    fn add(&self, n: u64, now: Instant) -> Option<u64> {
+        let mut state = self.state.lock().expect("send drops poisoned");
+        state.0 += n;
+        match state.1 {
+            Some(last) if now.duration_since(last) < Duration::from_secs(1) => None,
+            _ => {
+                state.1 = Some(now);
+                Some(std::mem::take(&mut state.0))
+            }
+        }
+    }
+}
+
+/// a token bucket per client, at most capacity of them, the client heard from least recently is forgotten to make room
+/// so spoofing many source addresses can't grow it without bound, forgetting one just gives it a full bucket again
+struct RateLimiter {
+    capacity: usize,
+    /// each client's bucket and when it was last heard from
+    buckets: HashMap<IpAddr, (TokenBucket, u64)>,
+    /// clients by when they were last heard from, oldest first
+    order: BTreeMap<u64, IpAddr>,
+    /// counts up with every packet, cheaper to order by than an Instant
+    seq: u64,
+}
+
+impl RateLimiter {
+    fn new(capacity: usize) -> RateLimiter {
+        RateLimiter {
+            capacity,
+            buckets: HashMap::new(),
+            order: BTreeMap::new(),
+            seq: 0,
+        }
+    }
+
+    /// whether ip is still under rate
+    fn take(&mut self, ip: IpAddr, rate: f64, now: Instant) -> bool {
+        self.seq += 1;
+        if let Some((bucket, last)) = self.buckets.get_mut(&ip) {
+            self.order.remove(last);
+            *last = self.seq;
+            self.order.insert(self.seq, ip);
+            return bucket.take(rate, now);
+        }
+        if self.buckets.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.buckets.remove(&oldest);
+            }
+        }
+        let mut bucket = TokenBucket::new(rate);
+        let ret = bucket.take(rate, now);
+        self.buckets.insert(ip, (bucket, self.seq));
+        self.order.insert(self.seq, ip);
+        ret
+    }
+}
+
+/// how long a serving loop pauses after back to back socket errors, doubling each time,
+/// so one that keeps failing can't spin a thread or flood the log
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    const MIN: Duration = Duration::from_millis(1);
+    const MAX: Duration = Duration::from_secs(1);
+
+    fn new() -> Backoff {
+        Backoff { delay: Duration::ZERO }
+    }
+
+    fn reset(&mut self) {
+        self.delay = Duration::ZERO;
+    }
+
+    /// the first error in a row goes straight back to the socket, each after that sleeps longer
+    fn wait(&mut self) {
+        if !self.delay.is_zero() {
+            std::thread::sleep(self.delay);
+        }
+        self.delay = (self.delay * 2).clamp(Backoff::MIN, Backoff::MAX);
+    }
+}
+
+/// what one look at the clock says for a response: the reference, the 2^-32 seconds to add to the system clock, the
+/// time now and the stratum and reference id to advertise
+type Reading = (Reference, i64, NtpTimestamp, (u8, [u8; 4]));
+
+/// a datagram the receiver thread read for a responder to answer, with --queue-depth
+struct Queued {
+    buf: Vec<u8>,
+    remote_addr: SocketAddr,
+    /// None if the clock was before 1900
+    local_ts: Option<NtpTimestamp>,
+    truncated: bool,
+    #[cfg(target_os = "linux")]
+    pktinfo: Option<mmsg::PktInfo>,
+    received: Instant,
+}
+
+#[derive(Debug)]
+enum Response {
+    Time(NtpPacket),
+    /// Kiss-o'-Death packet, stratum 0 with the kiss code as ref_id
+    KissOfDeath(NtpPacket),
+}
+
+/// options for an NtpServer named as they are on the command line, see kiss-ntpd --help, and parsed the same way
+pub struct NtpServerBuilder {
+    addr: SocketAddr,
+    args: Vec<String>,
+    clock: Option<Arc<dyn TimeSource>>,
+}
+
+impl NtpServerBuilder {
+    /// where to listen, default 0.0.0.0:123, port 0 has the kernel pick one that NtpServer::local_addr reports
+    pub fn bind(mut self, addr: SocketAddr) -> NtpServerBuilder {
+        self.addr = addr;
+        self
+    }
+
+    pub fn stratum(self, stratum: u8) -> NtpServerBuilder {
+        self.option("--stratum", stratum)
+    }
+
+    pub fn refid(self, refid: &str) -> NtpServerBuilder {
+        self.option("--refid", refid)
+    }
+
+    /// print every request and response, like --debug
+    pub fn debug(self, debug: bool) -> NtpServerBuilder {
+        if debug {
+            self.flag("--debug")
+        } else {
+            self
+        }
+    }
+
+    pub fn threads(self, threads: usize) -> NtpServerBuilder {
+        self.option("--threads", threads)
+    }
+
+    /// packets per second allowed per client IP
+    pub fn rate_limit(self, rate_limit: f64) -> NtpServerBuilder {
+        self.option("--rate-limit", rate_limit)
+    }
+
+    /// where the served time is read from, SystemClock by default, a MockClock makes every timestamp predictable
+    pub fn clock(mut self, clock: Arc<dyn TimeSource>) -> NtpServerBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// any other option that takes a value, by its long name, like option("--max-poll", 10)
+    pub fn option(mut self, name: &str, value: impl Display) -> NtpServerBuilder {
+        self.args.push(format!("{}={}", name, value));
+        self
+    }
+
+    /// any option that doesn't take a value, by its long name, like flag("--interleaved")
+    pub fn flag(mut self, name: &str) -> NtpServerBuilder {
+        self.args.push(name.to_owned());
+        self
+    }
+
+    /// validates the options and binds, the KISS_NTPD_ environment variables are never read, and only the server itself
+    /// runs, what the binary starts alongside it like --upstream, --peer, --pps, --broadcast, --metrics-addr, --health-addr or --record does nothing here
+    pub fn build(self) -> Result<NtpServer, String> {
+        let addr = self.addr;
+        let mut args = Args::new(self.args).without_env();
+        let config = Config::from_args(&mut args)?;
+        if let Some(arg) = args.remaining()?.first() {
+            return Err(format!("unexpected argument {}", arg));
+        }
+        let reference = Arc::new(Mutex::new(config.system_reference()));
+        let throttle = Arc::new(Mutex::new(TokenBucket::new(config.max_packet_rate)));
+        let live_config = Arc::new(RwLock::new(Arc::new(config)));
+        let mut server = NtpServer::with_state(&addr, live_config, Arc::new(Metrics::default()), reference, throttle).map_err(|e| format!("failed to bind {}: {}", addr, e))?;
+        if let Some(clock) = self.clock {
+            server.clock = clock;
+        }
+        if server.config.require_cookie {
+            server.cookies = Some(Arc::new(cookie::Cookies::new().map_err(|e| e.to_string())?));
+        }
+        Ok(server)
+    }
+}
+
+/// answers every request on one address, from config.threads threads
+pub struct NtpServer {
+    /// None only for --replay, which answers offline
+    socket: Option<UdpSocket>,
+    /// sockets for the rest of the threads, opened up front because we may not have the privileges to later
+    thread_sockets: Vec<UdpSocket>,
+    /// where we ended up bound, with the port the kernel picked if we asked for port 0
+    local_addr: SocketAddr,
+    /// a snapshot of live_config, refreshed for each packet
+    config: Arc<Config>,
+    /// swapped out on SIGHUP
+    live_config: Arc<RwLock<Arc<Config>>>,
+    /// shared by all threads so a client can't get more by landing on different ones
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    /// shared by every server so --max-packet-rate caps what we send in total
+    throttle: Arc<Mutex<TokenBucket>>,
+    /// shared by all threads so they log one summary between them
+    send_drops: Arc<SendDrops>,
+    /// receive and actual transmit timestamps of the last response to each client, for --interleaved
+    transmits: Arc<Mutex<HashMap<IpAddr, (NtpTimestamp, NtpTimestamp)>>>,
+    metrics: Arc<Metrics>,
+    reference: Arc<Mutex<Reference>>,
+    /// what every timestamp we serve is read from
+    clock: Arc<dyn TimeSource>,
+    /// with --record, where every datagram we receive is written, set by the binary so all servers share one file
+    recorder: Option<Arc<record::Recorder>>,
+    /// with --require-cookie, the secret cookies are made with, set by the binary so every server hands out the same ones
+    cookies: Option<Arc<cookie::Cookies>>,
+    /// with --replay, a real instant standing for the first recorded receive time, so the rate limiter and throttle
+    /// run on the recorded times
+    replay_start: Option<(Instant, NtpTimestamp)>,
+}
+
+impl NtpServer {
+    pub fn builder() -> NtpServerBuilder {
+        NtpServerBuilder {
+            addr: SocketAddr::from(([0, 0, 0, 0], socket::DEFAULT_PORT)),
+            args: Vec::new(),
+            clock: None,
+        }
+    }
+
+    /// a server on addr with every other option at its default, for anything more use builder
+    pub fn new(addr: SocketAddr, debug: bool) -> Result<NtpServer, String> {
+        NtpServer::builder().bind(addr).debug(debug).build()
+    }
+
+    /// binds addr, sharing the config, metrics, reference and throttle with the servers on the other bind addresses
+    pub(crate) fn with_state(
+        addr: &SocketAddr,
+        live_config: Arc<RwLock<Arc<Config>>>,
+        metrics: Arc<Metrics>,
+        reference: Arc<Mutex<Reference>>,
+        throttle: Arc<Mutex<TokenBucket>>,
+    ) -> std::io::Result<NtpServer> {
+        let config = live_config.read().expect("config poisoned").clone();
+        let socket = socket::bind(addr, config.reuseport, config.bind_device.as_deref())?;
+        let local_addr = socket.local_addr()?;
+        info!("listening on {}", local_addr);
+        // either a new SO_REUSEPORT socket on the same address or a handle on the same socket
+        let thread_sockets: Vec<UdpSocket> = (1..config.threads)
+            .map(|_| {
+                if config.reuseport {
+                    socket::bind(&local_addr, true, config.bind_device.as_deref())
+                } else {
+                    socket.try_clone()
+                }
+            })
+            .collect::<std::io::Result<_>>()?;
+        if config.rcvbuf.is_some() || config.sndbuf.is_some() {
+            let mut granted = (0, 0);
+            for socket in thread_sockets.iter().chain(std::iter::once(&socket)) {
+                granted = socket::set_buffer_sizes(socket, config.rcvbuf, config.sndbuf)?;
+            }
+            info!("{} receive buffer {} bytes, send buffer {} bytes", local_addr, granted.0, granted.1);
+        }
+        if let Some(dscp) = config.dscp {
+            let mut tos = 0;
+            for socket in thread_sockets.iter().chain(std::iter::once(&socket)) {
+                tos = socket::set_dscp(socket, dscp).map_err(|e| std::io::Error::new(e.kind(), format!("could not set DSCP: {}", e)))?;
+            }
+            info!(
+                "{} marking packets DSCP {}, {} {:#04x}",
+                local_addr,
+                tos >> 2,
+                if local_addr.is_ipv4() { "TOS" } else { "traffic class" },
+                tos
+            );
+        }
+        #[cfg(target_os = "linux")]
+        if config.kernel_timestamps {
+            for socket in thread_sockets.iter().chain(std::iter::once(&socket)) {
+                mmsg::enable_rx_timestamps(socket).map_err(|e| std::io::Error::new(e.kind(), format!("could not enable kernel timestamps: {}", e)))?;
+            }
+        }
+        // on a wildcard address the kernel would pick the reply's source by route, which on a multi-homed host
+        // needn't be the address the client asked
+        #[cfg(target_os = "linux")]
+        if local_addr.ip().is_unspecified() {
+            for socket in thread_sockets.iter().chain(std::iter::once(&socket)) {
+                mmsg::enable_pktinfo(socket).map_err(|e| std::io::Error::new(e.kind(), format!("could not enable packet info: {}", e)))?;
+            }
+        }
+        let rate_limiter = RateLimiter::new(config.rate_limit_table_size);
+        let clock = config.time_source();
+        Ok(NtpServer {
+            socket: Some(socket),
+            thread_sockets,
+            local_addr,
+            config,
+            live_config,
+            rate_limiter: Arc::new(Mutex::new(rate_limiter)),
+            throttle,
+            send_drops: Arc::new(SendDrops::default()),
+            transmits: Arc::new(Mutex::new(HashMap::new())),
+            metrics,
+            reference,
+            clock,
+            recorder: None,
+            cookies: None,
+            replay_start: None,
+        })
+    }
+
+    /// --replay, a server with no socket reading clock, which starts at start, the first recorded receive time
+    pub(crate) fn offline(config: Config, clock: Arc<dyn TimeSource>, start: NtpTimestamp) -> NtpServer {
+        // last set as the recording started, not as the replay does
+        let reference = Reference {
+            ref_ts: start,
+            ..config.system_reference()
+        };
+        let (rate_limiter, throttle) = (RateLimiter::new(config.rate_limit_table_size), TokenBucket::new(config.max_packet_rate));
+        // a secret of our own would print different cookies every replay
+        let cookies = if config.require_cookie { Some(Arc::new(cookie::Cookies::fixed())) } else { None };
+        let config = Arc::new(config);
+        NtpServer {
+            socket: None,
+            thread_sockets: Vec::new(),
+            local_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            live_config: Arc::new(RwLock::new(config.clone())),
+            config,
+            rate_limiter: Arc::new(Mutex::new(rate_limiter)),
+            throttle: Arc::new(Mutex::new(throttle)),
+            send_drops: Arc::new(SendDrops::default()),
+            transmits: Arc::new(Mutex::new(HashMap::new())),
+            metrics: Arc::new(Metrics::default()),
+            reference: Arc::new(Mutex::new(reference)),
+            clock,
+            recorder: None,
+            cookies,
+            replay_start: Some((Instant::now(), start)),
+        }
+    }
+
+    fn socket(&self) -> &UdpSocket {
+        self.socket.as_ref().expect("replay servers never touch the network")
+    }
+
+    /// now, or with --replay as long after the first recorded datagram as the clock says
+    fn instant(&self) -> Instant {
+        match (self.replay_start, self.clock.now()) {
+            (Some((instant, start)), Ok(now)) => instant + Duration::from_secs_f64((now.as_secs_f64() - start.as_secs_f64()).max(0.0)),
+            (Some((instant, _)), Err(_)) => instant,
+            (None, _) => Instant::now(),
+        }
+    }
+
+    /// where we're listening, with the port the kernel picked if we asked for port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// picks up a config swapped in by a reload, only an Arc clone when there wasn't one
+    fn refresh_config(&mut self) {
+        self.config = self.live_config.read().expect("config poisoned").clone();
+    }
+
+    fn with_socket(&self, socket: UdpSocket) -> NtpServer {
+        NtpServer {
+            socket: Some(socket),
+            thread_sockets: Vec::new(),
+            local_addr: self.local_addr,
+            config: self.config.clone(),
+            live_config: self.live_config.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            throttle: self.throttle.clone(),
+            send_drops: self.send_drops.clone(),
+            transmits: self.transmits.clone(),
+            metrics: self.metrics.clone(),
+            reference: self.reference.clone(),
+            clock: self.clock.clone(),
+            recorder: self.recorder.clone(),
+            cookies: self.cookies.clone(),
+            replay_start: self.replay_start,
+        }
+    }
+
+    /// record::request, writing the datagram to --record first whatever it turns out to be
+    fn received(&self, buf: &[u8], remote_addr: SocketAddr, local_ts: Option<NtpTimestamp>, truncated: bool) -> Result<NtpPacket, NtpError> {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(buf, remote_addr, local_ts, truncated);
+        }
+        record::request(buf, remote_addr, local_ts, truncated)
+    }
+
+    /// everything between receiving a datagram from wherever the socket said and sending the reply, counting and logging
+    /// what happened
+    fn handle(&mut self, request: Result<NtpPacket, NtpError>, from: Option<SocketAddr>) -> Option<NtpPacket> {
+        self.refresh_config();
+        Metrics::inc(&self.metrics.requests);
+        match &request {
+            Ok(NtpPacket { mode, .. }) | Err(NtpError::ControlQuery(mode, _)) => Metrics::inc(&self.metrics.modes[*mode as usize & 0x7]),
+            Err(NtpError::PacketTooShort(_) | NtpError::UnsupportedVersion(_) | NtpError::MalformedExtension | NtpError::PacketTruncated) => Metrics::inc(&self.metrics.malformed),
+            Err(NtpError::EmptyDatagram(_)) => {
+                Metrics::inc(&self.metrics.malformed);
+                Metrics::inc(&self.metrics.empty);
+            }
+            Err(_) => {}
+        }
+        if let Ok(request) = &request {
+            if !self.config.acl.allows(request.remote_addr.ip()) {
+                Metrics::inc(&self.metrics.packets_dropped);
+                Metrics::inc(&self.metrics.acl_dropped);
+                if self.config.log_dropped {
+                    debug!("dropped request from {} denied by --allow/--deny", request.remote_addr);
+                }
+                return None;
+            }
+            if let Some(port_filter) = &self.config.port_filter {
+                if !port_filter.allows(request.remote_addr.port()) {
+                    Metrics::inc(&self.metrics.packets_dropped);
+                    Metrics::inc(&self.metrics.port_dropped);
+                    if self.config.log_dropped {
+                        debug!("dropped request from {} refused by --client-port-filter", request.remote_addr);
+                    }
+                    return None;
+                }
+            }
+            if !(self.config.min_version..=self.config.max_version).contains(&request.version) {
+                Metrics::inc(&self.metrics.packets_dropped);
+                Metrics::inc(&self.metrics.version_dropped);
+                if self.config.log_dropped {
+                    debug!("dropped version {} request from {}", request.version, request.remote_addr);
+                }
+                return None;
+            }
+            if matches!(request.mode, 1 | 3) && !self.config.allow_modes.contains(&request.mode) {
+                Metrics::inc(&self.metrics.packets_dropped);
+                Metrics::inc(&self.metrics.mode_dropped);
+                if self.config.log_dropped {
+                    debug!("dropped mode {} request from {} refused by --allow-modes", request.mode, request.remote_addr);
+                }
+                return None;
+            }
+        }
+        let response = request.and_then(|request| {
+            let response = self.make_response(&request)?;
+            if self.config.debug {
+                self.log_event(&request, &response);
+            }
+            Ok(response)
+        });
+        match response {
+            Ok(Response::Time(packet)) | Ok(Response::KissOfDeath(packet)) if self.throttled() => {
+                Metrics::inc(&self.metrics.packets_dropped);
+                Metrics::inc(&self.metrics.throttled);
+                if self.config.log_dropped {
+                    debug!("dropped response to {} over --max-packet-rate", packet.remote_addr);
+                }
+                None
+            }
+            Ok(Response::KissOfDeath(packet)) if packet.ref_id == u32::from_be_bytes(*b"CKIE") => {
+                Metrics::inc(&self.metrics.cookies_sent);
+                if self.config.log_dropped {
+                    debug!("sent CKIE instead of the time to {}", packet.remote_addr);
+                }
+                Some(packet)
+            }
+            Ok(Response::Time(packet)) | Ok(Response::KissOfDeath(packet)) => Some(packet),
+            Err(NtpError::ControlQuery(mode, remote_addr)) => {
+                Metrics::inc(&self.metrics.packets_dropped);
+                Metrics::inc(&self.metrics.control_dropped);
+                if self.config.log_dropped {
+                    debug!("dropped mode {} control query from {}", mode, remote_addr);
+                }
+                None
+            }
+            Err(e) => {
+                Metrics::inc(&self.metrics.packets_dropped);
+                match e {
+                    NtpError::PacketTruncated => Metrics::inc(&self.metrics.truncated_dropped),
+                    NtpError::UnexpectedResponse(_) => Metrics::inc(&self.metrics.unexpected_responses),
+                    NtpError::Amplification(..) => Metrics::inc(&self.metrics.amplification_dropped),
+                    NtpError::BadCookie(_) => Metrics::inc(&self.metrics.cookie_dropped),
+                    _ => {}
+                }
+                self.log_error(&e, from);
+                None
+            }
+        }
+    }
+
+    /// the debug output for a request we answered, in whichever format was configured
+    fn log_event(&self, request: &NtpPacket, response: &Response) {
+        let (packet, kiss_code) = match response {
+            Response::Time(packet) => (packet, None),
+            Response::KissOfDeath(packet) => (packet, Some(String::from_utf8_lossy(&packet.ref_id.to_be_bytes()).into_owned())),
+        };
+        match self.config.log_format {
+            LogFormat::Text => {
+                trace!("request: {:?}", request);
+                if let Some(kiss_code) = kiss_code {
+                    debug!("sending {} Kiss-o'-Death to {}", kiss_code, packet.remote_addr);
+                }
+                trace!("response: {:?}", packet);
+            }
+            LogFormat::Json => debug!(
+                r#"{{"remote_addr":"{}","mode":{},"version":{},"stratum_out":{},"kiss_code":{},"rx_ts":"{}","tx_ts":"{}"}}"#,
+                request.remote_addr,
+                request.mode,
+                request.version,
+                packet.stratum,
+                kiss_code.map_or("null".to_owned(), |k| format!("\"{}\"", k)),
+                packet.rx_ts.to_iso8601(),
+                packet.tx_ts.to_iso8601()
+            ),
+        }
+    }
+
+    /// from is where the datagram came from, when we know
+    fn log_error(&self, e: &NtpError, from: Option<SocketAddr>) {
+        Metrics::inc(&self.metrics.errors);
+        match (e, from) {
+            (NtpError::Io(e), _) => error!("{}", e),
+            (NtpError::ClockBeforeEpoch, _) => warn!("{}", e),
+            (e, Some(from)) if self.config.verbose_errors => warn!("dropped packet from {}: {}", from, e),
+            (e, _) => {
+                if self.config.debug {
+                    debug!("dropped packet: {}", e);
+                }
+            }
+        }
+    }
+
+    fn respond(&mut self) -> Result<(), NtpError> {
+        // one byte spare, so a datagram that fills the buffer was too long and cut short
+        let mut buf = [0u8; packet::MAX_PACKET_LEN + 1];
+        // nothing was received
+        let (len, remote_addr) = self.socket().recv_from(&mut buf)?;
+        let local_ts = self.clock.now().ok();
+        let request = self.received(&buf[..len], remote_addr, local_ts, len > packet::MAX_PACKET_LEN);
+        let received = self.config.stats.map(|_| Instant::now());
+        if let Some(packet) = self.handle(request, Some(remote_addr)) {
+            self.test_delay();
+            if let Err(e) = packet.send(self.socket()) {
+                self.send_failed(1, &e);
+                return Ok(());
+            }
+            if let Some(received) = received {
+                self.metrics.latency.record(received.elapsed());
+            }
+            self.record_transmit(&packet);
+            Metrics::inc(&self.metrics.responses);
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn respond_batch(&mut self, batch: &mut mmsg::Batch) -> Result<(), NtpError> {
+        let count = batch.recv(self.socket())?;
+        let received = self.config.stats.map(|_| Instant::now());
+        // used when the kernel didn't timestamp the packet for us
+        let local_ts = self.clock.now().ok();
+
+        let mut responses = Vec::with_capacity(count);
+        let mut packets = Vec::with_capacity(count);
+        for i in 0..count {
+            let packet = batch.packet(i).map_err(NtpError::Io);
+            let from = packet.as_ref().ok().map(|(_, remote_addr, _)| *remote_addr);
+            let request = packet.and_then(|(buf, remote_addr, rx_ts)| self.received(buf, remote_addr, rx_ts.map(NtpTimestamp::from_unix).or(local_ts), batch.truncated(i)));
+            if let Some(packet) = self.handle(request, from) {
+                responses.push((packet.to_bytes(), packet.remote_addr, batch.pktinfo(i)));
+                packets.push(packet);
+            }
+        }
+
+        if !responses.is_empty() {
+            self.test_delay();
+        }
+        let (sent, error) = mmsg::send(self.socket(), &responses);
+        // every packet in the batch waited for the whole batch
+        if let Some(received) = received {
+            let elapsed = received.elapsed();
+            for _ in 0..sent.len() {
+                self.metrics.latency.record(elapsed);
+            }
+        }
+        // a client whose response didn't go out has no transmit timestamp to be told next time
+        for &i in &sent {
+            self.record_transmit(&packets[i]);
+        }
+        Metrics::add(&self.metrics.responses, sent.len() as u64);
+        if let Some(e) = error {
+            self.send_failed((responses.len() - sent.len()) as u64, &e);
+        }
+        Ok(())
+    }
+
+    fn rate_limited(&mut self, ip: IpAddr) -> bool {
+        let rate = self.config.rate_limit;
+        if rate == 0.0 {
+            return false;
+        }
+        let now = self.instant();
+        let mut rate_limiter = self.rate_limiter.lock().expect("rate limiter poisoned");
+        !rate_limiter.take(ip, rate, now)
+    }
+
+    /// --test-delay-ms, only for testing clients against a slow server
+    fn test_delay(&self) {
+        if !self.config.test_delay.is_zero() {
+            std::thread::sleep(self.config.test_delay);
+        }
+    }
+
+    /// counts responses we couldn't send instead of returning the error to be logged one by one
+    fn send_failed(&self, n: u64, e: &std::io::Error) {
+        Metrics::add(&self.metrics.errors, n);
+        Metrics::add(&self.metrics.packets_dropped, n);
+        Metrics::add(&self.metrics.send_dropped, n);
+        // a short send means something is badly wrong with the network stack, worth hearing about every time
+        if e.kind() == std::io::ErrorKind::WriteZero {
+            error!("{}", e);
+        } else if let Some(dropped) = self.send_drops.add(n, Instant::now()) {
+            warn!("dropped {} responses in the last second, could not send: {}", dropped, e);
+        }
+    }
+
+    /// whether sending another response would go over --max-packet-rate
+    fn throttled(&self) -> bool {
+        let rate = self.config.max_packet_rate;
+        if rate == 0.0 {
+            return false;
+        }
+        let now = self.instant();
+        !self.throttle.lock().expect("throttle poisoned").take(rate, now)
+    }
+
+    /// remembers when a response actually went out, which an interleaved response to the client's next request reports
+    fn record_transmit(&self, packet: &NtpPacket) {
+        if !self.config.interleaved {
+            return;
+        }
+        if let Ok(now) = self.clock.now() {
+            let now = now.offset(self.time_offset(&self.reference(), now));
+            let mut transmits = self.transmits.lock().expect("transmits poisoned");
+            transmits.insert(packet.remote_addr.ip(), (packet.rx_ts, now));
+        }
+    }
+
+    /// a client asks for interleaved mode by sending our last receive timestamp back as its origin timestamp,
+    /// if it matches, the transmit timestamp of that last response
+    fn interleaved_transmit(&self, request: &NtpPacket) -> Option<NtpTimestamp> {
+        if !self.config.interleaved || request.orig_ts == NtpTimestamp::zero() || request.rx_ts == request.tx_ts {
+            return None;
+        }
+        let transmits = self.transmits.lock().expect("transmits poisoned");
+        match transmits.get(&request.remote_addr.ip()) {
+            Some((rx_ts, tx_ts)) if *rx_ts == request.orig_ts => Some(*tx_ts),
+            _ => None,
+        }
+    }
+
+    fn reference(&self) -> Reference {
+        *self.reference.lock().expect("reference poisoned")
+    }
+
+    /// 2^-32 seconds to add to the system clock, which read local, what upstream or PPS measured plus how far --leap-smear
+    /// has taken us
+    fn time_offset(&self, reference: &Reference, local: NtpTimestamp) -> i64 {
+        match (self.config.leap_smear, &self.config.leap_seconds) {
+            (Some(window), Some(leap_seconds)) => {
+                let smear = leap_seconds.smear(local.offset(reference.offset).as_secs_f64(), window as f64);
+                reference.offset + (smear * 4294967296.0) as i64
+            }
+            _ => reference.offset,
+        }
+    }
+
+    /// seconds at now, the maximum once our reference has gone stale
+    fn root_dispersion(&self, reference: &Reference, now: NtpTimestamp) -> f64 {
+        if reference.stale(now, self.config.max_ref_age) {
+            upstream::MAX_DISPERSION
+        } else {
+            reference.root_dispersion(now)
+        }
+    }
+
+    /// a source that isn't ready yet, an unsynchronized clock or stale reference wins, then an explicit --leap, otherwise whatever the leap seconds file says is coming up,
+    /// unless we're smearing it
+    fn leap(&self, reference: &Reference, now: NtpTimestamp) -> Leap {
+        if !health::synchronized(reference, now, self.config.max_ref_age) {
+            return Leap::Alarm;
+        }
+        if self.config.leap_smear.is_some() {
+            return Leap::None;
+        }
+        match (self.config.leap, &self.config.leap_seconds) {
+            (Leap::None, Some(leap_seconds)) => leap_seconds.indicator(now.secs()),
+            (leap, _) => leap,
+        }
+    }
+
+    /// one reading for the whole response, what it says about our clock and when it went out
+    fn reading(&self) -> Result<Reading, NtpError> {
+        let reference = self.reference();
+        let local = self.clock.now()?;
+        let offset = self.time_offset(&reference, local);
+        Ok((reference, offset, local.offset(offset), reference.advertised()))
+    }
+
+    fn make_response(&mut self, request: &NtpPacket) -> Result<Response, NtpError> {
+        let mode = match request.classify() {
+            RequestKind::ClientRequest => 4,
+            RequestKind::SymmetricActive => 2,
+            RequestKind::ServerResponse => return Err(NtpError::UnexpectedResponse(request.mode)),
+            // parse turns these away first, but they must never be answered however we came by them
+            RequestKind::Control => return Err(NtpError::ControlQuery(request.mode, request.remote_addr)),
+            RequestKind::Unknown => return Err(NtpError::NotRequest(request.mode)),
+        };
+        let mode = self.config.force_mode.unwrap_or(mode);
+
+        let (reference, offset, now, (stratum, ref_id)) = self.reading()?;
+        let mut response = NtpPacket {
+            remote_addr: request.remote_addr,
+            local_ts: NtpTimestamp::zero(),
+            leap: self.leap(&reference, now) as u8,
+            version: request.version,
+            mode,
+            stratum,
+            poll: if self.config.echo_poll {
+                request.poll.clamp(self.config.min_poll, self.config.max_poll)
+            } else {
+                self.config.poll
+            },
+            precision: self.config.precision,
+            delay: NtpFracValue::from_secs(reference.root_delay),
+            dispersion: NtpFracValue::from_secs(self.root_dispersion(&reference, now)),
+            ref_id: u32::from_be_bytes(ref_id),
+            ref_ts: reference.ref_ts,
+            orig_ts: request.tx_ts,
+            rx_ts: request.local_ts.offset(offset),
+            tx_ts: now,
+            extensions: Vec::new(),
+            mac: None,
+        };
+
+        if let Some(tx_ts) = self.interleaved_transmit(request) {
+            response.orig_ts = request.rx_ts;
+            response.tx_ts = tx_ts;
+        }
+
+        // a zero transmit timestamp, sent by simple probes that only want the time, goes back as a zero origin
+        // timestamp like any other, a client checking it against what it sent still matches
+        if self.config.debug && self.config.log_format == LogFormat::Text && request.tx_ts == NtpTimestamp::zero() {
+            debug!("request from {} has a zero transmit timestamp", request.remote_addr);
+        }
+
+        if self.config.debug && self.config.log_format == LogFormat::Text && self.config.echo_poll && response.poll != request.poll {
+            debug!("clamped poll {} to {} for {}", request.poll, response.poll, request.remote_addr);
+        }
+
+        let mut kiss_code = if self.rate_limited(request.remote_addr.ip()) { Some(*b"RATE") } else { None };
+
+        let nts_session = match &self.config.nts {
+            Some(nts) if nts::is_nts(request) => Some(nts.verify(request)),
+            _ => None,
+        };
+        if let Some(None) = nts_session {
+            if self.config.debug {
+                debug!("sending NTS NAK to {}, could not verify NTS request", request.remote_addr);
+            }
+            kiss_code = Some(*b"NTSN");
+            response.extensions = nts::unique_id(request);
+        }
+
+        // NTS proves the client is who it says already
+        if let (Some(cookies), None) = (&self.cookies, &nts_session) {
+            match cookies.check(request) {
+                cookie::Check::Valid => {}
+                // whatever else the Kiss-o'-Death says, no cookie with it
+                cookie::Check::Missing | cookie::Check::Placeholder if kiss_code.is_some() => {}
+                // a standard client would never send it back, so it only costs a spoofed victim bandwidth
+                cookie::Check::Missing => kiss_code = Some(*b"CKIE"),
+                cookie::Check::Placeholder => {
+                    if self.config.debug {
+                        debug!("sending a cookie to {}", request.remote_addr);
+                    }
+                    kiss_code = Some(*b"CKIE");
+                    response.extensions.push(cookies.field(request.remote_addr.ip()));
+                }
+                cookie::Check::Invalid => return Err(NtpError::BadCookie(request.remote_addr)),
+            }
+        }
+
+        if let Some(kiss_code) = kiss_code {
+            response.stratum = 0;
+            response.ref_id = u32::from_be_bytes(kiss_code);
+        }
+
+        // last, the MAC or NTS authenticator covers everything else
+        let authenticated = match (&self.config.nts, nts_session) {
+            (Some(nts), Some(Some(session))) => {
+                nts.seal(&session, &mut response)?;
+                true
+            }
+            (_, Some(None)) => false,
+            _ => {
+                response.mac = self.authenticate(request, &response);
+                // a crypto-NAK has an empty digest
+                response.mac.as_ref().is_some_and(|(_, digest)| !digest.is_empty())
+            }
+        };
+        if !authenticated {
+            check_amplification(request, &response)?;
+        }
+
+        Ok(if kiss_code.is_some() { Response::KissOfDeath(response) } else { Response::Time(response) })
+    }
+
+    /// the MAC for a response to an authenticated request, or a crypto-NAK if we can't verify the request,
+    /// without --keyfile MACs are ignored
+    fn authenticate(&self, request: &NtpPacket, response: &NtpPacket) -> Option<(u32, Vec<u8>)> {
+        let keys = self.config.keys.as_ref()?;
+        let (key_id, digest) = request.mac.as_ref()?;
+        if !keys.verify(*key_id, &request.mac_data(), digest) {
+            if self.config.debug {
+                debug!("sending crypto-NAK to {}, could not verify MAC with key {}", request.remote_addr, key_id);
+            }
+            return Some((0, Vec::new()));
+        }
+        keys.mac(*key_id, &response.mac_data()).map(|digest| (*key_id, digest))
+    }
+
+    /// an unsolicited mode 5 packet for broadcast clients, who use our transmit timestamp alone
+    fn broadcast_packet(&self, remote_addr: SocketAddr) -> Result<NtpPacket, NtpError> {
+        let (reference, _, now, (stratum, ref_id)) = self.reading()?;
+        Ok(NtpPacket {
+            remote_addr,
+            local_ts: NtpTimestamp::zero(),
+            leap: self.leap(&reference, now) as u8,
+            version: 4,
+            mode: 5,
+            stratum,
+            // log2 of the interval, rounded down
+            poll: (63 - self.config.broadcast_interval.as_secs().leading_zeros()) as i8,
+            precision: self.config.precision,
+            delay: NtpFracValue::from_secs(reference.root_delay),
+            dispersion: NtpFracValue::from_secs(self.root_dispersion(&reference, now)),
+            ref_id: u32::from_be_bytes(ref_id),
+            ref_ts: reference.ref_ts,
+            orig_ts: NtpTimestamp::zero(),
+            rx_ts: NtpTimestamp::zero(),
+            tx_ts: now,
+            extensions: Vec::new(),
+            mac: None,
+        })
+    }
+
+    /// sends a broadcast or multicast announcement to addr every config.broadcast_interval until shutdown
+    fn announce(mut self, addr: SocketAddr) {
+        while !signal::shutdown_requested() {
+            self.refresh_config();
+            match self.broadcast_packet(addr).and_then(|packet| Ok(packet.send(self.socket())?)) {
+                Ok(_) => Metrics::inc(&self.metrics.responses),
+                Err(e) => self.log_error(&e, None),
+            }
+            // sleep in short steps so we notice shutdown requests
+            let next = Instant::now() + self.config.broadcast_interval;
+            while !signal::shutdown_requested() && Instant::now() < next {
+                std::thread::sleep(Duration::from_secs(1).min(next.saturating_duration_since(Instant::now())));
+            }
+        }
+    }
+
+    /// joins the NTP multicast group on every socket for this address so manycast clients find us,
+    /// returning the group to announce to
+    fn join_multicast(&self) -> std::io::Result<SocketAddr> {
+        let group = socket::join_multicast(self.socket())?;
+        for socket in &self.thread_sockets {
+            socket::join_multicast(socket)?;
+        }
+        Ok(group)
+    }
+
+    /// runs config.threads workers all receiving on the same address, the kernel spreads packets between them
+    pub fn run(mut self) {
+        if self.config.queue_depth > 0 {
+            return self.run_queued();
+        }
+        let threads: Vec<_> = std::mem::take(&mut self.thread_sockets)
+            .into_iter()
+            .map(|socket| {
+                let server = self.with_socket(socket);
+                std::thread::spawn(|| server.process_requests())
+            })
+            .collect();
+
+        self.process_requests();
+
+        for thread in threads {
+            thread.join().expect("server thread panicked");
+        }
+    }
+
+    /// with --queue-depth, this thread only receives and config.threads responders answer what it queues
+    fn run_queued(mut self) {
+        let queue = Arc::new(queue::Queue::new(self.config.queue_depth));
+        let mut sockets = std::mem::take(&mut self.thread_sockets);
+        match self.socket().try_clone() {
+            Ok(socket) => sockets.push(socket),
+            Err(e) => {
+                error!("could not clone socket: {}", e);
+                Metrics::inc(&self.metrics.stopped);
+                return;
+            }
+        }
+        let responders: Vec<_> = sockets
+            .into_iter()
+            .map(|socket| {
+                let server = self.with_socket(socket);
+                let queue = queue.clone();
+                std::thread::spawn(move || server.answer_queued(&queue))
+            })
+            .collect();
+
+        self.receive_queued(&queue);
+        queue.close();
+
+        for responder in responders {
+            responder.join().expect("responder thread panicked");
+        }
+    }
+
+    /// receives until shutdown, queueing every datagram and dropping the oldest when the responders can't keep up
+    fn receive_queued(&mut self, queue: &queue::Queue<Queued>) {
+        // wake up regularly even when idle so we notice shutdown requests
+        if let Err(e) = self.socket().set_read_timeout(Some(Duration::from_secs(1))) {
+            error!("could not set read timeout: {}", e);
+        }
+        #[cfg(target_os = "linux")]
+        let wildcard = self.local_addr.ip().is_unspecified();
+        #[cfg(target_os = "linux")]
+        let mut batch = if self.config.batch_size > 1 || self.config.kernel_timestamps || wildcard {
+            Some(mmsg::Batch::new(self.config.batch_size))
+        } else {
+            None
+        };
+        let mut buf = [0u8; packet::MAX_PACKET_LEN + 1];
+        let mut received = Vec::new();
+        let mut backoff = Backoff::new();
+        while !signal::shutdown_requested() {
+            #[cfg(target_os = "linux")]
+            let ret = match batch.as_mut() {
+                Some(batch) => batch.recv(self.socket()).map(|count| {
+                    let now = Instant::now();
+                    // used when the kernel didn't timestamp the packet for us
+                    let local_ts = self.clock.now().ok();
+                    for i in 0..count {
+                        if let Ok((data, remote_addr, rx_ts)) = batch.packet(i) {
+                            received.push(Queued {
+                                buf: data.to_vec(),
+                                remote_addr,
+                                local_ts: rx_ts.map(NtpTimestamp::from_unix).or(local_ts),
+                                truncated: batch.truncated(i),
+                                pktinfo: batch.pktinfo(i),
+                                received: now,
+                            });
+                        }
+                    }
+                }),
+                None => self.receive_one(&mut buf).map(|queued| received.push(queued)),
+            };
+            #[cfg(not(target_os = "linux"))]
+            let ret = self.receive_one(&mut buf).map(|queued| received.push(queued));
+            match ret.map_err(NtpError::Io) {
+                Ok(()) => backoff.reset(),
+                Err(e) if e.is_transient() => {}
+                Err(e) if e.is_fatal() => {
+                    error!("stopped serving {}: {}", self.local_addr, e);
+                    Metrics::inc(&self.metrics.stopped);
+                    break;
+                }
+                Err(e) => {
+                    self.log_error(&e, None);
+                    backoff.wait();
+                }
+            }
+            for queued in received.drain(..) {
+                if queue.push(queued).is_some() {
+                    Metrics::inc(&self.metrics.requests);
+                    Metrics::inc(&self.metrics.packets_dropped);
+                    Metrics::inc(&self.metrics.queue_dropped);
+                }
+            }
+        }
+    }
+
+    fn receive_one(&self, buf: &mut [u8]) -> std::io::Result<Queued> {
+        let (len, remote_addr) = self.socket().recv_from(buf)?;
+        Ok(Queued {
+            buf: buf[..len.min(packet::MAX_PACKET_LEN)].to_vec(),
+            remote_addr,
+            local_ts: self.clock.now().ok(),
+            truncated: len > packet::MAX_PACKET_LEN,
+            #[cfg(target_os = "linux")]
+            pktinfo: None,
+            received: Instant::now(),
+        })
+    }
+
+    /// with --cpu-affinity, pins the calling worker thread to the next core
+    fn pin_worker(&self) {
+        #[cfg(target_os = "linux")]
+        if self.config.cpu_affinity {
+            match affinity::pin_next_worker() {
+                Ok(core) if self.config.debug => debug!("worker for {} pinned to cpu {}", self.local_addr, core),
+                Ok(_) => {}
+                Err(e) => warn!("could not pin worker for {} to a cpu: {}", self.local_addr, e),
+            }
+        }
+    }
+
+    /// answers queued requests until shutdown
+    fn answer_queued(mut self, queue: &queue::Queue<Queued>) {
+        self.pin_worker();
+        let mut backoff = Backoff::new();
+        while !signal::shutdown_requested() && !queue.is_closed() {
+            // wake up regularly even when idle so we notice shutdown requests
+            if let Some(queued) = queue.pop(Duration::from_secs(1)) {
+                match self.respond_queued(queued) {
+                    Ok(()) => backoff.reset(),
+                    Err(e) if e.is_fatal() => {
+                        error!("stopped serving {}: {}", self.local_addr, e);
+                        Metrics::inc(&self.metrics.stopped);
+                        break;
+                    }
+                    Err(e @ NtpError::Io(_)) => {
+                        self.log_error(&e, None);
+                        backoff.wait();
+                    }
+                    Err(e) => self.log_error(&e, None),
+                }
+            }
+        }
+    }
+
+    fn respond_queued(&mut self, queued: Queued) -> Result<(), NtpError> {
+        let request = self.received(&queued.buf, queued.remote_addr, queued.local_ts, queued.truncated);
+        if let Some(packet) = self.handle(request, Some(queued.remote_addr)) {
+            self.test_delay();
+            #[cfg(target_os = "linux")]
+            let sent = match queued.pktinfo {
+                Some(pktinfo) => mmsg::send(self.socket(), &[(packet.to_bytes(), packet.remote_addr, Some(pktinfo))]).1.map_or(Ok(()), Err),
+                None => packet.send(self.socket()).map(|_| ()),
+            };
+            #[cfg(not(target_os = "linux"))]
+            let sent = packet.send(self.socket()).map(|_| ());
+            if let Err(e) = sent {
+                self.send_failed(1, &e);
+                return Ok(());
+            }
+            if self.config.stats.is_some() {
+                self.metrics.latency.record(queued.received.elapsed());
+            }
+            self.record_transmit(&packet);
+            Metrics::inc(&self.metrics.responses);
+        }
+        Ok(())
+    }
+
+    fn process_requests(mut self) {
+        self.pin_worker();
+        // wake up regularly even when idle so we notice shutdown requests
+        if let Err(e) = self.socket().set_read_timeout(Some(Duration::from_secs(1))) {
+            error!("could not set read timeout: {}", e);
+        }
+        // a batch of one still gets us the packet info recv_from can't
+        #[cfg(target_os = "linux")]
+        let wildcard = self.local_addr.ip().is_unspecified();
+        #[cfg(target_os = "linux")]
+        let mut batch = if self.config.batch_size > 1 || self.config.kernel_timestamps || wildcard {
+            Some(mmsg::Batch::new(self.config.batch_size))
+        } else {
+            None
+        };
+        let mut backoff = Backoff::new();
+        while !signal::shutdown_requested() {
+            #[cfg(target_os = "linux")]
+            let ret = match batch.as_mut() {
+                Some(batch) => self.respond_batch(batch),
+                None => self.respond(),
+            };
+            #[cfg(not(target_os = "linux"))]
+            let ret = self.respond();
+            match ret {
+                Ok(()) => backoff.reset(),
+                Err(e) if e.is_transient() => {}
+                Err(e) if e.is_fatal() => {
+                    error!("stopped serving {}: {}", self.local_addr, e);
+                    Metrics::inc(&self.metrics.stopped);
+                    break;
+                }
+                // only socket errors, a client can't slow us down by sending garbage
+                Err(e @ NtpError::Io(_)) => {
+                    self.log_error(&e, None);
+                    backoff.wait();
+                }
+                Err(e) => self.log_error(&e, None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::new(args.iter().map(|a| a.to_string()).collect())
+    }
+
+    #[test]
+    fn option_separate_value() {
+        let mut a = args(&["--stratum", "2", "127.0.0.1"]);
+        assert_eq!(a.get_option(&["--stratum"], "KISS_NTPD_TEST_UNSET").unwrap(), Some("2".to_owned()));
+        assert_eq!(a.remaining().unwrap(), vec!["127.0.0.1".to_owned()]);
+    }
+
+    #[test]
+    fn option_equals_value() {
+        let mut a = args(&["--stratum=2", "-u=ntp", "127.0.0.1"]);
+        assert_eq!(a.get_option(&["--stratum"], "KISS_NTPD_TEST_UNSET").unwrap(), Some("2".to_owned()));
+        assert_eq!(a.get_option(&["-u", "--user"], "KISS_NTPD_TEST_UNSET").unwrap(), Some("ntp".to_owned()));
+        assert_eq!(a.remaining().unwrap(), vec!["127.0.0.1".to_owned()]);
+    }
+
+    #[test]
+    fn option_equals_in_value() {
+        let mut a = args(&["--nts-key=a=b", "-c", "x=y.toml"]);
+        assert_eq!(a.get_option(&["--nts-key"], "KISS_NTPD_TEST_UNSET").unwrap(), Some("a=b".to_owned()));
+        assert_eq!(a.get_option(&["-c", "--config"], "KISS_NTPD_TEST_UNSET").unwrap(), Some("x=y.toml".to_owned()));
+        assert!(a.remaining().unwrap().is_empty());
+    }
+
+    #[test]
+    fn option_equals_empty_value() {
+        let mut a = args(&["--refid="]);
+        assert_eq!(a.get_option(&["--refid"], "KISS_NTPD_TEST_UNSET").unwrap(), Some(String::new()));
+    }
+
+    #[test]
+    fn option_prefix_is_not_a_match() {
+        let mut a = args(&["--stratums=2"]);
+        assert_eq!(a.get_option(&["--stratum"], "KISS_NTPD_TEST_UNSET").unwrap(), None);
+        assert!(a.remaining().is_err());
+    }
+
+    #[test]
+    fn all_mixed_forms() {
+        let mut a = args(&["--allow", "10.0.0.0/8", "--allow=192.168.0.0/16", "--allow", "::1"]);
+        assert_eq!(a.get_all(&["--allow"], "KISS_NTPD_TEST_UNSET").unwrap(), vec!["10.0.0.0/8", "192.168.0.0/16", "::1"]);
+        assert!(a.remaining().unwrap().is_empty());
+    }
+
+    #[test]
+    fn clustered_flags() {
+        let mut a = args(&["-dV", "127.0.0.1"]);
+        assert!(a.flag(&["-d", "--debug"]).unwrap());
+        assert!(a.flag(&["-V", "-v", "--version"]).unwrap());
+        assert!(!a.flag(&["-h", "--help"]).unwrap());
+        assert_eq!(a.remaining().unwrap(), vec!["127.0.0.1".to_owned()]);
+    }
+
+    #[test]
+    fn clustered_flags_unknown_letter() {
+        let mut a = args(&["-dq"]);
+        assert!(a.flag(&["-d", "--debug"]).unwrap());
+        assert_eq!(a.remaining().unwrap_err(), "unknown option -q in -dq");
+    }
+
+    #[test]
+    fn clustered_flags_ignore_values() {
+        let mut a = args(&["--precision", "-20", "-d"]);
+        assert_eq!(a.get_option(&["--precision"], "KISS_NTPD_TEST_UNSET").unwrap(), Some("-20".to_owned()));
+        assert!(a.flag(&["-d", "--debug"]).unwrap());
+        assert!(!a.flag(&["--daemonize"]).unwrap());
+        assert!(a.remaining().unwrap().is_empty());
+    }
+
+    #[test]
+    fn all_repeated() {
+        let mut a = args(&["--allow", "10.0.0.0/8", "--deny", "10.0.0.1", "--allow", "::1"]);
+        assert_eq!(a.get_all(&["--allow"], "KISS_NTPD_TEST_UNSET").unwrap(), vec!["10.0.0.0/8", "::1"]);
+        assert_eq!(a.get_all(&["--deny"], "KISS_NTPD_TEST_UNSET").unwrap(), vec!["10.0.0.1"]);
+        assert!(a.remaining().unwrap().is_empty());
+    }
+
+    #[test]
+    fn all_from_env() {
+        std::env::set_var("KISS_NTPD_TEST_ALLOW", "10.0.0.0/8, ::1,,192.168.0.0/16");
+        let mut a = args(&[]);
+        assert_eq!(a.get_all(&["--allow"], "KISS_NTPD_TEST_ALLOW").unwrap(), vec!["10.0.0.0/8", "::1", "192.168.0.0/16"]);
+        let mut a = args(&["--allow", "127.0.0.1"]);
+        assert_eq!(a.get_all(&["--allow"], "KISS_NTPD_TEST_ALLOW").unwrap(), vec!["127.0.0.1"]);
+        assert!(args(&[]).get_all(&["--allow"], "KISS_NTPD_TEST_UNSET").unwrap().is_empty());
+    }
+
+    #[test]
+    fn config_file_layering() {
+        let path = std::env::temp_dir().join(format!("kiss-ntpd-layering-{}.toml", std::process::id()));
+        std::fs::write(&path, "stratum = 3\npoll = 7\nallow = [\"10.0.0.0/8\"]\nquiet = true\n").unwrap();
+        for var in ["STRATUM", "POLL", "PRECISION", "ALLOW"] {
+            std::env::set_var(format!("KISS_NTPD_TEST_LAYER_{}", var), "-9");
+        }
+        let mut a = args(&["-c", path.to_str().unwrap(), "--stratum", "2", "--allow=::1"]);
+        a.load_config().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // the command line beats the config file, which beats the environment, which beats the default
+        assert_eq!(a.get(&["--stratum"], "KISS_NTPD_TEST_LAYER_STRATUM", 8).unwrap(), 2);
+        assert_eq!(a.get(&["--poll"], "KISS_NTPD_TEST_LAYER_POLL", 4).unwrap(), 7);
+        assert_eq!(a.get(&["--precision"], "KISS_NTPD_TEST_LAYER_PRECISION", -20).unwrap(), -9);
+        assert_eq!(a.get(&["--root-delay"], "KISS_NTPD_TEST_UNSET", 0.5).unwrap(), 0.5);
+        assert_eq!(a.get_all(&["--allow"], "KISS_NTPD_TEST_LAYER_ALLOW").unwrap(), vec!["::1"]);
+        assert!(a.flag(&["-q", "--quiet"]).unwrap());
+        assert!(!a.flag(&["--echo-poll"]).unwrap());
+        let sources: Vec<Source> = ["stratum", "poll", "precision", "root-delay", "allow", "quiet", "echo-poll"]
+            .iter()
+            .map(|name| a.sources[name])
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                Source::CommandLine,
+                Source::ConfigFile,
+                Source::Environment,
+                Source::Default,
+                Source::CommandLine,
+                Source::ConfigFile,
+                Source::Default
+            ]
+        );
+        // a config file list beats the environment's
+        let mut a = args(&[]);
+        a.config = toml::parse("allow = [\"10.0.0.0/8\", \"::1\"]").unwrap();
+        assert_eq!(a.get_all(&["--allow"], "KISS_NTPD_TEST_LAYER_ALLOW").unwrap(), vec!["10.0.0.0/8", "::1"]);
+        assert!(a.remaining().unwrap().is_empty());
+    }
+
+    fn server_with(extra: &[&str]) -> NtpServer {
+        let mut builder = NtpServer::builder().bind("127.0.0.1:0".parse().unwrap()).threads(1);
+        builder.args.extend(extra.iter().map(|a| a.to_string()));
+        builder.build().unwrap()
+    }
+
+    fn server() -> NtpServer {
+        server_with(&[])
+    }
+
+    #[test]
+    fn builder_sets_options() {
+        let server = NtpServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .stratum(1)
+            .refid("GPS")
+            .threads(1)
+            .debug(true)
+            .build()
+            .unwrap();
+        assert_eq!(server.config.stratum, 1);
+        assert_eq!(&server.config.ref_id, b"GPS\0");
+        assert!(server.config.debug);
+        assert!(!NtpServer::new("127.0.0.1:0".parse().unwrap(), false).unwrap().config.debug);
+        assert!(NtpServer::builder().bind("127.0.0.1:0".parse().unwrap()).option("--stratum", "bogus").build().is_err());
+    }
+
+    fn request(version: u8, mode: u8) -> NtpPacket {
+        let mut buf = [0u8; 48];
+        buf[0] = version << 3 | mode;
+        NtpPacket::parse(&buf, "192.0.2.1:40000".parse().unwrap(), NtpTimestamp::now().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn responses_are_never_answered() {
+        let mut server = server();
+        for mode in [2, 4] {
+            assert!(matches!(server.make_response(&request(4, mode)), Err(NtpError::UnexpectedResponse(m)) if m == mode));
+            assert!(server.handle(Ok(request(4, mode)), None).is_none());
+        }
+        assert_eq!(server.metrics.unexpected_responses.load(std::sync::atomic::Ordering::Relaxed), 2);
+        assert!(matches!(server.make_response(&request(4, 3)), Ok(Response::Time(_))));
+    }
+
+    #[test]
+    fn answers_in_the_matching_mode() {
+        let mut server = server();
+        let reply_mode = |server: &mut NtpServer, version, mode| match server.make_response(&request(version, mode)) {
+            Ok(Response::Time(packet)) => Some(packet.mode),
+            _ => None,
+        };
+        assert_eq!(reply_mode(&mut server, 4, 3), Some(4));
+        assert_eq!(reply_mode(&mut server, 4, 1), Some(2));
+        assert_eq!(reply_mode(&mut server, 1, 0), Some(4));
+        assert_eq!(reply_mode(&mut server, 4, 0), None);
+        assert_eq!(reply_mode(&mut server, 4, 5), None);
+    }
+
+    /// a batch size of 1 takes the plain recv_from and send_to path every platform has, which is all windows gets
+    #[test]
+    fn portable_loop_answers() {
+        let server = server_with(&["--batch-size", "1", "--stratum", "3"]);
+        let addr = server.local_addr();
+        std::thread::spawn(|| server.process_requests());
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0u8; 48];
+        buf[0] = 4 << 3 | 3;
+        buf[40..48].copy_from_slice(&0x0102_0304_0506_0708u64.to_be_bytes());
+        client.send_to(&buf, addr).unwrap();
+        let mut reply = [0u8; 1024];
+        let (len, from) = client.recv_from(&mut reply).unwrap();
+        assert_eq!(from, addr);
+        assert_eq!(len, 48);
+        assert_eq!(reply[0] & 0x7, 4);
+        assert_eq!(reply[1], 3);
+        assert_eq!(reply[24..32], buf[40..48]);
+    }
+
+    #[test]
+    fn rate_limiter_is_bounded() {
+        let mut limiter = RateLimiter::new(100);
+        for i in 0..10_000u32 {
+            assert!(limiter.take(IpAddr::V4(i.into()), 1.0, Instant::now()));
+            assert!(limiter.buckets.len() <= 100);
+            assert_eq!(limiter.order.len(), limiter.buckets.len());
+        }
+        // the most recent clients are the ones remembered
+        assert!(limiter.buckets.contains_key(&IpAddr::V4(9_999.into())));
+        assert!(!limiter.buckets.contains_key(&IpAddr::V4(0.into())));
+        assert!(!limiter.take(IpAddr::V4(9_999.into()), 1.0, Instant::now()));
+    }
+
+    #[test]
+    fn rate_limiter_keeps_busy_clients() {
+        let mut limiter = RateLimiter::new(2);
+        let busy = IpAddr::V4(1.into());
+        assert!(limiter.take(busy, 1.0, Instant::now()));
+        for i in 2..100u32 {
+            limiter.take(IpAddr::V4(i.into()), 1.0, Instant::now());
+            // heard from again, so it's never the oldest
+            assert!(!limiter.take(busy, 1.0, Instant::now()));
+        }
+        assert_eq!(limiter.buckets.len(), 2);
+    }
+
+    #[test]
+    fn origin_is_client_transmit() {
+        let mut server = server();
+        for tx_ts in [NtpTimestamp::zero(), NtpTimestamp { ts: 0xe000_0000_1234_5678 }, NtpTimestamp { ts: u64::MAX }] {
+            let mut request = request(4, 3);
+            request.tx_ts = tx_ts;
+            match server.make_response(&request) {
+                Ok(Response::Time(response)) => {
+                    assert_eq!(response.orig_ts, tx_ts);
+                    assert_eq!(NtpPacket::parse(&response.to_bytes(), request.remote_addr, NtpTimestamp::zero()).unwrap().orig_ts, tx_ts);
+                }
+                response => panic!("unexpected {:?}", response),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_datagrams_counted() {
+        let mut server = server();
+        let from = "192.0.2.1:40000".parse().unwrap();
+        assert!(server.handle(NtpPacket::parse(&[], from, NtpTimestamp::zero()), Some(from)).is_none());
+        assert!(server.handle(NtpPacket::parse(&[0; 47], from, NtpTimestamp::zero()), Some(from)).is_none());
+        assert_eq!(server.metrics.empty.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(server.metrics.malformed.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn mock_clock_timestamps() {
+        let clock = Arc::new(MockClock::new(NtpTimestamp { ts: 0xe000_0000_8000_0000 }));
+        let mut server = NtpServer::builder().bind("127.0.0.1:0".parse().unwrap()).clock(clock.clone()).build().unwrap();
+        let mut request = request(4, 3);
+        request.local_ts = NtpTimestamp { ts: 0xe000_0000_0000_0000 };
+        request.tx_ts = NtpTimestamp { ts: 0xdfff_ffff_0000_0000 };
+        clock.advance(1 << 32);
+        let response = match server.make_response(&request) {
+            Ok(Response::Time(response)) => response,
+            response => panic!("unexpected {:?}", response),
+        };
+        assert_eq!(response.orig_ts, request.tx_ts);
+        assert_eq!(response.rx_ts.ts, 0xe000_0000_0000_0000);
+        assert_eq!(response.tx_ts.ts, 0xe000_0001_8000_0000);
+    }
+
+    /// a clock that ticks a second every time it is read, and counts the reads
+    struct TickingClock(std::sync::atomic::AtomicU64);
+
+    impl TimeSource for TickingClock {
+        fn now(&self) -> Result<NtpTimestamp, NtpError> {
+            let reads = self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(NtpTimestamp {
+                ts: 0xe000_0000_0000_0000 + (reads << 32),
+            })
+        }
+    }
+
+    #[test]
+    fn verbose_errors() {
+        let from: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let (verbose, quiet) = (server_with(&["--verbose-errors"]), server());
+        logger::take_captured();
+        verbose.log_error(&NtpError::PacketTruncated, Some(from));
+        assert_eq!(
+            logger::take_captured(),
+            [(logger::Severity::Warning, format!("dropped packet from {}: {}", from, NtpError::PacketTruncated))]
+        );
+        quiet.log_error(&NtpError::PacketTruncated, Some(from));
+        assert_eq!(logger::take_captured(), []);
+    }
+
+    #[test]
+    fn short_send_counted_and_logged() {
+        let server = server();
+        logger::take_captured();
+        let e = std::io::Error::new(std::io::ErrorKind::WriteZero, "short send to 192.0.2.1:40000, 48 of 68 bytes");
+        server.send_failed(1, &e);
+        server.send_failed(1, &e);
+        for counter in [&server.metrics.errors, &server.metrics.packets_dropped, &server.metrics.send_dropped] {
+            assert_eq!(counter.load(std::sync::atomic::Ordering::Relaxed), 2);
+        }
+        // every one, not summarized like a full send buffer
+        let logged = (logger::Severity::Error, e.to_string());
+        assert_eq!(logger::take_captured(), [logged.clone(), logged]);
+    }
+
+    #[test]
+    fn replay_runs_on_recorded_times() {
+        let replay = || {
+            let config = Config::from_args(&mut args(&["--rate-limit=1"])).unwrap();
+            let start = NtpTimestamp::from_rfc3339("2021-06-01T12:00:00Z").unwrap();
+            let clock = Arc::new(MockClock::new(start));
+            let server = NtpServer::offline(config, clock.clone(), start);
+            assert!(server.socket.is_none());
+            (server, clock, start)
+        };
+        let from: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let buf = request(4, 3).to_bytes();
+        // however long the replay itself takes, a client a tenth of a second apart is rate limited and one two seconds
+        // apart isn't
+        for _ in 0..2 {
+            let (mut server, clock, start) = replay();
+            let mut kiss_codes = Vec::new();
+            for (at, truncated) in [(0, false), ((1 << 32) / 8, false), (2 << 32, false), (3 << 32, true)] {
+                clock.set(start.offset(at));
+                let request = record::request(&buf, from, Some(start.offset(at)), truncated);
+                kiss_codes.push(server.handle(request, Some(from)).map(|response| response.stratum == 0));
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(kiss_codes, [Some(false), Some(true), Some(false), None]);
+            assert_eq!(server.metrics.truncated_dropped.load(std::sync::atomic::Ordering::Relaxed), 1);
+        }
+    }
+
+    #[test]
+    fn clock_read_once_per_response() {
+        let clock = Arc::new(TickingClock(Default::default()));
+        let mut server = NtpServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .option("--max-ref-age", 60)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let ref_ts = server.reference.lock().unwrap().ref_ts;
+        let response = match server.make_response(&request(4, 3)) {
+            Ok(Response::Time(response)) => response,
+            response => panic!("unexpected {:?}", response),
+        };
+        assert_eq!(clock.0.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(response.tx_ts.ts, 0xe000_0000_0000_0000);
+        // when we last synced, not when we answered
+        assert_eq!(response.ref_ts, ref_ts);
+    }
+
+    #[test]
+    fn stale_reference() {
+        let clock = Arc::new(MockClock::new(NtpTimestamp::from_rfc3339("2021-06-01T12:00:00Z").unwrap()));
+        let mut server = NtpServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .option("--max-ref-age", 60)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        {
+            let mut reference = server.reference.lock().unwrap();
+            reference.ref_ts = clock.now().unwrap();
+            reference.synced = true;
+        }
+        clock.advance(60 << 32);
+        let response = match server.make_response(&request(4, 3)) {
+            Ok(Response::Time(response)) => response,
+            response => panic!("unexpected {:?}", response),
+        };
+        assert_eq!(response.leap, 0);
+        assert!(response.dispersion.to_secs() < 1.0);
+        clock.advance(1 << 32);
+        let response = match server.make_response(&request(4, 3)) {
+            Ok(Response::Time(response)) => response,
+            response => panic!("unexpected {:?}", response),
+        };
+        assert_eq!(response.leap, 3);
+        assert_eq!(response.dispersion.to_secs(), upstream::MAX_DISPERSION);
+        // the system clock alone never goes stale
+        server.reference.lock().unwrap().synced = false;
+        assert!(matches!(server.make_response(&request(4, 3)), Ok(Response::Time(NtpPacket { leap: 0, .. }))));
+    }
+
+    #[test]
+    fn poll() {
+        let mut request = request(4, 3);
+        request.poll = 10;
+        let poll = |server: &mut NtpServer| match server.make_response(&request) {
+            Ok(Response::Time(response)) => response.poll,
+            response => panic!("unexpected {:?}", response),
+        };
+        assert_eq!(poll(&mut server()), 6);
+        assert_eq!(poll(&mut server_with(&["--poll=8"])), 8);
+        assert_eq!(poll(&mut server_with(&["--poll=3", "--min-poll=5"])), 5);
+        assert_eq!(poll(&mut server_with(&["--echo-poll"])), 10);
+        assert_eq!(poll(&mut server_with(&["--echo-poll", "--max-poll=9"])), 9);
+    }
+
+    #[test]
+    fn source_state() {
+        let mut server = server();
+        let response = |server: &mut NtpServer| match server.make_response(&request(4, 3)) {
+            Ok(Response::Time(response)) => (response.leap, response.stratum, response.ref_id.to_be_bytes()),
+            response => panic!("unexpected {:?}", response),
+        };
+        let ready = response(&mut server);
+        assert_ne!(ready.0, 3);
+        server.reference.lock().unwrap().state = SourceState::Init;
+        assert_eq!(response(&mut server), (3, 0, *b"INIT"));
+        {
+            let mut reference = server.reference.lock().unwrap();
+            reference.synced = true;
+            reference.set_offset(1 << 31);
+        }
+        assert_eq!(response(&mut server), (3, 0, *b"STEP"));
+        server.reference.lock().unwrap().set_offset(1 << 31);
+        assert_eq!(response(&mut server), ready);
+    }
+
+    #[test]
+    fn log_level() {
+        let level = |a: &[&str]| Config::from_args(&mut args(a)).map(|c| (c.log_level, c.debug));
+        assert_eq!(level(&[]), Ok((logger::Severity::Info, false)));
+        assert_eq!(level(&["--debug"]), Ok((logger::Severity::Debug, true)));
+        assert_eq!(level(&["--log-level=trace"]), Ok((logger::Severity::Trace, true)));
+        assert_eq!(level(&["--debug", "--log-level=warn"]), Ok((logger::Severity::Warning, false)));
+        assert!(level(&["--log-level=loud"]).is_err());
+    }
+
+    #[test]
+    fn amplification() {
+        let plain = request(4, 3);
+        let mut response = request(4, 4);
+        assert!(check_amplification(&plain, &response).is_ok());
+        response.extensions.push(ExtensionField::new(0x0104, vec![0; 16]));
+        assert!(matches!(check_amplification(&plain, &response), Err(NtpError::Amplification(68, 48, _))));
+        // a request padded out as far gets it
+        let mut padded = request(4, 3);
+        padded.extensions.push(ExtensionField::new(0x0000, vec![0; 16]));
+        assert!(check_amplification(&padded, &response).is_ok());
+    }
+
+    #[test]
+    fn require_cookie() {
+        let mut server = server_with(&["--require-cookie"]);
+        // a plain request has no room for the cookie, it gets a bare CKIE no bigger than itself
+        let response = server.handle(Ok(request(4, 3)), None).unwrap();
+        assert_eq!(response.ref_id.to_be_bytes(), *b"CKIE");
+        assert!(response.extensions.is_empty());
+        assert_eq!(response.wire_len(), 48);
+        let placeholder = |len| {
+            let mut request = request(4, 3);
+            request.extensions.push(ExtensionField::new(cookie::FIELD_TYPE, vec![0; len]));
+            request
+        };
+        // so does a placeholder too short to hold it
+        let response = server.handle(Ok(placeholder(16)), None).unwrap();
+        assert_eq!(response.ref_id.to_be_bytes(), *b"CKIE");
+        assert!(response.extensions.is_empty());
+        // the zeroed placeholder gets the cookie in a reply the same size
+        let first = placeholder(cookie::COOKIE_LEN);
+        let response = server.handle(Ok(placeholder(cookie::COOKIE_LEN)), None).unwrap();
+        assert_eq!(response.ref_id.to_be_bytes(), *b"CKIE");
+        assert_eq!(response.wire_len(), first.wire_len());
+        let cookie = response.extensions.into_iter().find(|e| e.field_type == cookie::FIELD_TYPE).unwrap();
+        assert_eq!(server.metrics.cookies_sent.load(std::sync::atomic::Ordering::Relaxed), 3);
+        assert_eq!(server.metrics.amplification_dropped.load(std::sync::atomic::Ordering::Relaxed), 0);
+        // and it goes through the wire format intact
+        let mut wire = request(4, 3).to_bytes();
+        cookie.write(&mut wire);
+        let mut echo = NtpPacket::parse(&wire, first.remote_addr, NtpTimestamp::zero()).unwrap();
+        assert!(matches!(server.make_response(&echo), Ok(Response::Time(NtpPacket { stratum: 8, .. }))));
+        echo.extensions[0].body[0] ^= 1;
+        assert!(matches!(server.make_response(&echo), Err(NtpError::BadCookie(_))));
+        // another address's cookie is no good either
+        echo.extensions[0].body[0] ^= 1;
+        echo.remote_addr = "192.0.2.2:40000".parse().unwrap();
+        assert!(matches!(server.make_response(&echo), Err(NtpError::BadCookie(_))));
+        assert!(matches!(server_with(&[]).make_response(&request(4, 3)), Ok(Response::Time(_))));
+        // rate limited, a client gets RATE and no cookie whatever room it left
+        let mut limited = server_with(&["--require-cookie", "--rate-limit=1"]);
+        assert!(limited.handle(Ok(placeholder(cookie::COOKIE_LEN)), None).is_some());
+        let response = limited.handle(Ok(placeholder(cookie::COOKIE_LEN)), None).unwrap();
+        assert_eq!(response.ref_id.to_be_bytes(), *b"RATE");
+        assert!(response.extensions.is_empty());
+    }
+
+    #[test]
+    fn allow_modes() {
+        let mut peers_only = server_with(&["--allow-modes=1"]);
+        assert!(peers_only.handle(Ok(request(4, 1)), None).is_some());
+        assert!(peers_only.handle(Ok(request(4, 3)), None).is_none());
+        assert_eq!(peers_only.metrics.mode_dropped.load(std::sync::atomic::Ordering::Relaxed), 1);
+        let mut both = server();
+        assert!(both.handle(Ok(request(4, 1)), None).is_some());
+        assert!(both.handle(Ok(request(4, 3)), None).is_some());
+        assert_eq!(Config::from_args(&mut args(&["--allow-modes", "3", "--allow-modes", "1"])).unwrap().allow_modes, [3, 1]);
+        assert_eq!(Config::from_args(&mut args(&["--allow-modes=3,1"])).unwrap().allow_modes, [3, 1]);
+        assert!(Config::from_args(&mut args(&["--allow-modes=3,4"])).is_err());
+    }
+
+    #[test]
+    fn force_mode() {
+        let mut server = server_with(&["--force-mode=5"]);
+        for mode in [1, 3] {
+            assert!(matches!(server.make_response(&request(4, mode)), Ok(Response::Time(NtpPacket { mode: 5, .. }))));
+        }
+        assert!(matches!(server.make_response(&request(4, 4)), Err(NtpError::UnexpectedResponse(4))));
+        assert!(matches!(server_with(&[]).make_response(&request(4, 1)), Ok(Response::Time(NtpPacket { mode: 2, .. }))));
+        assert!(Config::from_args(&mut args(&["--force-mode=8"])).is_err());
+    }
+
+    #[test]
+    fn freeze_time() {
+        let mut server = server_with(&["--freeze-time=2038-01-19T03:14:08Z"]);
+        let at = NtpTimestamp::from_rfc3339("2038-01-19T03:14:08Z").unwrap();
+        match server.make_response(&request(4, 3)) {
+            Ok(Response::Time(response)) => {
+                assert_eq!(response.tx_ts, at);
+                assert_eq!(response.ref_ts, at);
+            }
+            response => panic!("unexpected {:?}", response),
+        }
+        assert!(Config::from_args(&mut args(&["--freeze-advance"])).is_err());
+    }
+
+    #[test]
+    fn test_delay_slows_responses() {
+        let server = server_with(&["--test-delay-ms=200"]);
+        let addr = server.local_addr();
+        std::thread::spawn(|| server.process_requests());
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut request = request(4, 3);
+        request.remote_addr = addr;
+        let sent = Instant::now();
+        request.send(&client).unwrap();
+        let mut buf = [0u8; 1024];
+        client.recv_from(&mut buf).unwrap();
+        assert!(sent.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn send_drops_summarized_once_a_second() {
+        let drops = SendDrops::default();
+        let start = Instant::now();
+        assert_eq!(drops.add(1, start), Some(1));
+        assert_eq!(drops.add(2, start + Duration::from_millis(300)), None);
+        assert_eq!(drops.add(3, start + Duration::from_millis(900)), None);
+        assert_eq!(drops.add(1, start + Duration::from_millis(1000)), Some(6));
+        assert_eq!(drops.add(1, start + Duration::from_millis(1500)), None);
+    }
+
+    /// the whole path a real client sees, with whatever receive path this platform defaults to
+    #[test]
+    fn answers_a_client() {
+        let server = NtpServer::builder().bind("127.0.0.1:0".parse().unwrap()).stratum(2).refid("192.0.2.123").build().unwrap();
+        let addr = server.local_addr();
+        assert_ne!(addr.port(), 0);
+        std::thread::spawn(|| server.process_requests());
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut request = request(4, 3);
+        request.remote_addr = addr;
+        request.tx_ts = NtpTimestamp::now().unwrap();
+        request.send(&client).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(from, addr);
+        let reply = NtpPacket::parse(&buf[..len], from, NtpTimestamp::now().unwrap()).unwrap();
+        assert_eq!(reply.mode, 4);
+        assert_eq!(reply.version, 4);
+        assert_eq!(reply.stratum, 2);
+        assert_eq!(reply.ref_id.to_be_bytes(), [192, 0, 2, 123]);
+        assert_eq!(reply.orig_ts, request.tx_ts);
+        assert!(reply.rx_ts.ts <= reply.tx_ts.ts);
+    }
+
+    #[test]
+    fn answers_a_peer() {
+        let server = server();
+        let addr = server.local_addr();
+        std::thread::spawn(|| server.process_requests());
+
+        let socket = upstream::client_socket(addr).unwrap();
+        let (request, reply) = upstream::exchange_mode(&socket, addr, 1).unwrap();
+        assert_eq!(reply.mode, 2);
+        assert_eq!(reply.orig_ts, request.tx_ts);
+        assert!(Config::from_args(&mut args(&["--peer=127.0.0.1:1123", "--upstream=127.0.0.1"])).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bind_device() {
+        let server = NtpServer::builder().bind("127.0.0.1:0".parse().unwrap()).option("--bind-device", "lo").build().unwrap();
+        let addr = server.local_addr();
+        std::thread::spawn(|| server.process_requests());
+        let socket = upstream::client_socket(addr).unwrap();
+        assert_eq!(upstream::exchange(&socket, addr).unwrap().1.mode, 4);
+        assert!(NtpServer::builder().bind("127.0.0.1:0".parse().unwrap()).option("--bind-device", "no-such-nic0").build().is_err());
+    }
+
+    #[test]
+    fn option_missing_value() {
+        let mut a = args(&["--stratum"]);
+        assert!(a.get_option(&["--stratum"], "KISS_NTPD_TEST_UNSET").is_err());
+    }
+}
@@ -0,0 +1,147 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! everything we log goes through here so it can go to stdout/stderr or syslog
+
+#[cfg(test)]
+use std::cell::RefCell;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+/// syslog severities, plus trace for packet dumps which goes to syslog as debug
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+    Debug = 7,
+    Trace = 8,
+}
+
+/// --log-level
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Severity, String> {
+        match s {
+            "error" => Ok(Severity::Error),
+            "warn" => Ok(Severity::Warning),
+            "info" => Ok(Severity::Info),
+            "debug" => Ok(Severity::Debug),
+            "trace" => Ok(Severity::Trace),
+            _ => Err("must be error, warn, info, debug or trace".to_owned()),
+        }
+    }
+}
+
+enum Target {
+    #[cfg(unix)]
+    Syslog(std::os::unix::net::UnixDatagram),
+}
+
+/// unset means stdout/stderr, which is also what we use before configuration is parsed
+static TARGET: OnceLock<Target> = OnceLock::new();
+
+/// --quiet, info messages are dropped but warnings, errors and debug output still go out
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// --log-level, anything less severe is dropped, everything goes out until it's set
+static LEVEL: AtomicU8 = AtomicU8::new(Severity::Trace as u8);
+
+pub fn set_level(level: Severity) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// send everything from now on to the local syslog socket with the daemon facility
+#[cfg(unix)]
+pub fn init_syslog() -> std::io::Result<()> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket.connect("/dev/log")?;
+    TARGET.set(Target::Syslog(socket)).map_err(|_| std::io::Error::other("logging already initialized"))
+}
+
+#[cfg(not(unix))]
+pub fn init_syslog() -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "syslog is only supported on unix"))
+}
+
+fn stdout(severity: Severity, args: fmt::Arguments) {
+    match severity {
+        Severity::Error => eprintln!("error: {}", args),
+        Severity::Warning => eprintln!("warning: {}", args),
+        Severity::Info | Severity::Debug | Severity::Trace => println!("{}", args),
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// everything this test's thread logged
+    static CAPTURED: RefCell<Vec<(Severity, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// what the calling test has logged since it last asked, tests each run on their own thread
+#[cfg(test)]
+pub fn take_captured() -> Vec<(Severity, String)> {
+    CAPTURED.with(|captured| captured.take())
+}
+
+pub fn log(severity: Severity, args: fmt::Arguments) {
+    if severity as u8 > LEVEL.load(Ordering::Relaxed) || (severity == Severity::Info && QUIET.load(Ordering::Relaxed)) {
+        return;
+    }
+    #[cfg(test)]
+    CAPTURED.with(|captured| captured.borrow_mut().push((severity, args.to_string())));
+    match TARGET.get() {
+        #[cfg(unix)]
+        Some(Target::Syslog(socket)) => {
+            const LOG_DAEMON: u8 = 3 << 3;
+            let msg = format!("<{}>kiss-ntpd[{}]: {}", LOG_DAEMON | (severity as u8).min(Severity::Debug as u8), std::process::id(), args);
+            if socket.send(msg.as_bytes()).is_err() {
+                // syslog went away, better to log somewhere than nowhere
+                stdout(severity, args);
+            }
+        }
+        _ => stdout(severity, args),
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::logger::log($crate::logger::Severity::Error, format_args!($($arg)*)))
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::logger::log($crate::logger::Severity::Warning, format_args!($($arg)*)))
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::logger::log($crate::logger::Severity::Info, format_args!($($arg)*)))
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::logger::log($crate::logger::Severity::Debug, format_args!($($arg)*)))
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::logger::log($crate::logger::Severity::Trace, format_args!($($arg)*)))
+}
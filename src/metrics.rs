@@ -0,0 +1,410 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::signal;
+
+/// counters shared by every server thread
+#[derive(Default)]
+pub struct Metrics {
+    /// every datagram received
+    pub requests: AtomicU64,
+    /// every reply successfully sent
+    pub responses: AtomicU64,
+    /// every error encountered, socket or packet
+    pub errors: AtomicU64,
+    /// datagrams received that we did not reply to
+    pub packets_dropped: AtomicU64,
+    /// mode 6 and 7 control queries, a common amplification vector, also counted in packets_dropped
+    pub control_dropped: AtomicU64,
+    /// requests from clients --allow/--deny refused, also counted in packets_dropped
+    pub acl_dropped: AtomicU64,
+    /// requests from source ports --client-port-filter refused, also counted in packets_dropped
+    pub port_dropped: AtomicU64,
+    /// requests outside --min-version/--max-version, also counted in packets_dropped
+    pub version_dropped: AtomicU64,
+    /// mode 1 or 3 requests --allow-modes refused, also counted in packets_dropped
+    pub mode_dropped: AtomicU64,
+    /// datagrams longer than we accept, which we only got part of, also counted in packets_dropped
+    pub truncated_dropped: AtomicU64,
+    /// the oldest queued datagrams thrown out to make room with --queue-depth, also counted in packets_dropped
+    pub queue_dropped: AtomicU64,
+    /// responses not sent because --max-packet-rate was used up, also counted in packets_dropped
+    pub throttled: AtomicU64,
+    /// responses the socket wouldn't take, usually because its send buffer was full, also counted in packets_dropped
+    pub send_dropped: AtomicU64,
+    /// responses bigger than the unauthenticated requests they answered, also counted in packets_dropped
+    pub amplification_dropped: AtomicU64,
+    /// requests with a --require-cookie cookie we never sent them, also counted in packets_dropped
+    pub cookie_dropped: AtomicU64,
+    /// requests without a --require-cookie cookie, answered with a CKIE Kiss-o'-Death instead of the time, carrying one if
+    /// they left room for it
+    pub cookies_sent: AtomicU64,
+    /// mode 2 and 4 packets, replies that shouldn't come to a server, like our own looping back, also counted in packets_dropped
+    pub unexpected_responses: AtomicU64,
+    /// requests by NTP mode, including the control modes we drop, so scans stand out from client load
+    pub modes: [AtomicU64; 8],
+    /// datagrams too short, too long, of an unknown version, or with bad extension fields to have a mode worth counting
+    pub malformed: AtomicU64,
+    /// zero-length datagrams, what port scanners send, also counted in malformed
+    pub empty: AtomicU64,
+    /// serving threads that gave up on a broken socket, anything but 0 fails --health-addr
+    pub stopped: AtomicU64,
+    /// from receive returning to the response being sent, only recorded with --stats or --debug
+    pub latency: Histogram,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        Metrics::add(counter, 1);
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn counters(&self) -> [(&'static str, &'static str, &AtomicU64); 20] {
+        [
+            ("kiss_ntpd_requests_total", "Datagrams received.", &self.requests),
+            ("kiss_ntpd_responses_total", "Responses sent.", &self.responses),
+            ("kiss_ntpd_errors_total", "Socket or packet errors.", &self.errors),
+            ("kiss_ntpd_packets_dropped_total", "Datagrams received but not answered.", &self.packets_dropped),
+            ("kiss_ntpd_control_packets_dropped_total", "Mode 6 and 7 control queries dropped.", &self.control_dropped),
+            ("kiss_ntpd_acl_packets_dropped_total", "Requests dropped by --allow/--deny.", &self.acl_dropped),
+            ("kiss_ntpd_port_packets_dropped_total", "Requests dropped by --client-port-filter.", &self.port_dropped),
+            ("kiss_ntpd_version_packets_dropped_total", "Requests dropped by --min-version/--max-version.", &self.version_dropped),
+            ("kiss_ntpd_mode_packets_dropped_total", "Requests dropped by --allow-modes.", &self.mode_dropped),
+            (
+                "kiss_ntpd_truncated_packets_dropped_total",
+                "Datagrams dropped for being too long to receive whole.",
+                &self.truncated_dropped,
+            ),
+            ("kiss_ntpd_queue_packets_dropped_total", "Datagrams dropped from a full --queue-depth queue.", &self.queue_dropped),
+            ("kiss_ntpd_throttled_packets_dropped_total", "Responses dropped by --max-packet-rate.", &self.throttled),
+            ("kiss_ntpd_send_packets_dropped_total", "Responses that failed to send.", &self.send_dropped),
+            (
+                "kiss_ntpd_amplification_packets_dropped_total",
+                "Responses dropped for being bigger than an unauthenticated request.",
+                &self.amplification_dropped,
+            ),
+            ("kiss_ntpd_cookie_packets_dropped_total", "Requests dropped for a wrong --require-cookie cookie.", &self.cookie_dropped),
+            (
+                "kiss_ntpd_cookies_sent_total",
+                "Requests without a --require-cookie cookie answered with a CKIE Kiss-o'-Death instead of the time.",
+                &self.cookies_sent,
+            ),
+            ("kiss_ntpd_unexpected_responses_total", "Mode 2 and 4 replies received and dropped.", &self.unexpected_responses),
+            ("kiss_ntpd_malformed_packets_total", "Datagrams that did not parse as NTP.", &self.malformed),
+            ("kiss_ntpd_empty_packets_total", "Zero-length datagrams, usually port scans.", &self.empty),
+            ("kiss_ntpd_serve_loops_stopped_total", "Serving threads stopped by a fatal socket error.", &self.stopped),
+        ]
+    }
+
+    /// a CBOR map of the same names to counts, with the by mode counts as an array indexed by mode
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut ret = Vec::new();
+        let counters = self.counters();
+        cbor_head(&mut ret, 5, counters.len() as u64 + 1);
+        for (name, _, counter) in counters.iter() {
+            cbor_text(&mut ret, name);
+            cbor_head(&mut ret, 0, counter.load(Ordering::Relaxed));
+        }
+        cbor_text(&mut ret, "kiss_ntpd_requests_by_mode_total");
+        cbor_head(&mut ret, 4, self.modes.len() as u64);
+        for counter in self.modes.iter() {
+            cbor_head(&mut ret, 0, counter.load(Ordering::Relaxed));
+        }
+        ret
+    }
+
+    /// Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut ret = String::new();
+        for (name, help, counter) in self.counters().iter() {
+            let _ = write!(ret, "# HELP {} {}\n# TYPE {} counter\n{} {}\n", name, help, name, name, counter.load(Ordering::Relaxed));
+        }
+        let name = "kiss_ntpd_requests_by_mode_total";
+        let _ = write!(ret, "# HELP {} Datagrams received by NTP mode.\n# TYPE {} counter\n", name, name);
+        for (mode, counter) in self.modes.iter().enumerate() {
+            let _ = writeln!(ret, "{}{{mode=\"{}\"}} {}", name, mode, counter.load(Ordering::Relaxed));
+        }
+        ret
+    }
+}
+
+/// a CBOR data item header, the major type and its argument in the fewest bytes that hold it
+fn cbor_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    if n < 24 {
+        out.push(major | n as u8);
+    } else if n <= u8::MAX as u64 {
+        out.extend_from_slice(&[major | 24, n as u8]);
+    } else if n <= u16::MAX as u64 {
+        out.push(major | 25);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u32::MAX as u64 {
+        out.push(major | 26);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn cbor_text(out: &mut Vec<u8>, s: &str) {
+    cbor_head(out, 3, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// how /metrics answers when the scraper doesn't ask for one with its Accept header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Prometheus,
+    Cbor,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "prometheus" => Ok(Format::Prometheus),
+            "cbor" => Ok(Format::Cbor),
+            _ => Err("must be prometheus or cbor".to_owned()),
+        }
+    }
+}
+
+/// 4 linear sub-buckets per power of two, so percentiles are within 25%
+const SUB_BUCKETS: u32 = 4;
+const BUCKETS: usize = (64 - 1) * SUB_BUCKETS as usize;
+
+/// nanosecond durations bucketed roughly logarithmically, cheap enough to record every packet into from every thread
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Histogram {
+    fn bucket(nanos: u64) -> usize {
+        if nanos < SUB_BUCKETS as u64 {
+            return nanos as usize;
+        }
+        let exp = 63 - nanos.leading_zeros();
+        let sub = (nanos >> (exp - 2)) & (SUB_BUCKETS as u64 - 1);
+        ((exp - 1) * SUB_BUCKETS) as usize + sub as usize
+    }
+
+    /// the largest value that lands in bucket
+    fn upper_bound(bucket: usize) -> u64 {
+        if bucket < SUB_BUCKETS as usize {
+            return bucket as u64;
+        }
+        let (exp, sub) = (bucket as u32 / SUB_BUCKETS + 1, bucket as u64 % SUB_BUCKETS as u64);
+        ((SUB_BUCKETS as u64 + sub) << (exp - 2)) + (1u64 << (exp - 2)) - 1
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        Metrics::inc(&self.buckets[Histogram::bucket(nanos)]);
+    }
+
+    /// the number of samples and the given percentiles since the last call, which starts a new histogram
+    pub fn take_percentiles<const N: usize>(&self, percentiles: [f64; N]) -> (u64, [Duration; N]) {
+        let counts: Vec<u64> = self.buckets.iter().map(|b| b.swap(0, Ordering::Relaxed)).collect();
+        let total: u64 = counts.iter().sum();
+        let ret = percentiles.map(|p| {
+            let rank = ((total as f64 * p).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (bucket, count) in counts.iter().enumerate() {
+                seen += count;
+                if seen >= rank {
+                    return Duration::from_nanos(Histogram::upper_bound(bucket));
+                }
+            }
+            Duration::ZERO
+        });
+        (total, ret)
+    }
+}
+
+/// requests a shutdown once no datagram has arrived for timeout
+pub fn exit_when_idle(metrics: Arc<Metrics>, timeout: Duration) {
+    let mut last_requests = metrics.requests.load(Ordering::Relaxed);
+    let mut last_request = Instant::now();
+    while !signal::shutdown_requested() {
+        std::thread::sleep(Duration::from_secs(1));
+        let requests = metrics.requests.load(Ordering::Relaxed);
+        if requests != last_requests {
+            last_requests = requests;
+            last_request = Instant::now();
+        } else if last_request.elapsed() >= timeout {
+            info!("no requests for {:?}, exiting", timeout);
+            signal::request_shutdown();
+        }
+    }
+}
+
+/// prints latency percentiles every interval until shutdown, rolling the histogram each time
+pub fn report_latency(metrics: Arc<Metrics>, interval: Duration) {
+    while !signal::shutdown_requested() {
+        signal::sleep(interval);
+        let (count, [p50, p95, p99]) = metrics.latency.take_percentiles([0.5, 0.95, 0.99]);
+        if count > 0 {
+            info!("latency over {} responses in the last {:?}: p50 {:?} p95 {:?} p99 {:?}", count, interval, p50, p95, p99);
+        }
+    }
+}
+
+/// prints the requests, responses and errors since the last summary and the request rate every interval until shutdown
+pub fn report_summary(metrics: Arc<Metrics>, interval: Duration) {
+    let totals = |metrics: &Metrics| [&metrics.requests, &metrics.responses, &metrics.errors].map(|c| c.load(Ordering::Relaxed));
+    let mut last = totals(&metrics);
+    let mut last_time = Instant::now();
+    while !signal::shutdown_requested() {
+        signal::sleep(interval);
+        let now = totals(&metrics);
+        let [requests, responses, errors] = [0, 1, 2].map(|i| now[i].wrapping_sub(last[i]));
+        let elapsed = last_time.elapsed();
+        info!(
+            "{} requests, {} responses, {} errors in the last {:?}, {:.1} requests/sec",
+            requests,
+            responses,
+            errors,
+            elapsed,
+            requests as f64 / elapsed.as_secs_f64()
+        );
+        last = now;
+        last_time = Instant::now();
+    }
+}
+
+/// the format an Accept header asks for, if it names one we have
+fn accepted(request: &str) -> Option<Format> {
+    let accept = request.lines().skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("accept") {
+            Some(value.to_ascii_lowercase())
+        } else {
+            None
+        }
+    })?;
+    if accept.contains("application/cbor") {
+        Some(Format::Cbor)
+    } else if accept.contains("text/plain") {
+        Some(Format::Prometheus)
+    } else {
+        None
+    }
+}
+
+fn handle(mut stream: TcpStream, metrics: &Metrics, format: Format) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    // we only care about the request line and Accept header, which must fit in the first read
+    let mut buf = [0u8; 1024];
+    let len = stream.read(&mut buf)?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut request_line = request.lines().next().unwrap_or("").split(' ');
+    let (method, path) = (request_line.next().unwrap_or(""), request_line.next().unwrap_or(""));
+
+    let (status, content_type, body) = if method == "GET" && (path == "/metrics" || path.starts_with("/metrics?")) {
+        match accepted(&request).unwrap_or(format) {
+            Format::Prometheus => ("200 OK", "text/plain; version=0.0.4", metrics.to_prometheus().into_bytes()),
+            Format::Cbor => ("200 OK", "application/cbor", metrics.to_cbor()),
+        }
+    } else {
+        ("404 Not Found", "text/plain", b"not found\n".to_vec())
+    };
+
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)
+}
+
+/// serves /metrics forever, one connection at a time which is plenty for a scraper
+pub fn serve(listener: TcpListener, metrics: Arc<Metrics>, format: Format) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle(stream, &metrics, format) {
+                    error!("metrics connection: {}", e);
+                }
+            }
+            Err(e) => error!("metrics accept: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cbor_heads() {
+        let head = |major, n| {
+            let mut out = Vec::new();
+            cbor_head(&mut out, major, n);
+            out
+        };
+        assert_eq!(head(0, 23), [0x17]);
+        assert_eq!(head(0, 24), [0x18, 24]);
+        assert_eq!(head(0, 1000), [0x19, 0x03, 0xe8]);
+        assert_eq!(head(0, 1_000_000), [0x1a, 0x00, 0x0f, 0x42, 0x40]);
+        assert_eq!(head(0, u64::MAX), [0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(head(5, 2), [0xa2]);
+    }
+
+    #[test]
+    fn cbor_counters() {
+        let metrics = Metrics::default();
+        Metrics::add(&metrics.requests, 500);
+        let cbor = metrics.to_cbor();
+        assert_eq!(cbor[0], 0xa0 | (metrics.counters().len() as u8 + 1));
+        let mut key = Vec::new();
+        cbor_text(&mut key, "kiss_ntpd_requests_total");
+        assert_eq!(cbor[1..1 + key.len()], key[..]);
+        assert_eq!(cbor[1 + key.len()..4 + key.len()], [0x19, 0x01, 0xf4]);
+    }
+
+    #[test]
+    fn accept_header() {
+        assert_eq!(accepted("GET /metrics HTTP/1.1\r\nAccept: application/cbor\r\n\r\n"), Some(Format::Cbor));
+        assert_eq!(accepted("GET /metrics HTTP/1.1\r\naccept: text/plain;version=0.0.4\r\n\r\n"), Some(Format::Prometheus));
+        assert_eq!(accepted("GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n"), None);
+    }
+}
@@ -0,0 +1,273 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! recvmmsg/sendmmsg so a busy server makes one syscall per batch instead of per packet
+
+use crate::packet::MAX_PACKET_LEN;
+use socket2::SockAddr;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+/// u64 for cmsghdr alignment, plenty for an SCM_TIMESTAMPING message and a packet info one
+type ControlBuf = [u64; 32];
+
+/// where a datagram arrived, from IP_PKTINFO or IPV6_PKTINFO, so the reply can leave from the same address and interface
+#[derive(Clone, Copy)]
+pub enum PktInfo {
+    V4(libc::in_pktinfo),
+    V6(libc::in6_pktinfo),
+}
+
+/// preallocated buffers for receiving up to size datagrams at once
+pub struct Batch {
+    bufs: Vec<[u8; MAX_PACKET_LEN]>,
+    addrs: Vec<libc::sockaddr_storage>,
+    controls: Vec<ControlBuf>,
+    msgs: Vec<libc::mmsghdr>,
+    iovecs: Vec<libc::iovec>,
+}
+
+/// ask the kernel to timestamp packets as they arrive, which we get back in a control message
+pub fn enable_rx_timestamps(socket: &UdpSocket) -> Result<()> {
+    // hardware timestamps are in the NIC's clock, not the system clock, so only software ones are useful to us
+    let flags: libc::c_uint = libc::SOF_TIMESTAMPING_RX_SOFTWARE | libc::SOF_TIMESTAMPING_SOFTWARE;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPING,
+            &flags as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&flags) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// ask the kernel which address and interface each packet arrived on, which binding to a wildcard address otherwise hides
+pub fn enable_pktinfo(socket: &UdpSocket) -> Result<()> {
+    let (level, name) = if socket.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_PKTINFO)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)
+    };
+    let on: libc::c_int = 1;
+    let ret = unsafe { libc::setsockopt(socket.as_raw_fd(), level, name, &on as *const _ as *const libc::c_void, std::mem::size_of_val(&on) as libc::socklen_t) };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn to_socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)), u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+impl Batch {
+    pub fn new(size: usize) -> Batch {
+        Batch {
+            bufs: vec![[0u8; MAX_PACKET_LEN]; size],
+            addrs: vec![unsafe { std::mem::zeroed() }; size],
+            controls: vec![[0u64; 32]; size],
+            msgs: vec![unsafe { std::mem::zeroed() }; size],
+            iovecs: vec![unsafe { std::mem::zeroed() }; size],
+        }
+    }
+
+    /// blocks until at least one datagram is available (or the read timeout hits), then takes whatever else is queued
+    pub fn recv(&mut self, socket: &UdpSocket) -> Result<usize> {
+        // the pointers are rebuilt every time so moving the Batch can never leave them dangling
+        for i in 0..self.msgs.len() {
+            self.iovecs[i] = libc::iovec {
+                iov_base: self.bufs[i].as_mut_ptr() as *mut libc::c_void,
+                iov_len: MAX_PACKET_LEN,
+            };
+            let hdr = &mut self.msgs[i].msg_hdr;
+            hdr.msg_name = &mut self.addrs[i] as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = &mut self.iovecs[i];
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = self.controls[i].as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = std::mem::size_of::<ControlBuf>() as _;
+            hdr.msg_flags = 0;
+        }
+        let ret = unsafe { libc::recvmmsg(socket.as_raw_fd(), self.msgs.as_mut_ptr(), self.msgs.len() as libc::c_uint, libc::MSG_WAITFORONE, std::ptr::null_mut()) };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    /// whether the i'th datagram from the last recv was longer than the buffer and cut short
+    pub fn truncated(&self, i: usize) -> bool {
+        self.msgs[i].msg_hdr.msg_flags & libc::MSG_TRUNC != 0
+    }
+
+    /// the i'th datagram from the last recv, and the time since the unix epoch the kernel received it, if it told us
+    pub fn packet(&self, i: usize) -> Result<(&[u8], SocketAddr, Option<Duration>)> {
+        let addr = to_socket_addr(&self.addrs[i]).ok_or_else(|| Error::other("unknown address family"))?;
+        Ok((&self.bufs[i][..self.msgs[i].msg_len as usize], addr, self.rx_timestamp(i)))
+    }
+
+    /// where the i'th datagram arrived, if enable_pktinfo was called on the socket
+    pub fn pktinfo(&self, i: usize) -> Option<PktInfo> {
+        let hdr = &self.msgs[i].msg_hdr;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    (libc::IPPROTO_IP, libc::IP_PKTINFO) => return Some(PktInfo::V4(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo))),
+                    (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => return Some(PktInfo::V6(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in6_pktinfo))),
+                    _ => {}
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        None
+    }
+
+    fn rx_timestamp(&self, i: usize) -> Option<Duration> {
+        let hdr = &self.msgs[i].msg_hdr;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPING {
+                    // struct scm_timestamping, the first one is the software timestamp
+                    let ts = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec);
+                    if ts.tv_sec > 0 {
+                        return Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        None
+    }
+}
+
+/// writes a control message sending from where info says the request arrived, returning its length
+fn write_pktinfo(control: &mut ControlBuf, info: PktInfo) -> usize {
+    let (level, kind, len) = match info {
+        PktInfo::V4(_) => (libc::IPPROTO_IP, libc::IP_PKTINFO, std::mem::size_of::<libc::in_pktinfo>()),
+        PktInfo::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, std::mem::size_of::<libc::in6_pktinfo>()),
+    };
+    unsafe {
+        let mut hdr: libc::msghdr = std::mem::zeroed();
+        hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = libc::CMSG_SPACE(len as u32) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = kind;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(len as u32) as _;
+        match info {
+            // ipi_spec_dst is the local address, which unlike ipi_addr is never a broadcast or multicast one
+            PktInfo::V4(mut info) => {
+                info.ipi_addr = info.ipi_spec_dst;
+                std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo, info);
+            }
+            // a multicast destination can't be our source, leave it to the kernel but keep the interface
+            PktInfo::V6(mut info) => {
+                if Ipv6Addr::from(info.ipi6_addr.s6_addr).is_multicast() {
+                    info.ipi6_addr.s6_addr = [0; 16];
+                }
+                std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::in6_pktinfo, info);
+            }
+        }
+        libc::CMSG_SPACE(len as u32) as usize
+    }
+}
+
+/// sends every packet it can, from the address and interface its request arrived on if known,
+/// returns the indexes of those that went out whole and, if any didn't, the first error
+pub fn send(socket: &UdpSocket, packets: &[(Vec<u8>, SocketAddr, Option<PktInfo>)]) -> (Vec<usize>, Option<Error>) {
+    let addrs: Vec<SockAddr> = packets.iter().map(|(_, addr, _)| SockAddr::from(*addr)).collect();
+    let mut controls: Vec<ControlBuf> = vec![[0u64; 32]; packets.len()];
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|(buf, _, _)| libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(addrs.iter())
+        .zip(controls.iter_mut())
+        .zip(packets.iter())
+        .map(|(((iovec, addr), control), (_, _, info))| {
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = addr.len();
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            if let Some(info) = info {
+                msg.msg_hdr.msg_controllen = write_pktinfo(control, *info) as _;
+                msg.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            }
+            msg
+        })
+        .collect();
+
+    // which packet each message is, as failed ones are removed
+    let mut indexes: Vec<usize> = (0..packets.len()).collect();
+    let mut sent = 0;
+    let mut error = None;
+    while sent < msgs.len() {
+        let ret = unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs[sent..].as_mut_ptr(), (msgs.len() - sent) as libc::c_uint, 0) };
+        if ret == 0 {
+            break;
+        } else if ret < 0 {
+            // the error belongs to the first unsent message, skip it and carry on with the rest
+            error.get_or_insert_with(Error::last_os_error);
+            msgs.remove(sent);
+            indexes.remove(sent);
+        } else {
+            sent += ret as usize;
+        }
+    }
+    // as with send_to, check rather than assume every datagram went out whole
+    let whole: Vec<usize> = msgs[..sent]
+        .iter()
+        .zip(indexes)
+        .filter(|(msg, i)| msg.msg_len as usize == packets[*i].0.len())
+        .map(|(_, i)| i)
+        .collect();
+    if whole.len() < sent {
+        error.get_or_insert_with(|| Error::new(ErrorKind::WriteZero, format!("short send of {} datagrams", sent - whole.len())));
+    }
+    (whole, error)
+}
@@ -0,0 +1,278 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! the NTP side of Network Time Security, RFC 8915, authenticating requests that carry a cookie and handing
+//! back fresh cookies, cookies are only ever opened by us so their format is ours: a nonce then the
+//! client to server and server to client keys sealed with the master key
+
+use crate::packet::{ExtensionField, NtpPacket};
+use aes_siv::aead::{Aead, KeyInit, Payload};
+use aes_siv::{Aes128SivAead, Nonce};
+use std::convert::TryInto;
+use std::io;
+
+pub const UNIQUE_IDENTIFIER: u16 = 0x0104;
+pub const COOKIE: u16 = 0x0204;
+pub const COOKIE_PLACEHOLDER: u16 = 0x0304;
+pub const AUTHENTICATOR: u16 = 0x0404;
+
+/// AEAD_AES_SIV_CMAC_256, the one every NTS implementation supports
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 16;
+/// most fresh cookies we'll hand out in one response, however many placeholders the client sent
+const MAX_COOKIES: usize = 8;
+
+type Key = [u8; KEY_LEN];
+
+/// whether the client is trying to use NTS at all
+pub fn is_nts(request: &NtpPacket) -> bool {
+    request.extensions.iter().any(|e| matches!(e.field_type, UNIQUE_IDENTIFIER | COOKIE | AUTHENTICATOR))
+}
+
+fn seal(key: &Key, msg: &[u8], aad: &[u8]) -> io::Result<(Vec<u8>, [u8; NONCE_LEN])> {
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(|e| io::Error::other(format!("could not generate NTS nonce: {}", e)))?;
+    let ciphertext = Aes128SivAead::new(key.into())
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg, aad })
+        .map_err(|_| io::Error::other("NTS encryption failed"))?;
+    Ok((ciphertext, nonce))
+}
+
+fn open(key: &Key, nonce: &[u8], msg: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    if nonce.len() != NONCE_LEN {
+        return None;
+    }
+    Aes128SivAead::new(key.into()).decrypt(Nonce::from_slice(nonce), Payload { msg, aad }).ok()
+}
+
+/// a verified request, what we need to answer it
+pub struct Session {
+    unique_id: ExtensionField,
+    c2s: Key,
+    s2c: Key,
+    cookies: usize,
+}
+
+#[derive(Clone)]
+pub struct Nts {
+    master_key: Key,
+}
+
+impl Nts {
+    /// the master key as 64 hex digits, it must be shared with whatever NTS-KE server issues our cookies
+    pub fn parse(hex: &str) -> Result<Nts, String> {
+        let hex = hex.trim();
+        let master_key: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+            .collect::<Option<_>>()
+            .ok_or("NTS key must be hex")?;
+        let master_key = master_key.try_into().map_err(|_| format!("NTS key must be {} hex digits", KEY_LEN * 2))?;
+        Ok(Nts { master_key })
+    }
+
+    pub fn load(path: &str) -> Result<Nts, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("could not read NTS key file {}: {}", path, e))?;
+        Nts::parse(&contents).map_err(|e| format!("invalid NTS key file {}: {}", path, e))
+    }
+
+    /// what NTS-KE hands a client for these keys, and what we hand out more of
+    pub fn make_cookie(&self, c2s: &Key, s2c: &Key) -> io::Result<Vec<u8>> {
+        let (ciphertext, nonce) = seal(&self.master_key, &[&c2s[..], &s2c[..]].concat(), &[])?;
+        Ok([&nonce[..], &ciphertext].concat())
+    }
+
+    fn open_cookie(&self, cookie: &[u8]) -> Option<(Key, Key)> {
+        if cookie.len() < NONCE_LEN {
+            return None;
+        }
+        let keys = open(&self.master_key, &cookie[..NONCE_LEN], &cookie[NONCE_LEN..], &[])?;
+        if keys.len() != KEY_LEN * 2 {
+            return None;
+        }
+        Some((keys[..KEY_LEN].try_into().ok()?, keys[KEY_LEN..].try_into().ok()?))
+    }
+
+    /// checks the request has a unique identifier, one cookie we can open and an authenticator over everything
+    /// before it made with the key in that cookie, None means send an NTS NAK
+    pub fn verify(&self, request: &NtpPacket) -> Option<Session> {
+        let (mut unique_id, mut cookie, mut placeholders) = (None, None, 0);
+        let auth = request.extensions.iter().position(|e| e.field_type == AUTHENTICATOR)?;
+        // anything after the authenticator would be unauthenticated
+        if auth != request.extensions.len() - 1 {
+            return None;
+        }
+        for extension in &request.extensions[..auth] {
+            match extension.field_type {
+                UNIQUE_IDENTIFIER if unique_id.is_none() && extension.body.len() >= 32 => unique_id = Some(extension),
+                COOKIE if cookie.is_none() => cookie = Some(extension),
+                COOKIE_PLACEHOLDER => placeholders += 1,
+                UNIQUE_IDENTIFIER | COOKIE => return None,
+                _ => {}
+            }
+        }
+        let (c2s, s2c) = self.open_cookie(&cookie?.body)?;
+
+        // nonce and ciphertext lengths, then each padded to 4 bytes
+        let body = &request.extensions[auth].body;
+        let nonce_len = u16::from_be_bytes(body.get(0..2)?.try_into().ok()?) as usize;
+        let ciphertext_len = u16::from_be_bytes(body.get(2..4)?.try_into().ok()?) as usize;
+        let nonce = body.get(4..4 + nonce_len)?;
+        let ciphertext_start = 4 + nonce_len.div_ceil(4) * 4;
+        let ciphertext = body.get(ciphertext_start..ciphertext_start + ciphertext_len)?;
+
+        let mut aad = request.header().to_vec();
+        for extension in &request.extensions[..auth] {
+            extension.write(&mut aad);
+        }
+        // any encrypted extension fields are ignored, we don't know of any a server should act on
+        open(&c2s, nonce, ciphertext, &aad)?;
+
+        Some(Session {
+            unique_id: unique_id?.clone(),
+            c2s,
+            s2c,
+            cookies: (1 + placeholders).min(MAX_COOKIES),
+        })
+    }
+
+    /// adds the unique identifier and an authenticator encrypting fresh cookies, the rest of the response must be final
+    pub fn seal(&self, session: &Session, response: &mut NtpPacket) -> io::Result<()> {
+        let mut cookies = Vec::new();
+        for _ in 0..session.cookies {
+            ExtensionField::new(COOKIE, self.make_cookie(&session.c2s, &session.s2c)?).write(&mut cookies);
+        }
+        response.extensions = vec![session.unique_id.clone()];
+        let mut aad = response.header().to_vec();
+        session.unique_id.write(&mut aad);
+        let (ciphertext, nonce) = seal(&session.s2c, &cookies, &aad)?;
+        response.extensions.push(authenticator(&nonce, &ciphertext));
+        Ok(())
+    }
+}
+
+/// the nonce and ciphertext lengths then each of them, a nonce of NONCE_LEN needs no padding
+fn authenticator(nonce: &[u8; NONCE_LEN], ciphertext: &[u8]) -> ExtensionField {
+    let mut body = Vec::with_capacity(4 + NONCE_LEN + ciphertext.len());
+    body.extend_from_slice(&(NONCE_LEN as u16).to_be_bytes());
+    body.extend_from_slice(&(ciphertext.len() as u16).to_be_bytes());
+    body.extend_from_slice(nonce);
+    body.extend_from_slice(ciphertext);
+    ExtensionField::new(AUTHENTICATOR, body)
+}
+
+/// the unique identifier of a request we send an NTS NAK to, so the client can match it up
+pub fn unique_id(request: &NtpPacket) -> Vec<ExtensionField> {
+    request.extensions.iter().filter(|e| e.field_type == UNIQUE_IDENTIFIER).take(1).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::NtpTimestamp;
+
+    const C2S: Key = [1; KEY_LEN];
+    const S2C: Key = [2; KEY_LEN];
+
+    fn nts() -> Nts {
+        Nts::parse(&"ab".repeat(KEY_LEN)).unwrap()
+    }
+
+    /// a client request carrying fields, then an authenticator over everything made with c2s
+    fn request(fields: Vec<ExtensionField>) -> NtpPacket {
+        let mut buf = [0u8; 48];
+        buf[0] = 4 << 3 | 3;
+        let mut request = NtpPacket::parse(&buf, "192.0.2.1:40000".parse().unwrap(), NtpTimestamp::zero()).unwrap();
+        request.extensions = fields;
+        authenticate(&mut request);
+        request
+    }
+
+    fn authenticate(request: &mut NtpPacket) {
+        let mut aad = request.header().to_vec();
+        for extension in &request.extensions {
+            extension.write(&mut aad);
+        }
+        let (ciphertext, nonce) = seal(&C2S, &[], &aad).unwrap();
+        request.extensions.push(authenticator(&nonce, &ciphertext));
+    }
+
+    fn fields(nts: &Nts, unique_id_len: usize, cookies: usize, placeholders: usize) -> Vec<ExtensionField> {
+        let mut fields = vec![ExtensionField::new(UNIQUE_IDENTIFIER, vec![7; unique_id_len])];
+        for _ in 0..cookies {
+            fields.push(ExtensionField::new(COOKIE, nts.make_cookie(&C2S, &S2C).unwrap()));
+        }
+        for _ in 0..placeholders {
+            fields.push(ExtensionField::new(COOKIE_PLACEHOLDER, vec![0; 100]));
+        }
+        fields
+    }
+
+    #[test]
+    fn verify() {
+        let nts = nts();
+        let session = nts.verify(&request(fields(&nts, 32, 1, 0))).unwrap();
+        assert_eq!((session.c2s, session.s2c, session.cookies), (C2S, S2C, 1));
+        // the header is covered
+        let mut tampered = request(fields(&nts, 32, 1, 0));
+        tampered.poll += 1;
+        assert!(nts.verify(&tampered).is_none());
+        // nothing may follow the authenticator
+        let mut trailing = request(fields(&nts, 32, 1, 0));
+        trailing.extensions.push(ExtensionField::new(COOKIE_PLACEHOLDER, vec![0; 100]));
+        assert!(nts.verify(&trailing).is_none());
+        // one cookie, and a unique identifier of at least 32 bytes, both authenticated correctly
+        assert!(nts.verify(&request(fields(&nts, 32, 2, 0))).is_none());
+        assert!(nts.verify(&request(fields(&nts, 31, 1, 0))).is_none());
+        // a cookie sealed with some other master key
+        assert!(Nts::parse(&"cd".repeat(KEY_LEN)).unwrap().verify(&request(fields(&nts, 32, 1, 0))).is_none());
+        // the cookie used plus one for each placeholder, up to MAX_COOKIES
+        assert_eq!(nts.verify(&request(fields(&nts, 32, 1, 3))).unwrap().cookies, 4);
+        assert_eq!(nts.verify(&request(fields(&nts, 32, 1, 20))).unwrap().cookies, MAX_COOKIES);
+    }
+
+    #[test]
+    fn seal_round_trip() {
+        let nts = nts();
+        let request = request(fields(&nts, 32, 1, 2));
+        let session = nts.verify(&request).unwrap();
+        let mut response = request;
+        response.mode = 4;
+        nts.seal(&session, &mut response).unwrap();
+        assert_eq!(response.extensions.len(), 2);
+        assert_eq!(response.extensions[0], ExtensionField::new(UNIQUE_IDENTIFIER, vec![7; 32]));
+
+        // what the client does with it
+        let body = &response.extensions[1].body;
+        assert_eq!(response.extensions[1].field_type, AUTHENTICATOR);
+        assert_eq!(u16::from_be_bytes([body[0], body[1]]) as usize, NONCE_LEN);
+        let mut aad = response.header().to_vec();
+        response.extensions[0].write(&mut aad);
+        assert!(open(&C2S, &body[4..4 + NONCE_LEN], &body[4 + NONCE_LEN..], &aad).is_none());
+        let mut plaintext = &open(&S2C, &body[4..4 + NONCE_LEN], &body[4 + NONCE_LEN..], &aad).unwrap()[..];
+        let mut cookies = 0;
+        while !plaintext.is_empty() {
+            assert_eq!(u16::from_be_bytes([plaintext[0], plaintext[1]]), COOKIE);
+            let len = u16::from_be_bytes([plaintext[2], plaintext[3]]) as usize;
+            assert_eq!(nts.open_cookie(&plaintext[4..len]), Some((C2S, S2C)));
+            plaintext = &plaintext[len..];
+            cookies += 1;
+        }
+        assert_eq!(cookies, 3);
+    }
+}
@@ -0,0 +1,941 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2017  Miroslav Lichvar
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::str::FromStr;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// the longest datagram we accept, plenty for a header, a few NTS cookies and a MAC
+pub const MAX_PACKET_LEN: usize = 1024;
+
+#[derive(Debug)]
+pub enum NtpError {
+    Io(io::Error),
+    PacketTooShort(usize),
+    /// a datagram with no payload at all, which scanners send to see whether anything is listening
+    EmptyDatagram(SocketAddr),
+    UnsupportedVersion(u8),
+    /// a well formed packet that isn't something we answer, holds the mode
+    NotRequest(u8),
+    /// a mode 2 or 4 reply, like one of our own looped back to us, which must never be answered, holds the mode
+    UnexpectedResponse(u8),
+    /// mode 6 (control) or 7 (private, ntpdc) query, which we never answer, these are often shorter than 48 bytes
+    ControlQuery(u8, SocketAddr),
+    /// the system clock reads before 1970, like an embedded board booting with an unset RTC
+    ClockBeforeEpoch,
+    /// the data after the header doesn't parse as extension fields followed by an optional MAC
+    MalformedExtension,
+    /// a datagram longer than MAX_PACKET_LEN, which we only got part of
+    PacketTruncated,
+    /// a reply from a server that isn't synchronized or sent a Kiss-o'-Death, holds its stratum and refid
+    Unsynchronized(u8, u32),
+    /// a response bigger than the unauthenticated request it answers, which we drop, holds both lengths and the client
+    Amplification(usize, usize, SocketAddr),
+    /// a --require-cookie cookie we never sent to this client
+    BadCookie(SocketAddr),
+}
+
+impl NtpError {
+    /// read timeouts and signals, which just mean go around the loop again
+    pub fn is_transient(&self) -> bool {
+        match self {
+            NtpError::Io(e) => matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted),
+            _ => false,
+        }
+    }
+
+    /// the socket was closed out from under us or never was one, every later call would fail the same way
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            #[cfg(unix)]
+            NtpError::Io(e) => matches!(e.raw_os_error(), Some(libc::EBADF) | Some(libc::ENOTSOCK)),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for NtpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NtpError::Io(e) => write!(f, "{}", e),
+            NtpError::PacketTooShort(len) => write!(f, "Packet too short: {} bytes", len),
+            NtpError::EmptyDatagram(remote_addr) => write!(f, "Empty datagram from {}, probably a probe", remote_addr),
+            NtpError::UnsupportedVersion(version) => write!(f, "Unsupported version: {}", version),
+            NtpError::NotRequest(mode) => write!(f, "Not a valid NTP request, mode: {}", mode),
+            NtpError::UnexpectedResponse(mode) => write!(f, "Unexpected response, mode: {}", mode),
+            NtpError::ControlQuery(mode, remote_addr) => write!(f, "Mode {} control query from {}", mode, remote_addr),
+            NtpError::ClockBeforeEpoch => write!(f, "System clock is set before 1970, not answering"),
+            NtpError::MalformedExtension => write!(f, "Malformed extension field"),
+            NtpError::PacketTruncated => write!(f, "Packet truncated, longer than {} bytes", MAX_PACKET_LEN),
+            NtpError::Unsynchronized(0, ref_id) => write!(f, "Kiss-o'-Death: {}", String::from_utf8_lossy(&ref_id.to_be_bytes())),
+            NtpError::Unsynchronized(stratum, _) => write!(f, "Server is not synchronized, stratum: {}", stratum),
+            NtpError::Amplification(response_len, request_len, remote_addr) => {
+                write!(f, "Not sending {} bytes in reply to {} unauthenticated bytes from {}", response_len, request_len, remote_addr)
+            }
+            NtpError::BadCookie(remote_addr) => write!(f, "Wrong cookie from {}", remote_addr),
+        }
+    }
+}
+
+impl std::error::Error for NtpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NtpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NtpError {
+    fn from(e: io::Error) -> NtpError {
+        NtpError::Io(e)
+    }
+}
+
+/// seconds from the NTP epoch, 1900, to the unix one
+const UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// the 32 bit seconds field wraps every era, the first time at 2036-02-07T06:28:16Z when era 1 starts over at 0
+const ERA_SECS: u64 = 1 << 32;
+
+/// a seconds field below this is read as era 1 rather than before 1968 in era 0, so we can tell 1968 through 2104 apart
+const ERA_PIVOT: u64 = 1 << 31;
+
+/// --clock, what NtpTimestamp::now reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    Realtime,
+    /// CLOCK_TAI less the kernel's TAI - UTC offset
+    Tai,
+}
+
+impl FromStr for Clock {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Clock, String> {
+        match s {
+            "realtime" => Ok(Clock::Realtime),
+            "tai" => Ok(Clock::Tai),
+            _ => Err("must be realtime or tai".to_owned()),
+        }
+    }
+}
+
+/// set once at startup, after checking CLOCK_TAI works
+#[cfg(target_os = "linux")]
+static TAI: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_os = "linux")]
+pub fn set_clock(clock: Clock) {
+    TAI.store(clock == Clock::Tai, Ordering::Relaxed);
+}
+
+/// where a server reads the time it serves, so tests can fix it and check exact timestamps
+pub trait TimeSource: Send + Sync {
+    fn now(&self) -> Result<NtpTimestamp, NtpError>;
+}
+
+/// NtpTimestamp::now, from whichever clock --clock picked
+pub struct SystemClock;
+
+impl TimeSource for SystemClock {
+    fn now(&self) -> Result<NtpTimestamp, NtpError> {
+        NtpTimestamp::now()
+    }
+}
+
+/// reads whatever it was last set to, time only passes when a test says so
+pub struct MockClock {
+    ts: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(ts: NtpTimestamp) -> MockClock {
+        MockClock { ts: AtomicU64::new(ts.ts) }
+    }
+
+    pub fn set(&self, ts: NtpTimestamp) {
+        self.ts.store(ts.ts, Ordering::Relaxed);
+    }
+
+    /// moves the time by a signed amount of 2^-32 seconds
+    pub fn advance(&self, by: i64) {
+        self.ts.fetch_add(by as u64, Ordering::Relaxed);
+    }
+}
+
+impl TimeSource for MockClock {
+    fn now(&self) -> Result<NtpTimestamp, NtpError> {
+        Ok(NtpTimestamp { ts: self.ts.load(Ordering::Relaxed) })
+    }
+}
+
+/// --freeze-time, always the same instant, or with advance that instant plus however long we've been running
+pub struct FrozenClock {
+    at: NtpTimestamp,
+    since: Option<Instant>,
+}
+
+impl FrozenClock {
+    pub fn new(at: NtpTimestamp, advance: bool) -> FrozenClock {
+        FrozenClock {
+            at,
+            since: if advance { Some(Instant::now()) } else { None },
+        }
+    }
+}
+
+impl TimeSource for FrozenClock {
+    fn now(&self) -> Result<NtpTimestamp, NtpError> {
+        let elapsed = self.since.map_or(Duration::ZERO, |since| since.elapsed());
+        Ok(self.at.offset((elapsed.as_secs() << 32) as i64 + ((elapsed.subsec_nanos() as u64) << 32) as i64 / 1_000_000_000))
+    }
+}
+
+/// days_from_civil from http://howardhinnant.github.io/date_algorithms.html
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct NtpTimestamp {
+    pub ts: u64,
+}
+
+/// UTC, like 2021-06-01T12:34:56.789012345Z
+impl fmt::Display for NtpTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_iso8601())
+    }
+}
+
+/// the raw value, with UTC alongside so --debug packet dumps are readable, zero means unset so it gets none
+impl fmt::Debug for NtpTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("NtpTimestamp");
+        s.field("ts", &self.ts);
+        if self.ts != 0 {
+            s.field("utc", &format_args!("{}", self));
+        }
+        s.finish()
+    }
+}
+
+impl NtpTimestamp {
+    pub fn now() -> Result<NtpTimestamp, NtpError> {
+        #[cfg(target_os = "linux")]
+        if TAI.load(Ordering::Relaxed) {
+            // it worked at startup, if it stops the realtime clock is the best we have
+            if let Ok(dur) = crate::clock::tai_utc() {
+                return Ok(NtpTimestamp::from_unix(dur));
+            }
+        }
+        let dur = SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_err(|_| NtpError::ClockBeforeEpoch)?;
+        Ok(NtpTimestamp::from_unix(dur))
+    }
+
+    /// from time since the unix epoch
+    pub fn from_unix(dur: Duration) -> NtpTimestamp {
+        // only the seconds within the era go on the wire, the era itself is implied
+        let secs = (dur.as_secs() + UNIX_OFFSET) % ERA_SECS;
+        let nanos = dur.subsec_nanos();
+
+        NtpTimestamp {
+            // exact and rounded down, 2^32 / 10^9 in floating point isn't
+            ts: (secs << 32) + ((nanos as u64) << 32) / 1_000_000_000,
+        }
+    }
+
+    pub fn zero() -> NtpTimestamp {
+        NtpTimestamp { ts: 0 }
+    }
+
+    /// shifted by a signed amount of 2^-32 seconds
+    pub fn offset(self, by: i64) -> NtpTimestamp {
+        NtpTimestamp { ts: self.ts.wrapping_add(by as u64) }
+    }
+
+    /// whole seconds since 1900, taking a small seconds field to be in era 1
+    pub fn secs(self) -> u64 {
+        let secs = self.ts >> 32;
+        if secs < ERA_PIVOT {
+            secs + ERA_SECS
+        } else {
+            secs
+        }
+    }
+
+    /// seconds since 1900 including the fraction, taking a small seconds field to be in era 1
+    pub fn as_secs_f64(self) -> f64 {
+        self.secs() as f64 + (self.ts & 0xffff_ffff) as f64 / 4294967296.0
+    }
+
+    /// UTC, like 2021-06-01T12:34:56.789012345Z
+    pub fn to_iso8601(self) -> String {
+        let unix_secs = self.secs() as i64 - UNIX_OFFSET as i64;
+        let nanos = ((self.ts & 0xffff_ffff) * 1_000_000_000) >> 32;
+        let (days, secs_of_day) = (unix_secs.div_euclid(86400), unix_secs.rem_euclid(86400));
+
+        // civil_from_days from http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+            year,
+            month,
+            day,
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            nanos
+        )
+    }
+
+    /// RFC 3339, like 2021-06-01T12:34:56.789Z or 2021-06-01T14:34:56+02:00, from 1900 on
+    pub fn from_rfc3339(s: &str) -> Option<NtpTimestamp> {
+        let field = |range: std::ops::Range<usize>| -> Option<i64> {
+            let digits = s.get(range)?;
+            if digits.bytes().all(|b| b.is_ascii_digit()) {
+                digits.parse().ok()
+            } else {
+                None
+            }
+        };
+        let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+        let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+        let bytes = s.as_bytes();
+        if bytes.get(4) != Some(&b'-') || bytes.get(7) != Some(&b'-') || !matches!(bytes.get(10), Some(b'T' | b't' | b' ')) {
+            return None;
+        }
+        if bytes.get(13) != Some(&b':') || bytes.get(16) != Some(&b':') {
+            return None;
+        }
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+
+        let mut rest = &s[19..];
+        let mut nanos = 0u64;
+        if let Some(fraction) = rest.strip_prefix('.') {
+            let len = fraction.bytes().take_while(|b| b.is_ascii_digit()).count();
+            if len == 0 {
+                return None;
+            }
+            // anything past nanoseconds is dropped
+            let digits = &fraction[..len.min(9)];
+            nanos = digits.parse::<u64>().ok()? * 10u64.pow(9 - digits.len() as u32);
+            rest = &fraction[len..];
+        }
+        let zone = match rest {
+            "Z" | "z" => 0,
+            _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+                let (hours, minutes): (i64, i64) = (rest.get(1..3)?.parse().ok()?, rest.get(4..6)?.parse().ok()?);
+                let zone = hours * 3600 + minutes * 60;
+                match rest.as_bytes()[0] {
+                    b'+' => zone,
+                    b'-' => -zone,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+
+        let unix_secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - zone;
+        let secs: u64 = (unix_secs + UNIX_OFFSET as i64).try_into().ok()?;
+        Some(NtpTimestamp {
+            ts: ((secs % ERA_SECS) << 32) + (nanos << 32) / 1_000_000_000,
+        })
+    }
+
+    fn read(buf: &[u8]) -> NtpTimestamp {
+        NtpTimestamp {
+            ts: u64::from_be_bytes(buf[..8].try_into().unwrap()),
+        }
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(&self.ts.to_be_bytes());
+    }
+}
+
+/// 16.16 fixed point seconds, used for root delay and dispersion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NtpFracValue {
+    pub val: u32,
+}
+
+impl NtpFracValue {
+    pub fn zero() -> NtpFracValue {
+        NtpFracValue { val: 0 }
+    }
+
+    /// saturating at the 65536 second limit
+    pub fn from_secs(secs: f64) -> NtpFracValue {
+        NtpFracValue {
+            val: (secs * 65536.0).clamp(0.0, u32::MAX as f64) as u32,
+        }
+    }
+
+    pub fn to_secs(self) -> f64 {
+        self.val as f64 / 65536.0
+    }
+
+    fn read(buf: &[u8]) -> NtpFracValue {
+        NtpFracValue {
+            val: u32::from_be_bytes(buf[..4].try_into().unwrap()),
+        }
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[..4].copy_from_slice(&self.val.to_be_bytes());
+    }
+}
+
+/// RFC 7822 sets a floor on extension field length, header included
+const MIN_EXTENSION_LEN: usize = 16;
+
+/// an RFC 7822 extension field, between the header and the MAC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionField {
+    pub field_type: u16,
+    /// as received, including any padding
+    pub body: Vec<u8>,
+}
+
+impl ExtensionField {
+    pub fn new(field_type: u16, body: Vec<u8>) -> ExtensionField {
+        ExtensionField { field_type, body }
+    }
+
+    /// the type/length header, then the body zero padded to a multiple of 4 bytes
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        let padding = (4 - self.body.len() % 4) % 4;
+        buf.extend_from_slice(&self.field_type.to_be_bytes());
+        buf.extend_from_slice(&((4 + self.body.len() + padding) as u16).to_be_bytes());
+        buf.extend_from_slice(&self.body);
+        buf.extend(std::iter::repeat_n(0, padding));
+    }
+
+    /// extension fields then an optional MAC, told apart by the MAC being exactly 4, 20 or 24 bytes at the end
+    #[allow(clippy::type_complexity)]
+    fn parse_all(mut buf: &[u8]) -> Result<(Vec<ExtensionField>, Option<(u32, Vec<u8>)>), NtpError> {
+        let mut extensions = Vec::new();
+        loop {
+            match buf.len() {
+                0 => return Ok((extensions, None)),
+                // a crypto-NAK, or a key id and an MD5 or SHA1 digest
+                4 | 20 | 24 => return Ok((extensions, Some((u32::from_be_bytes(buf[..4].try_into().unwrap()), buf[4..].to_vec())))),
+                len if len < MIN_EXTENSION_LEN => return Err(NtpError::MalformedExtension),
+                _ => {}
+            }
+            let field_type = u16::from_be_bytes([buf[0], buf[1]]);
+            let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+            if length < MIN_EXTENSION_LEN || !length.is_multiple_of(4) || length > buf.len() {
+                return Err(NtpError::MalformedExtension);
+            }
+            extensions.push(ExtensionField::new(field_type, buf[4..length].to_vec()));
+            buf = &buf[length..];
+        }
+    }
+}
+
+/// what a packet's mode, and for NTPv1 its source port, says it wants from us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    /// mode 3, or mode 0 from an NTPv1 client, answered in mode 4
+    ClientRequest,
+    /// mode 1, a peer offering to synchronize with us, answered in mode 2 as its symmetric passive side
+    SymmetricActive,
+    /// mode 6 or 7, ntpq and ntpdc queries, never answered
+    Control,
+    /// mode 2 or 4, what we send, never answered so two servers can't bounce replies between each other
+    ServerResponse,
+    /// mode 5 broadcasts, and mode 0 from anything but an NTPv1 client
+    Unknown,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct NtpPacket {
+    pub remote_addr: SocketAddr,
+    /// when we received it, only meaningful for requests
+    pub local_ts: NtpTimestamp,
+
+    pub leap: u8,
+    pub version: u8,
+    pub mode: u8,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    pub delay: NtpFracValue,
+    pub dispersion: NtpFracValue,
+    pub ref_id: u32,
+    pub ref_ts: NtpTimestamp,
+    pub orig_ts: NtpTimestamp,
+    pub rx_ts: NtpTimestamp,
+    pub tx_ts: NtpTimestamp,
+    /// NTPv4 only, older versions can only carry a MAC
+    pub extensions: Vec<ExtensionField>,
+    /// symmetric key MAC after the header and extension fields, key id and digest, key id 0 with no digest is a crypto-NAK
+    pub mac: Option<(u32, Vec<u8>)>,
+}
+
+impl NtpPacket {
+    /// receive and parse one datagram, socket I/O around parse
+    pub fn receive(socket: &UdpSocket, clock: &dyn TimeSource) -> Result<NtpPacket, NtpError> {
+        // one byte spare, so a datagram that fills the buffer was too long and cut short
+        let mut buf = [0u8; MAX_PACKET_LEN + 1];
+
+        let (len, remote_addr) = socket.recv_from(&mut buf)?;
+
+        let local_ts = clock.now()?;
+
+        if len > MAX_PACKET_LEN {
+            return Err(NtpError::PacketTruncated);
+        }
+
+        NtpPacket::parse(&buf[..len], remote_addr, local_ts)
+    }
+
+    /// the wire format, from a datagram however it was received
+    pub fn parse(buf: &[u8], remote_addr: SocketAddr, local_ts: NtpTimestamp) -> Result<NtpPacket, NtpError> {
+        if buf.is_empty() {
+            return Err(NtpError::EmptyDatagram(remote_addr));
+        }
+
+        if let Some(mode @ (6 | 7)) = buf.first().map(|b| b & 0x7) {
+            return Err(NtpError::ControlQuery(mode, remote_addr));
+        }
+
+        if buf.len() < 48 {
+            return Err(NtpError::PacketTooShort(buf.len()));
+        }
+
+        let version = (buf[0] >> 3) & 0x7;
+        if !(1..=4).contains(&version) {
+            return Err(NtpError::UnsupportedVersion(version));
+        }
+
+        // older versions can't have extension fields, anything after their header that isn't a MAC is ignored
+        let (extensions, mac) = if version < 4 && !matches!(buf.len() - 48, 4 | 20 | 24) {
+            (Vec::new(), None)
+        } else {
+            ExtensionField::parse_all(&buf[48..])?
+        };
+
+        Ok(NtpPacket {
+            remote_addr,
+            local_ts,
+            leap: buf[0] >> 6,
+            version,
+            mode: buf[0] & 0x7,
+            stratum: buf[1],
+            poll: buf[2] as i8,
+            precision: buf[3] as i8,
+            delay: NtpFracValue::read(&buf[4..8]),
+            dispersion: NtpFracValue::read(&buf[8..12]),
+            ref_id: u32::from_be_bytes(buf[12..16].try_into().unwrap()),
+            ref_ts: NtpTimestamp::read(&buf[16..24]),
+            orig_ts: NtpTimestamp::read(&buf[24..32]),
+            rx_ts: NtpTimestamp::read(&buf[32..40]),
+            tx_ts: NtpTimestamp::read(&buf[40..48]),
+            extensions,
+            mac,
+        })
+    }
+
+    /// socket I/O around to_bytes, UDP sends a whole datagram or fails, but one only partly sent is an error too
+    pub fn send(&self, socket: &UdpSocket) -> io::Result<usize> {
+        self.send_with(|buf, addr| socket.send_to(buf, addr))
+    }
+
+    /// send with whatever send_to does the sending
+    fn send_with(&self, send_to: impl FnOnce(&[u8], SocketAddr) -> io::Result<usize>) -> io::Result<usize> {
+        let buf = self.to_bytes();
+        let sent = send_to(&buf, self.remote_addr)?;
+        if sent != buf.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, format!("short send to {}, {} of {} bytes", self.remote_addr, sent, buf.len())));
+        }
+        Ok(sent)
+    }
+
+    /// the wire format, the header, any extension fields, then the MAC if there is one
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.mac_data();
+        if let Some((key_id, digest)) = &self.mac {
+            buf.extend_from_slice(&key_id.to_be_bytes());
+            buf.extend_from_slice(digest);
+        }
+        buf
+    }
+
+    /// how long to_bytes would be, without building it
+    pub fn wire_len(&self) -> usize {
+        let extensions: usize = self.extensions.iter().map(|e| 4 + e.body.len().div_ceil(4) * 4).sum();
+        48 + extensions + self.mac.as_ref().map_or(0, |(_, digest)| 4 + digest.len())
+    }
+
+    /// what a MAC is computed over, the header and extension fields
+    pub fn mac_data(&self) -> Vec<u8> {
+        let mut buf = self.header().to_vec();
+        for extension in &self.extensions {
+            extension.write(&mut buf);
+        }
+        buf
+    }
+
+    pub fn header(&self) -> [u8; 48] {
+        let mut buf = [0u8; 48];
+
+        buf[0] = self.leap << 6 | self.version << 3 | self.mode;
+        buf[1] = self.stratum;
+        buf[2] = self.poll as u8;
+        buf[3] = self.precision as u8;
+        self.delay.write(&mut buf[4..8]);
+        self.dispersion.write(&mut buf[8..12]);
+        buf[12..16].copy_from_slice(&self.ref_id.to_be_bytes());
+        self.ref_ts.write(&mut buf[16..24]);
+        self.orig_ts.write(&mut buf[24..32]);
+        self.rx_ts.write(&mut buf[32..40]);
+        self.tx_ts.write(&mut buf[40..48]);
+
+        buf
+    }
+
+    /// NTPv1 clients left the mode 0, unspecified, and peers sent from port 123 where clients never did, so a mode 0
+    /// packet from port 123 is taken for a peer's and not answered, --client-port-filter is checked before this
+    pub fn classify(&self) -> RequestKind {
+        match self.mode {
+            3 => RequestKind::ClientRequest,
+            0 if self.version == 1 && self.remote_addr.port() != 123 => RequestKind::ClientRequest,
+            1 => RequestKind::SymmetricActive,
+            2 | 4 => RequestKind::ServerResponse,
+            6 | 7 => RequestKind::Control,
+            _ => RequestKind::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(version: u8) -> NtpPacket {
+        NtpPacket {
+            remote_addr: "192.0.2.1:40000".parse().unwrap(),
+            local_ts: NtpTimestamp { ts: 0x0102_0304_0506_0708 },
+            leap: 1,
+            version,
+            mode: 4,
+            stratum: 2,
+            poll: 6,
+            precision: -20,
+            delay: NtpFracValue { val: 0x0001_8000 },
+            dispersion: NtpFracValue { val: 0x0000_4000 },
+            ref_id: u32::from_be_bytes(*b"GPS\0"),
+            ref_ts: NtpTimestamp { ts: 0xe3a2_0000_8000_0000 },
+            orig_ts: NtpTimestamp { ts: 0x1122_3344_5566_7788 },
+            rx_ts: NtpTimestamp { ts: 0xe3a2_0001_0000_0001 },
+            tx_ts: NtpTimestamp { ts: 0xe3a2_0001_0000_0002 },
+            extensions: Vec::new(),
+            mac: None,
+        }
+    }
+
+    fn round_trip(packet: &NtpPacket) -> NtpPacket {
+        NtpPacket::parse(&packet.to_bytes(), packet.remote_addr, packet.local_ts).unwrap()
+    }
+
+    #[test]
+    fn rfc3339() {
+        let parse = |s| NtpTimestamp::from_rfc3339(s).map(NtpTimestamp::to_iso8601);
+        assert_eq!(parse("2021-06-01T12:34:56Z").unwrap(), "2021-06-01T12:34:56.000000000Z");
+        assert_eq!(parse("2021-06-01t14:34:56.5+02:00").unwrap(), "2021-06-01T12:34:56.500000000Z");
+        assert_eq!(parse("2021-06-01 10:04:56.123456789123-02:30").unwrap(), "2021-06-01T12:34:56.123456788Z");
+        assert_eq!(parse("2038-01-19T03:14:08Z").unwrap(), "2038-01-19T03:14:08.000000000Z");
+        assert_eq!(NtpTimestamp::from_rfc3339("1900-01-01T00:00:00Z").unwrap().ts, 0);
+        for bad in [
+            "1899-12-31T23:59:59Z",
+            "2021-06-01T12:34:56",
+            "2021-13-01T12:34:56Z",
+            "2021-06-01T12:34:56.Z",
+            "2021-06-01X12:34:56Z",
+            "2021-6-01T12:34:56Z",
+        ] {
+            assert!(NtpTimestamp::from_rfc3339(bad).is_none(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn frozen_clock() {
+        let at = NtpTimestamp::from_rfc3339("2021-06-01T12:34:56Z").unwrap();
+        assert_eq!(FrozenClock::new(at, false).now().unwrap(), at);
+        let clock = FrozenClock::new(at, true);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(clock.now().unwrap().ts > at.ts);
+    }
+
+    #[test]
+    fn error_classes() {
+        let io = |kind| NtpError::Io(io::Error::from(kind));
+        assert!(io(io::ErrorKind::Interrupted).is_transient());
+        assert!(io(io::ErrorKind::WouldBlock).is_transient());
+        assert!(!io(io::ErrorKind::ConnectionRefused).is_transient());
+        assert!(!io(io::ErrorKind::ConnectionRefused).is_fatal());
+        #[cfg(unix)]
+        assert!(NtpError::Io(io::Error::from_raw_os_error(libc::EBADF)).is_fatal());
+        assert!(!NtpError::PacketTooShort(0).is_fatal());
+    }
+
+    #[test]
+    fn round_trip_every_version() {
+        for version in 1..=4 {
+            let packet = packet(version);
+            assert_eq!(round_trip(&packet), packet);
+        }
+    }
+
+    #[test]
+    fn round_trip_max_values() {
+        let mut packet = packet(4);
+        packet.leap = 3;
+        packet.mode = 5;
+        packet.stratum = u8::MAX;
+        packet.delay = NtpFracValue { val: u32::MAX };
+        packet.dispersion = NtpFracValue { val: u32::MAX };
+        packet.ref_id = u32::MAX;
+        packet.ref_ts = NtpTimestamp { ts: u64::MAX };
+        packet.orig_ts = NtpTimestamp { ts: u64::MAX };
+        packet.rx_ts = NtpTimestamp { ts: u64::MAX };
+        packet.tx_ts = NtpTimestamp { ts: u64::MAX };
+        assert_eq!(round_trip(&packet), packet);
+    }
+
+    #[test]
+    fn round_trip_mac() {
+        let mut packet = packet(4);
+        for mac in [(0, vec![]), (1, vec![0xaa; 16]), (u32::MAX, vec![0x55; 20])] {
+            packet.mac = Some(mac);
+            assert_eq!(round_trip(&packet), packet);
+        }
+        assert_eq!(packet.to_bytes().len(), 48 + 4 + 20);
+    }
+
+    #[test]
+    fn response_sizes() {
+        let mut packet = packet(4);
+        assert_eq!(packet.to_bytes().len(), 48);
+        // a key id and an MD5 digest
+        packet.mac = Some((1, vec![0xaa; 16]));
+        assert_eq!(packet.to_bytes().len(), 68);
+        assert_eq!(packet.wire_len(), 68);
+        assert_eq!(packet.to_bytes()[..48], packet.header());
+    }
+
+    #[test]
+    fn sends_whole_datagram() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut packet = packet(4);
+        packet.remote_addr = socket.local_addr().unwrap();
+        packet.mac = Some((1, vec![0xaa; 16]));
+        assert_eq!(packet.send(&socket).unwrap(), 68);
+        let mut buf = [0u8; 100];
+        assert_eq!(socket.recv(&mut buf).unwrap(), 68);
+        // a send_to that only took part of it
+        let e = packet.send_with(|buf, _| Ok(buf.len() - 20)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WriteZero);
+        assert_eq!(e.to_string(), format!("short send to {}, 48 of 68 bytes", packet.remote_addr));
+    }
+
+    #[test]
+    fn round_trip_extensions() {
+        let mut packet = packet(4);
+        packet.extensions = vec![ExtensionField::new(0x0104, vec![7; 32]), ExtensionField::new(0x2005, vec![1; 12])];
+        assert_eq!(round_trip(&packet), packet);
+        assert_eq!(packet.to_bytes().len(), 48 + 36 + 16);
+        assert_eq!(packet.wire_len(), 48 + 36 + 16);
+        // and with a MAC after them
+        packet.mac = Some((1, vec![0xaa; 16]));
+        assert_eq!(round_trip(&packet), packet);
+    }
+
+    #[test]
+    fn extension_padding() {
+        let mut buf = Vec::new();
+        ExtensionField::new(0x0104, vec![1; 13]).write(&mut buf);
+        assert_eq!(buf.len(), 20);
+        assert_eq!(buf[..4], [0x01, 0x04, 0, 20]);
+        assert_eq!(buf[17..], [0, 0, 0]);
+    }
+
+    #[test]
+    fn parse_rejects_malformed_extensions() {
+        let addr = "192.0.2.1:40000".parse().unwrap();
+        let header = packet(4).to_bytes();
+        let malformed: [&[u8]; 5] = [
+            // too short for a field or a MAC
+            &[0; 8],
+            // length shorter than the minimum
+            &[0, 1, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            // length not a multiple of 4
+            &[0, 1, 0, 17, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            // length past the end of the packet
+            &[0, 1, 0, 32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            // a valid field followed by a truncated one
+            &[0, 1, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0],
+        ];
+        for trailer in malformed.iter() {
+            let buf = [&header[..], trailer].concat();
+            assert!(matches!(NtpPacket::parse(&buf, addr, NtpTimestamp::zero()), Err(NtpError::MalformedExtension)), "{:?}", trailer);
+        }
+        // older versions never had extension fields, so whatever follows is ignored as before
+        let mut buf = [&header[..], &[0; 8]].concat();
+        buf[0] = 3 << 3 | 3;
+        assert!(NtpPacket::parse(&buf, addr, NtpTimestamp::zero()).unwrap().extensions.is_empty());
+    }
+
+    #[test]
+    fn round_trip_negative_poll_and_precision() {
+        let mut packet = packet(4);
+        for (poll, precision) in [(-1, -1), (i8::MIN, i8::MIN), (i8::MAX, i8::MAX), (0, -32)] {
+            packet.poll = poll;
+            packet.precision = precision;
+            assert_eq!(round_trip(&packet), packet);
+        }
+    }
+
+    #[test]
+    fn to_bytes_offsets() {
+        let buf = packet(4).to_bytes();
+        assert_eq!(buf[0], 1 << 6 | 4 << 3 | 4);
+        assert_eq!(buf[1], 2);
+        assert_eq!(buf[2], 6);
+        assert_eq!(buf[3] as i8, -20);
+        assert_eq!(buf[4..8], [0, 1, 0x80, 0]);
+        assert_eq!(buf[8..12], [0, 0, 0x40, 0]);
+        assert_eq!(&buf[12..16], b"GPS\0");
+        assert_eq!(buf[24..32], [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]);
+        assert_eq!(buf[40..48], [0xe3, 0xa2, 0, 1, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn parse_rejects_bad_packets() {
+        let addr = "192.0.2.1:40000".parse().unwrap();
+        let mut buf = packet(4).to_bytes();
+        assert!(matches!(NtpPacket::parse(&buf[..47], addr, NtpTimestamp::zero()), Err(NtpError::PacketTooShort(47))));
+        assert!(matches!(NtpPacket::parse(&[], addr, NtpTimestamp::zero()), Err(NtpError::EmptyDatagram(a)) if a == addr));
+        for version in [0, 5, 7] {
+            buf[0] = version << 3 | 3;
+            assert!(matches!(NtpPacket::parse(&buf, addr, NtpTimestamp::zero()), Err(NtpError::UnsupportedVersion(v)) if v == version));
+        }
+        for mode in [6, 7] {
+            assert!(matches!(NtpPacket::parse(&[2 << 3 | mode], addr, NtpTimestamp::zero()), Err(NtpError::ControlQuery(m, _)) if m == mode));
+        }
+    }
+
+    #[test]
+    fn classify_every_mode_and_version() {
+        let classify = |version, mode, port| {
+            let mut packet = packet(version);
+            packet.mode = mode;
+            packet.remote_addr.set_port(port);
+            packet.classify()
+        };
+        for version in 1..=4 {
+            for port in [123, 40000] {
+                assert_eq!(classify(version, 1, port), RequestKind::SymmetricActive);
+                assert_eq!(classify(version, 2, port), RequestKind::ServerResponse);
+                assert_eq!(classify(version, 3, port), RequestKind::ClientRequest);
+                assert_eq!(classify(version, 4, port), RequestKind::ServerResponse);
+                assert_eq!(classify(version, 5, port), RequestKind::Unknown);
+                assert_eq!(classify(version, 6, port), RequestKind::Control);
+                assert_eq!(classify(version, 7, port), RequestKind::Control);
+            }
+            // only NTPv1 clients sent mode 0, and never from port 123
+            let mode_0 = if version == 1 { RequestKind::ClientRequest } else { RequestKind::Unknown };
+            assert_eq!(classify(version, 0, 40000), mode_0, "version {}", version);
+            assert_eq!(classify(version, 0, 123), RequestKind::Unknown, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn from_unix_fraction() {
+        let frac = |nanos| NtpTimestamp::from_unix(Duration::new(0, nanos)).ts & 0xffff_ffff;
+        let mut last = 0;
+        for nanos in (0..1_000_000_000).step_by(997).chain([999_999_999]) {
+            let float = (nanos as f64 * 4.294967296) as u64;
+            assert!(frac(nanos).abs_diff(float) <= 1, "{} nanos gave {} not about {}", nanos, frac(nanos), float);
+            // rounding back up lands on the same nanosecond
+            assert_eq!((frac(nanos) * 1_000_000_000 + 0xffff_ffff) >> 32, nanos as u64);
+            assert!(frac(nanos) >= last);
+            last = frac(nanos);
+        }
+        assert_eq!(frac(1), 4);
+        assert_eq!(frac(500_000_000), 0x8000_0000);
+    }
+
+    #[test]
+    fn timestamp_formatting() {
+        let ts = NtpTimestamp::from_unix(Duration::new(1_622_550_896, 500_000_000));
+        assert_eq!(ts.to_string(), "2021-06-01T12:34:56.500000000Z");
+        assert_eq!(format!("{:?}", ts), format!("NtpTimestamp {{ ts: {}, utc: 2021-06-01T12:34:56.500000000Z }}", ts.ts));
+        assert_eq!(format!("{:?}", NtpTimestamp::zero()), "NtpTimestamp { ts: 0 }");
+    }
+
+    #[test]
+    fn era_rollover() {
+        // 2036-02-07T06:28:16Z starts era 1 with the seconds field back at 0
+        let era1 = NtpTimestamp::from_unix(Duration::from_secs(ERA_SECS - UNIX_OFFSET));
+        assert_eq!(era1.ts, 0);
+        assert_eq!(era1.secs(), ERA_SECS);
+        assert_eq!(era1.to_iso8601(), "2036-02-07T06:28:16.000000000Z");
+
+        let last = NtpTimestamp::from_unix(Duration::from_secs(ERA_SECS - UNIX_OFFSET - 1));
+        assert_eq!(last.ts >> 32, 0xffff_ffff);
+        assert_eq!(last.to_iso8601(), "2036-02-07T06:28:15.000000000Z");
+
+        // 2040-01-01T00:00:00Z is 4417977600 seconds after 1900, which wraps to 123010304
+        let ts = NtpTimestamp::from_unix(Duration::from_secs(2_208_988_800));
+        assert_eq!(ts.ts, 123_010_304 << 32);
+        assert_eq!(ts.secs(), 4_417_977_600);
+        assert_eq!(ts.to_iso8601(), "2040-01-01T00:00:00.000000000Z");
+
+        // era 0 still reads back as itself
+        let ts = NtpTimestamp::from_unix(Duration::from_secs(1_622_550_896));
+        assert_eq!(ts.secs(), 1_622_550_896 + UNIX_OFFSET);
+    }
+}
@@ -0,0 +1,184 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! the Linux PPS API, the kernel timestamps each pulse of a pulse-per-second signal against the system clock,
+//! which tells us how far the system clock is from the top of the second
+
+use crate::gps::{Fix, SharedFix};
+use crate::packet::NtpTimestamp;
+use crate::signal;
+use crate::upstream::{Reference, SourceState};
+use crate::Config;
+use std::fs::File;
+use std::io::{Error, Result};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// struct pps_ktime from linux/pps.h
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PpsKtime {
+    sec: i64,
+    nsec: i32,
+    flags: u32,
+}
+
+/// struct pps_kinfo
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PpsKinfo {
+    assert_sequence: u32,
+    clear_sequence: u32,
+    assert_tu: PpsKtime,
+    clear_tu: PpsKtime,
+    current_mode: libc::c_int,
+}
+
+/// struct pps_fdata
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PpsFdata {
+    info: PpsKinfo,
+    timeout: PpsKtime,
+}
+
+/// _IOWR('p', 0xa4, struct pps_fdata *), the kernel header really does encode the size of a pointer, this is the
+/// asm-generic encoding that x86 and arm use
+const PPS_FETCH: libc::c_ulong = (3 << 30) | ((std::mem::size_of::<*const PpsFdata>() as libc::c_ulong) << 16) | ((b'p' as libc::c_ulong) << 8) | 0xa4;
+
+/// how long without a pulse before we stop trusting the last one
+const MAX_SILENCE: Duration = Duration::from_secs(10);
+
+pub struct Pps {
+    path: String,
+    device: File,
+}
+
+impl Pps {
+    pub fn open(path: &str) -> Result<Pps> {
+        let device = File::open(path).map_err(|e| Error::new(e.kind(), format!("could not open {}: {}", path, e)))?;
+        let pps = Pps { path: path.to_owned(), device };
+        // make sure it really is a PPS device
+        pps.fetch()?;
+        Ok(pps)
+    }
+
+    /// the sequence number and system clock time since the unix epoch of the latest assert edge, without waiting for one
+    fn fetch(&self) -> Result<(u32, Duration)> {
+        // a zero timeout without PPS_TIME_INVALID returns right away
+        let mut fdata = PpsFdata::default();
+        if unsafe { libc::ioctl(self.device.as_raw_fd(), PPS_FETCH as _, &mut fdata) } != 0 {
+            let e = Error::last_os_error();
+            return Err(Error::new(e.kind(), format!("PPS_FETCH on {} failed: {}", self.path, e)));
+        }
+        let ts = fdata.info.assert_tu;
+        Ok((fdata.info.assert_sequence, Duration::new(ts.sec.max(0) as u64, ts.nsec.clamp(0, 999_999_999) as u32)))
+    }
+}
+
+/// 2^-32 seconds to add to the system clock to put edge on the top of the second. With a GPS fix that is the second
+/// the fix puts the edge nearest, otherwise the nearest by the system clock, which has to be within half a second then
+fn edge_offset(edge: Duration, fix: Option<Fix>) -> i64 {
+    let second = match fix {
+        // the fix's second plus however long ago by the system clock it was read, less than a second before the edge
+        // if the receiver is sending every second, and its late arrival only has to be under half a second
+        Some(fix) => (fix.utc as f64 + (edge.as_secs_f64() - fix.received.as_secs_f64())).round() as i128,
+        None => edge.as_secs_f64().round() as i128,
+    };
+    let nanos = second * 1_000_000_000 - edge.as_nanos() as i128;
+    ((nanos << 32) / 1_000_000_000) as i64
+}
+
+/// checks for a new pulse every second until shutdown, pointing reference at it, and back at the plain system clock
+/// as live_config has it if the pulses stop, gps numbers the seconds when there is one
+pub fn run(pps: Pps, gps: Option<SharedFix>, reference: Arc<Mutex<Reference>>, precision: i8, live_config: Arc<RwLock<Arc<Config>>>, debug: bool) {
+    let mut last_sequence = None;
+    let mut last_pulse: Option<Instant> = None;
+    reference.lock().expect("reference poisoned").state = SourceState::Init;
+    while !signal::shutdown_requested() {
+        match pps.fetch() {
+            Ok((sequence, edge)) if Some(sequence) != last_sequence && sequence != 0 => {
+                let fix = gps.as_ref().and_then(|gps| *gps.lock().expect("gps fix poisoned"));
+                let offset = edge_offset(edge, fix);
+                if debug {
+                    debug!("pps {} pulse {} offset {:.9}s", pps.path, sequence, offset as f64 / 4294967296.0);
+                }
+                if last_pulse.is_none() {
+                    info!("pps {} locked", pps.path);
+                }
+                last_sequence = Some(sequence);
+                last_pulse = Some(Instant::now());
+                let source = if fix.is_some() { *b"GPS\0" } else { *b"PPS\0" };
+                reference
+                    .lock()
+                    .expect("reference poisoned")
+                    .set_primary(source, offset, NtpTimestamp::from_unix(edge).offset(offset), 2f64.powi(precision as i32));
+            }
+            Ok(_) => {}
+            Err(e) => warn!("{}", e),
+        }
+        if last_pulse.is_some_and(|t| t.elapsed() > MAX_SILENCE) {
+            warn!("no pulse from pps {} for {:?}, serving the system clock", pps.path, MAX_SILENCE);
+            last_pulse = None;
+            // as configured now, a reload may have changed it since startup
+            let system = live_config.read().expect("config poisoned").system_reference();
+            reference.lock().expect("reference poisoned").set_system_clock(&system);
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// offset in 2^-32 seconds is about seconds, allowing for rounding
+    fn assert_offset(offset: i64, seconds: f64) {
+        let expected = seconds * 4294967296.0;
+        assert!((offset as f64 - expected).abs() <= 1.0, "{} is not {}", offset, expected);
+    }
+
+    #[test]
+    fn edge_without_fix() {
+        // a microsecond after the second by the system clock, so the clock is fast and the offset negative
+        assert_offset(edge_offset(Duration::new(1000, 1_000), None), -0.000_001);
+        // a microsecond before, slow and positive
+        assert_offset(edge_offset(Duration::new(999, 999_999_000), None), 0.000_001);
+        assert_eq!(edge_offset(Duration::new(1000, 0), None), 0);
+    }
+
+    #[test]
+    fn edge_with_fix() {
+        // the system clock is 5.4s slow, the last fix said second 1000 and arrived 0.3s after that second's edge,
+        // so the edge is second 1001 whatever the system clock says
+        let fix = Some(Fix {
+            utc: 1000,
+            received: Duration::new(994, 900_000_000),
+        });
+        assert_offset(edge_offset(Duration::new(995, 600_001_000), fix), 5.399_999);
+        assert_offset(edge_offset(Duration::new(995, 599_999_000), fix), 5.400_001);
+        // and 5.4s fast
+        let fix = Some(Fix {
+            utc: 1000,
+            received: Duration::new(1005, 700_000_000),
+        });
+        assert_offset(edge_offset(Duration::new(1006, 400_001_000), fix), -5.400_001);
+        assert_offset(edge_offset(Duration::new(1006, 399_999_000), fix), -5.399_999);
+    }
+}
@@ -0,0 +1,133 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+#[cfg(unix)]
+use std::ffi::CString;
+use std::io::{Error, Result};
+
+#[cfg(unix)]
+fn c_string(name: &str) -> Result<CString> {
+    CString::new(name).map_err(|_| Error::other(format!("invalid name {:?}", name)))
+}
+
+#[cfg(unix)]
+/// returns the uid and primary gid, if known
+fn lookup_user(user: &str) -> Result<(libc::uid_t, Option<libc::gid_t>)> {
+    let name = c_string(user)?;
+    // we are still single threaded at this point so the non-reentrant versions are fine
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !pw.is_null() {
+        return unsafe { Ok(((*pw).pw_uid, Some((*pw).pw_gid))) };
+    }
+    let uid = user.parse().map_err(|_| Error::other(format!("unknown user {}", user)))?;
+    let pw = unsafe { libc::getpwuid(uid) };
+    Ok((uid, if pw.is_null() { None } else { unsafe { Some((*pw).pw_gid) } }))
+}
+
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = c_string(group)?;
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    if !gr.is_null() {
+        return unsafe { Ok((*gr).gr_gid) };
+    }
+    group.parse().map_err(|_| Error::other(format!("unknown group {}", group)))
+}
+
+#[cfg(unix)]
+fn check(ret: libc::c_int, call: &str) -> Result<()> {
+    if ret != 0 {
+        let e = Error::last_os_error();
+        return Err(Error::new(e.kind(), format!("{} failed: {}", call, e)));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+/// the uid and gid to switch to
+fn lookup(user: Option<&str>, group: Option<&str>) -> Result<(Option<libc::uid_t>, Option<libc::gid_t>)> {
+    let (uid, user_gid) = match user {
+        Some(user) => {
+            let (uid, gid) = lookup_user(user)?;
+            (Some(uid), gid)
+        }
+        None => (None, None),
+    };
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user_gid,
+    };
+
+    if let (Some(_), None) = (uid, gid) {
+        return Err(Error::other("user has no primary group, supply one with --group"));
+    }
+    Ok((uid, gid))
+}
+
+#[cfg(unix)]
+/// what drop_privileges would fail on, without doing anything
+pub fn check_privileges(user: Option<&str>, group: Option<&str>, chroot: Option<&str>) -> Result<()> {
+    lookup(user, group)?;
+    if let Some(dir) = chroot {
+        if !std::fs::metadata(dir).map_err(|e| Error::new(e.kind(), format!("chroot directory {}: {}", dir, e)))?.is_dir() {
+            return Err(Error::other(format!("chroot directory {} is not a directory", dir)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+/// chroot into a directory and switch to the given user and/or group, names are looked up before the
+/// chroot hides /etc/passwd, and the group must be changed before the user because we can't change it
+/// anymore once we are no longer root
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>, chroot: Option<&str>) -> Result<()> {
+    let (uid, gid) = lookup(user, group)?;
+
+    if let Some(dir) = chroot {
+        let path = c_string(dir)?;
+        check(unsafe { libc::chroot(path.as_ptr()) }, &format!("chroot({})", dir))?;
+        check(unsafe { libc::chdir(b"/\0".as_ptr() as *const libc::c_char) }, "chdir(/)")?;
+    }
+
+    if let Some(gid) = gid {
+        check(unsafe { libc::setgroups(1, &gid) }, &format!("setgroups({})", gid))?;
+        check(unsafe { libc::setgid(gid) }, &format!("setgid({})", gid))?;
+    }
+    if let Some(uid) = uid {
+        check(unsafe { libc::setuid(uid) }, &format!("setuid({})", uid))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn unsupported(user: Option<&str>, group: Option<&str>, chroot: Option<&str>) -> Result<()> {
+    if user.is_some() || group.is_some() || chroot.is_some() {
+        return Err(Error::new(std::io::ErrorKind::Unsupported, "--user, --group and --chroot are only supported on unix"));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn check_privileges(user: Option<&str>, group: Option<&str>, chroot: Option<&str>) -> Result<()> {
+    unsupported(user, group, chroot)
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>, chroot: Option<&str>) -> Result<()> {
+    unsupported(user, group, chroot)
+}
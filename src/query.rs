@@ -0,0 +1,68 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! kiss-ntpd query, a one shot client for checking a server is answering sensibly
+
+use crate::packet::NtpError;
+use crate::socket;
+use crate::upstream::{self, secs};
+use std::net::Ipv4Addr;
+
+/// stratum 0 and 1 refids are ASCII, like a kiss code or GPS, higher ones an IPv4 address or the start of a hash
+pub fn ref_id(stratum: u8, ref_id: u32) -> String {
+    let bytes = ref_id.to_be_bytes();
+    let name: Vec<u8> = bytes.iter().copied().take_while(|b| *b != 0).collect();
+    if stratum < 2 && !name.is_empty() && name.iter().all(|b| b.is_ascii_graphic()) {
+        String::from_utf8_lossy(&name).into_owned()
+    } else {
+        Ipv4Addr::from(bytes).to_string()
+    }
+}
+
+/// sends one request to server, printing what it said and how far off our clock is from it,
+/// a Kiss-o'-Death or unsynchronized reply is printed and then an error
+pub fn run(server: &str) -> Result<(), String> {
+    let addr = socket::resolve(&socket::with_default_port(server)).map_err(|e| format!("could not resolve {}: {}", server, e))?;
+    let socket = upstream::client_socket(addr).map_err(|e| format!("could not open socket: {}", e))?;
+    let (request, reply) = upstream::exchange(&socket, addr).map_err(|e| match e {
+        e if e.is_transient() => format!("no reply from {}", addr),
+        e => format!("{}: {}", addr, e),
+    })?;
+    let (offset, delay) = upstream::offset_delay(&request, &reply);
+
+    println!(
+        "server {}, version {}, stratum {}, refid {}, leap {}",
+        addr,
+        reply.version,
+        reply.stratum,
+        ref_id(reply.stratum, reply.ref_id),
+        reply.leap
+    );
+    println!("offset {:+.6}s, delay {:.6}s", secs(offset), secs(delay));
+    println!(
+        "root delay {:.6}s, root dispersion {:.6}s, precision 2^{}, reference time {}",
+        reply.delay.to_secs(),
+        reply.dispersion.to_secs(),
+        reply.precision,
+        reply.ref_ts.to_iso8601()
+    );
+    if reply.leap == 3 || reply.stratum == 0 || reply.stratum > 15 {
+        return Err(NtpError::Unsynchronized(reply.stratum, reply.ref_id).to_string());
+    }
+    Ok(())
+}
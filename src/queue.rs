@@ -0,0 +1,108 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! a bounded queue between the receiver thread and the responders for --queue-depth
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// when full, pushing drops the oldest item, under overload a stale request is worth less than a fresh one
+pub struct Queue<T> {
+    items: Mutex<VecDeque<T>>,
+    ready: Condvar,
+    depth: usize,
+    /// set once nothing more will be pushed
+    closed: AtomicBool,
+}
+
+impl<T> Queue<T> {
+    pub fn new(depth: usize) -> Queue<T> {
+        Queue {
+            items: Mutex::new(VecDeque::with_capacity(depth)),
+            ready: Condvar::new(),
+            depth,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// adds item at the back, returning the item dropped from the front to make room if it was full
+    pub fn push(&self, item: T) -> Option<T> {
+        let mut items = self.items.lock().expect("queue poisoned");
+        let dropped = if items.len() >= self.depth { items.pop_front() } else { None };
+        items.push_back(item);
+        drop(items);
+        self.ready.notify_one();
+        dropped
+    }
+
+    /// the oldest item, waiting up to timeout for one to arrive or the queue to close
+    pub fn pop(&self, timeout: Duration) -> Option<T> {
+        let items = self.items.lock().expect("queue poisoned");
+        let (mut items, _) = self.ready.wait_timeout_while(items, timeout, |items| items.is_empty() && !self.is_closed()).expect("queue poisoned");
+        items.pop_front()
+    }
+
+    /// tells whoever pops that the receiver is gone
+    pub fn close(&self) {
+        // under the lock so a pop between checking closed and waiting can't miss the wake up
+        let items = self.items.lock().expect("queue poisoned");
+        self.closed.store(true, Ordering::Relaxed);
+        drop(items);
+        self.ready.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[test]
+    fn full_drops_oldest() {
+        let queue = Queue::new(3);
+        let dropped: Vec<_> = (0..4).filter_map(|i| queue.push(i)).collect();
+        assert_eq!(dropped, [0]);
+        let popped: Vec<_> = std::iter::from_fn(|| queue.pop(Duration::ZERO)).collect();
+        assert_eq!(popped, [1, 2, 3]);
+    }
+
+    #[test]
+    fn close_wakes_pop() {
+        let queue = Arc::new(Queue::<u32>::new(1));
+        let popper = {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                let start = Instant::now();
+                (queue.pop(Duration::from_secs(60)), start.elapsed())
+            })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        queue.close();
+        let (popped, waited) = popper.join().unwrap();
+        assert_eq!(popped, None);
+        assert!(waited < Duration::from_secs(10), "waited {:?}", waited);
+        assert!(queue.is_closed());
+    }
+}
@@ -0,0 +1,219 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! --record writes every datagram we receive to a file, --replay feeds one back through the same parsing and response
+//! logic offline, so a user's problem traffic can be reproduced exactly
+//!
+//! the file is MAGIC then one record per datagram, all big endian: the 64 bit NTP receive timestamp, 0 if the clock
+//! couldn't be read, the source address family as 4 or 6, its 4 or 16 address bytes and 2 port bytes, 1 if the datagram
+//! was too long to receive whole or else 0, then a 2 byte length and as much of the datagram as we received
+
+use crate::packet::{MockClock, NtpError, NtpPacket, NtpTimestamp, MAX_PACKET_LEN};
+use crate::{Config, NtpServer};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+const MAGIC: &[u8; 8] = b"KISSREC1";
+
+/// one received datagram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recorded {
+    /// None if the clock couldn't be read
+    pub local_ts: Option<NtpTimestamp>,
+    pub remote_addr: SocketAddr,
+    /// only the first MAX_PACKET_LEN bytes of a truncated datagram
+    pub buf: Vec<u8>,
+    pub truncated: bool,
+}
+
+impl Recorded {
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&self.local_ts.map_or(0, |ts| ts.ts).to_be_bytes())?;
+        match self.remote_addr.ip() {
+            IpAddr::V4(ip) => {
+                out.write_all(&[4])?;
+                out.write_all(&ip.octets())?;
+            }
+            IpAddr::V6(ip) => {
+                out.write_all(&[6])?;
+                out.write_all(&ip.octets())?;
+            }
+        }
+        out.write_all(&self.remote_addr.port().to_be_bytes())?;
+        out.write_all(&[self.truncated as u8])?;
+        out.write_all(&(self.buf.len() as u16).to_be_bytes())?;
+        out.write_all(&self.buf)
+    }
+
+    /// the next record, None at a clean end of the file
+    fn read(input: &mut impl Read) -> io::Result<Option<Recorded>> {
+        let mut ts = [0u8; 8];
+        match input.read_exact(&mut ts) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            ret => ret?,
+        }
+        let mut family = [0u8; 1];
+        input.read_exact(&mut family)?;
+        let ip = match family[0] {
+            4 => {
+                let mut octets = [0u8; 4];
+                input.read_exact(&mut octets)?;
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            6 => {
+                let mut octets = [0u8; 16];
+                input.read_exact(&mut octets)?;
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            family => return Err(io::Error::new(ErrorKind::InvalidData, format!("unknown address family {}", family))),
+        };
+        let mut port = [0u8; 2];
+        input.read_exact(&mut port)?;
+        let mut truncated = [0u8; 1];
+        input.read_exact(&mut truncated)?;
+        let mut len = [0u8; 2];
+        input.read_exact(&mut len)?;
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        input.read_exact(&mut buf)?;
+        Ok(Some(Recorded {
+            local_ts: Some(NtpTimestamp { ts: u64::from_be_bytes(ts) }).filter(|ts| ts.ts != 0),
+            remote_addr: SocketAddr::new(ip, u16::from_be_bytes(port)),
+            buf,
+            truncated: truncated[0] != 0,
+        }))
+    }
+}
+
+/// what a received datagram makes for NtpServer::handle, live or replayed
+pub fn request(buf: &[u8], remote_addr: SocketAddr, local_ts: Option<NtpTimestamp>, truncated: bool) -> Result<NtpPacket, NtpError> {
+    if truncated {
+        return Err(NtpError::PacketTruncated);
+    }
+    NtpPacket::parse(buf, remote_addr, local_ts.ok_or(NtpError::ClockBeforeEpoch)?)
+}
+
+/// --record, shared by every server thread
+pub struct Recorder {
+    path: String,
+    file: Mutex<BufWriter<File>>,
+    /// so a full disk is logged once rather than for every packet
+    failed: AtomicBool,
+}
+
+impl Recorder {
+    /// truncates path and starts a new recording
+    pub fn create(path: &str) -> io::Result<Recorder> {
+        let mut file = BufWriter::new(File::create(path).map_err(|e| io::Error::new(e.kind(), format!("could not create {}: {}", path, e)))?);
+        file.write_all(MAGIC)?;
+        file.flush()?;
+        Ok(Recorder {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// appends a datagram, flushed right away so a crash doesn't lose the packets that caused it
+    pub fn record(&self, buf: &[u8], remote_addr: SocketAddr, local_ts: Option<NtpTimestamp>, truncated: bool) {
+        let recorded = Recorded {
+            local_ts,
+            remote_addr,
+            buf: buf[..buf.len().min(MAX_PACKET_LEN)].to_vec(),
+            truncated,
+        };
+        let mut file = self.file.lock().expect("recorder poisoned");
+        if let Err(e) = recorded.write(&mut *file).and_then(|_| file.flush()) {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                error!("could not record to {}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+/// every datagram in a recording
+pub fn read(path: &str) -> io::Result<Vec<Recorded>> {
+    let mut input = BufReader::new(File::open(path).map_err(|e| io::Error::new(e.kind(), format!("could not open {}: {}", path, e)))?);
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("{} is not a kiss-ntpd recording", path)));
+    }
+    let mut ret = Vec::new();
+    while let Some(recorded) = Recorded::read(&mut input)? {
+        ret.push(recorded);
+    }
+    Ok(ret)
+}
+
+/// feeds every datagram in path through a server with no socket, its clock set to when each was received, printing
+/// what we would have sent back, so the same recording always prints the same
+pub fn replay(path: &str, config: Config) -> io::Result<()> {
+    let records = read(path)?;
+    let start = records.iter().find_map(|recorded| recorded.local_ts).unwrap_or_else(NtpTimestamp::zero);
+    let clock = Arc::new(MockClock::new(start));
+    let mut server = NtpServer::offline(config, clock.clone(), start);
+    for recorded in records {
+        // a datagram the clock couldn't be read for keeps the last time, it's never served
+        if let Some(local_ts) = recorded.local_ts {
+            clock.set(local_ts);
+        }
+        let request = request(&recorded.buf, recorded.remote_addr, recorded.local_ts, recorded.truncated);
+        match server.handle(request, Some(recorded.remote_addr)) {
+            Some(response) => println!("{} bytes from {}: {:?}", recorded.buf.len(), recorded.remote_addr, response),
+            None => println!("{} bytes from {}: no response", recorded.buf.len(), recorded.remote_addr),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let records = [
+            Recorded {
+                local_ts: Some(NtpTimestamp { ts: 0xe000_0000_8000_0000 }),
+                remote_addr: "192.0.2.1:40000".parse().unwrap(),
+                buf: vec![0x23; 48],
+                truncated: false,
+            },
+            Recorded {
+                local_ts: None,
+                remote_addr: "[2001:db8::1]:123".parse().unwrap(),
+                buf: vec![0; MAX_PACKET_LEN],
+                truncated: true,
+            },
+        ];
+        let mut buf = Vec::new();
+        for recorded in &records {
+            recorded.write(&mut buf).unwrap();
+        }
+        let mut input = &buf[..];
+        for recorded in &records {
+            assert_eq!(Recorded::read(&mut input).unwrap().as_ref(), Some(recorded));
+        }
+        assert_eq!(Recorded::read(&mut input).unwrap(), None);
+        // cut off partway through a record is an error, not the end
+        assert!(Recorded::read(&mut &buf[..10]).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+#[cfg(unix)]
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::Relaxed)
+}
+
+/// the same as SIGTERM, for when we decide to stop on our own
+pub fn request_shutdown() {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+/// sleeps for interval in short steps, so a shutdown request cuts it short
+pub fn sleep(interval: Duration) {
+    let next = Instant::now() + interval;
+    while !shutdown_requested() && Instant::now() < next {
+        std::thread::sleep(Duration::from_secs(1).min(next.saturating_duration_since(Instant::now())));
+    }
+}
+
+/// whether SIGHUP arrived since the last call
+#[cfg(unix)]
+pub fn take_reload_request() -> bool {
+    RELOAD.swap(false, Ordering::Relaxed)
+}
+
+#[cfg(unix)]
+extern "C" fn handle_signal(signal: libc::c_int) {
+    // only async-signal-safe things allowed in here, which an atomic store is
+    if signal == libc::SIGHUP {
+        RELOAD.store(true, Ordering::Relaxed);
+    } else {
+        SHUTDOWN.store(true, Ordering::Relaxed);
+    }
+}
+
+/// SIGINT and SIGTERM request a shutdown, which the receive loops notice on their next read timeout, SIGHUP a config reload
+#[cfg(unix)]
+pub fn install_handlers() -> std::io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // no SA_RESTART so a blocked recv_from returns EINTR right away
+            action.sa_flags = 0;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install_handlers() -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,169 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+/// whether set_reuse_port exists on this platform
+pub const REUSEPORT_SUPPORTED: bool = cfg!(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))));
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))))]
+fn set_reuse_port(socket: &Socket) -> Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin")))))]
+fn set_reuse_port(_: &Socket) -> Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "SO_REUSEPORT is not supported on this platform"))
+}
+
+/// SO_BINDTODEVICE, only what arrives on that interface reaches the socket whatever address it's bound to
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, device: &str) -> Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_: &Socket, _: &str) -> Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "SO_BINDTODEVICE is only supported on linux"))
+}
+
+/// the IANA assigned NTP multicast groups, IPv6 at site-local scope
+pub const MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 1);
+pub const MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0x101);
+
+/// the NTP port, used when an address doesn't say
+pub const DEFAULT_PORT: u16 = 123;
+
+/// appends DEFAULT_PORT to an address without one, a bare IP, a hostname, or a bracketed IPv6 address like [::1]
+pub fn with_default_port(addr: &str) -> String {
+    if addr.parse::<SocketAddr>().is_ok() {
+        addr.to_owned()
+    } else if let Ok(ip) = addr.parse::<IpAddr>() {
+        // this takes care of the brackets for bare IPv6
+        SocketAddr::new(ip, DEFAULT_PORT).to_string()
+    } else if (addr.starts_with('[') && addr.ends_with(']')) || !addr.contains(':') {
+        format!("{}:{}", addr, DEFAULT_PORT)
+    } else {
+        addr.to_owned()
+    }
+}
+
+/// the first address addr resolves to
+pub fn resolve(addr: &str) -> Result<SocketAddr> {
+    resolve_all(addr).map(|addrs| addrs[0])
+}
+
+/// every distinct address addr resolves to, in resolver order, never empty
+pub fn resolve_all(addr: &str) -> Result<Vec<SocketAddr>> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for resolved in addr.to_socket_addrs()? {
+        if !addrs.contains(&resolved) {
+            addrs.push(resolved);
+        }
+    }
+    if addrs.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, format!("{} did not resolve to any address", addr)));
+    }
+    Ok(addrs)
+}
+
+/// binds a UDP socket, with reuseport each one bound to the same address gets its own share of the packets,
+/// with device it only hears from that interface
+pub fn bind(addr: &SocketAddr, reuseport: bool, device: Option<&str>) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if reuseport {
+        set_reuse_port(&socket)?;
+    }
+    if let Some(device) = device {
+        bind_device(&socket, device).map_err(|e| Error::new(e.kind(), format!("could not bind to device {}: {}", device, e)))?;
+    }
+    socket.bind(&(*addr).into())?;
+    Ok(socket.into())
+}
+
+/// sets SO_RCVBUF and SO_SNDBUF where asked, returning what the kernel actually gave us, linux doubles the request
+/// and caps it at net.core.rmem_max and wmem_max
+pub fn set_buffer_sizes(socket: &UdpSocket, rcvbuf: Option<usize>, sndbuf: Option<usize>) -> Result<(usize, usize)> {
+    let socket = SockRef::from(socket);
+    if let Some(size) = rcvbuf {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = sndbuf {
+        socket.set_send_buffer_size(size)?;
+    }
+    Ok((socket.recv_buffer_size()?, socket.send_buffer_size()?))
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "illumos",
+))]
+fn set_tclass(socket: &SockRef, tclass: u32) -> Result<u32> {
+    socket.set_tclass_v6(tclass)?;
+    socket.tclass_v6()
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "illumos",
+)))]
+fn set_tclass(_: &SockRef, _: u32) -> Result<u32> {
+    Err(Error::new(ErrorKind::Unsupported, "IPV6_TCLASS is not supported on this platform"))
+}
+
+/// marks everything the socket sends with dscp in the top 6 bits of IP_TOS or IPV6_TCLASS, returning the whole byte
+/// the kernel reports back
+pub fn set_dscp(socket: &UdpSocket, dscp: u8) -> Result<u32> {
+    let tos = (dscp as u32) << 2;
+    let local_addr = socket.local_addr()?;
+    let socket = SockRef::from(socket);
+    if local_addr.is_ipv4() {
+        socket.set_tos_v4(tos)?;
+        socket.tos_v4()
+    } else {
+        set_tclass(&socket, tos)
+    }
+}
+
+/// joins the NTP multicast group for the socket's address family on the default interface, returning the group and our port
+pub fn join_multicast(socket: &UdpSocket) -> Result<SocketAddr> {
+    let local_addr = socket.local_addr()?;
+    if local_addr.is_ipv4() {
+        socket.join_multicast_v4(&MULTICAST_V4, &Ipv4Addr::UNSPECIFIED)?;
+        Ok(SocketAddr::new(MULTICAST_V4.into(), local_addr.port()))
+    } else {
+        socket.join_multicast_v6(&MULTICAST_V6, 0)?;
+        Ok(SocketAddr::new(MULTICAST_V6.into(), local_addr.port()))
+    }
+}
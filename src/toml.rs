@@ -0,0 +1,306 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! just enough TOML for a flat config file: top level `key = value` pairs where a value is a string,
+//! integer, float, boolean or an array of those; anything else is an error rather than silently ignored
+
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Array(a) => {
+                let items: Vec<String> = a.iter().map(|v| v.to_string()).collect();
+                write!(f, "{}", items.join(","))
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn err<T>(&self, msg: &str) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line, msg))
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(' ' | '\t') = self.chars.peek() {
+            self.next();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.chars.peek() == Some(&'#') {
+            while !matches!(self.chars.peek(), None | Some('\n')) {
+                self.next();
+            }
+        }
+    }
+
+    /// whitespace, newlines and comments
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.chars.peek() {
+                Some('\n' | '\r') => {
+                    self.next();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        if self.chars.peek() == Some(&'"') {
+            self.next();
+            return self.basic_string();
+        }
+        let mut key = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                break;
+            }
+            key.push(c);
+            self.next();
+        }
+        if key.is_empty() {
+            return self.err("expected a key");
+        }
+        Ok(key)
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        let mut ret = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(ret),
+                Some('\\') => match self.next() {
+                    Some('"') => ret.push('"'),
+                    Some('\\') => ret.push('\\'),
+                    Some('n') => ret.push('\n'),
+                    Some('t') => ret.push('\t'),
+                    Some('r') => ret.push('\r'),
+                    Some('u') => {
+                        let hex: String = (0..4).filter_map(|_| self.next()).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) => ret.push(c),
+                            None => return self.err("invalid unicode escape"),
+                        }
+                    }
+                    _ => return self.err("invalid escape in string"),
+                },
+                Some('\n') | None => return self.err("unterminated string"),
+                Some(c) => ret.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        let mut ret = String::new();
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(ret),
+                Some('\n') | None => return self.err("unterminated string"),
+                Some(c) => ret.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        let mut ret = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.chars.peek() == Some(&']') {
+                self.next();
+                return Ok(Value::Array(ret));
+            }
+            ret.push(self.value()?);
+            self.skip_blank();
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(ret)),
+                _ => return self.err("expected , or ] in array"),
+            }
+        }
+    }
+
+    fn bare(&mut self) -> Result<Value, String> {
+        let mut word = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_alphanumeric() || "+-._".contains(c)) {
+                break;
+            }
+            word.push(c);
+            self.next();
+        }
+        match word.as_str() {
+            "true" => return Ok(Value::Boolean(true)),
+            "false" => return Ok(Value::Boolean(false)),
+            _ => {}
+        }
+        let number = word.replace('_', "");
+        if let Ok(i) = number.parse() {
+            Ok(Value::Integer(i))
+        } else if let (true, Ok(n)) = (word.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-'), number.parse()) {
+            Ok(Value::Float(n))
+        } else {
+            self.err(&format!("unsupported value '{}'", word))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.chars.peek() {
+            Some('"') => {
+                self.next();
+                self.basic_string().map(Value::String)
+            }
+            Some('\'') => {
+                self.next();
+                self.literal_string().map(Value::String)
+            }
+            Some('[') => {
+                self.next();
+                self.array()
+            }
+            _ => self.bare(),
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<HashMap<String, Value>, String> {
+    let mut parser = Parser {
+        chars: input.chars().peekable(),
+        line: 1,
+    };
+    let mut ret = HashMap::new();
+    loop {
+        parser.skip_blank();
+        match parser.chars.peek() {
+            None => return Ok(ret),
+            Some('[') => return parser.err("tables are not supported"),
+            _ => {}
+        }
+        let key = parser.key()?;
+        parser.skip_spaces();
+        if parser.next() != Some('=') {
+            return parser.err("expected =");
+        }
+        parser.skip_spaces();
+        let value = parser.value()?;
+        parser.skip_spaces();
+        parser.skip_comment();
+        if !matches!(parser.chars.peek(), None | Some('\n' | '\r')) {
+            return parser.err("expected end of line");
+        }
+        if ret.insert(key.clone(), value).is_some() {
+            return parser.err(&format!("duplicate key {}", key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(toml: &str) -> Result<Value, String> {
+        parse(&format!("key = {}", toml)).map(|mut map| map.remove("key").unwrap())
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(value(r#""a \"b\"\t\\ \u00e9""#), Ok(Value::String("a \"b\"\t\\ \u{e9}".to_owned())));
+        // no escapes at all in a literal string
+        assert_eq!(value(r"'C:\path\n'"), Ok(Value::String(r"C:\path\n".to_owned())));
+        assert!(value(r#""\q""#).is_err());
+        assert!(value(r#""\uZZZZ""#).is_err());
+        assert!(value("\"unterminated\n\"").is_err());
+        assert!(value("'unterminated").is_err());
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(value("123"), Ok(Value::Integer(123)));
+        assert_eq!(value("-1_000"), Ok(Value::Integer(-1000)));
+        assert_eq!(value("0.5"), Ok(Value::Float(0.5)));
+        assert_eq!(value("1e3"), Ok(Value::Float(1000.0)));
+        assert_eq!(value("-2.5"), Ok(Value::Float(-2.5)));
+        assert_eq!(value("true"), Ok(Value::Boolean(true)));
+        // a bare word is no string
+        assert!(value("inf").is_err());
+        assert!(value("localhost").is_err());
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!(
+            value("[ \"a\", 'b' , 3,\n  # a comment\n  [true], ]"),
+            Ok(Value::Array(vec![
+                Value::String("a".to_owned()),
+                Value::String("b".to_owned()),
+                Value::Integer(3),
+                Value::Array(vec![Value::Boolean(true)]),
+            ]))
+        );
+        assert_eq!(value("[]"), Ok(Value::Array(Vec::new())));
+        assert!(value("[1 2]").is_err());
+        assert!(value("[1,").is_err());
+    }
+
+    #[test]
+    fn documents() {
+        let map = parse("# comment\n\nstratum = 2 # trailing\r\n\"quoted-key\" = 'x'\nbind = [\"[::]:123\"]\n").unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map["stratum"], Value::Integer(2));
+        assert_eq!(map["quoted-key"], Value::String("x".to_owned()));
+        assert_eq!(map["bind"].to_string(), "[::]:123");
+        assert_eq!(parse("a = 1\nb = 2\na = 3"), Err("line 3: duplicate key a".to_owned()));
+        assert_eq!(parse("a = 1\n[table]\n"), Err("line 2: tables are not supported".to_owned()));
+        assert_eq!(parse("a = 1 2"), Err("line 1: expected end of line".to_owned()));
+        assert_eq!(parse("a 1"), Err("line 1: expected =".to_owned()));
+        assert!(parse("= 1").is_err());
+    }
+}
@@ -0,0 +1,389 @@
+/*
+kiss-ntpd: an NTP server that Keeps It Simple, Stupid
+Copyright (C) 2021  Travis Burtrum
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! a minimal NTP client, polling one upstream server and keeping the offset of the best recent sample,
+//! or polling --peer machines in symmetric active mode and meeting them halfway
+
+use crate::packet::{NtpError, NtpFracValue, NtpPacket, NtpTimestamp, SystemClock};
+use crate::signal;
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// samples with a round trip longer than this are thrown away
+const MAX_DELAY: f64 = 1.0;
+/// and so are ones claiming our clock is this far off, ntpd's panic threshold
+const MAX_OFFSET: f64 = 1000.0;
+/// the clock filter picks the lowest delay sample of the last this many
+const FILTER_SIZE: usize = 8;
+/// how fast we assume our clock drifts once we stop hearing from upstream, 15 ppm like RFC 5905
+const PHI: f64 = 15e-6;
+/// seconds, what we advertise when the clock can't be trusted at all, RFC 5905's MAXDISP
+pub const MAX_DISPERSION: f64 = 16.0;
+/// seconds, an offset moving further than this in one sample is a step, ntpd's step threshold
+const STEP_THRESHOLD: f64 = 0.128;
+
+/// whether our time source is ready, while it isn't we send ntpd's INIT or STEP refid at stratum 0 with the alarm leap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceState {
+    /// the system clock alone, or a source we've had a sample from
+    Ready,
+    /// waiting for the first sample from --upstream, --peer, --pps or --gps
+    Init,
+    /// the last sample moved the offset by more than STEP_THRESHOLD, until another confirms it
+    Step,
+}
+
+/// what we know about where our time comes from, shared by every server thread
+#[derive(Debug, Clone, Copy)]
+pub struct Reference {
+    /// when the clock was last set from a real reference, process start when there's none
+    pub ref_ts: NtpTimestamp,
+    /// added to the system clock, in 2^-32 seconds
+    pub offset: i64,
+    pub stratum: u8,
+    pub ref_id: [u8; 4],
+    /// seconds, the round trip to the primary reference through upstream, --root-delay without one
+    pub root_delay: f64,
+    /// seconds, how far off we could be as of ref_ts
+    pub root_dispersion: f64,
+    /// whether we've had a good sample from upstream
+    pub synced: bool,
+    /// whether the kernel says the system clock isn't synchronized, only with --check-sync
+    pub clock_unsynchronized: bool,
+    pub state: SourceState,
+}
+
+impl Reference {
+    /// seconds, root_dispersion grown by how long it's been since we last heard from upstream, or the maximum when the clock is unsynchronized
+    pub fn root_dispersion(&self, now: NtpTimestamp) -> f64 {
+        if self.clock_unsynchronized {
+            return MAX_DISPERSION;
+        }
+        if !self.synced {
+            return self.root_dispersion;
+        }
+        self.root_dispersion + PHI * secs(now.ts.wrapping_sub(self.ref_ts.ts) as i64).max(0.0)
+    }
+
+    /// whether a real reference last set the clock longer than max_age ago, never with a max_age of 0,
+    /// and the system clock alone never goes stale
+    pub fn stale(&self, now: NtpTimestamp, max_age: Duration) -> bool {
+        self.synced && !max_age.is_zero() && secs(now.ts.wrapping_sub(self.ref_ts.ts) as i64) > max_age.as_secs_f64()
+    }
+
+    /// the stratum and refid to send, 0 and INIT or STEP while our source isn't ready
+    pub fn advertised(&self) -> (u8, [u8; 4]) {
+        match self.state {
+            SourceState::Ready => (self.stratum, self.ref_id),
+            SourceState::Init => (0, *b"INIT"),
+            SourceState::Step => (0, *b"STEP"),
+        }
+    }
+
+    /// a new offset from upstream or peers, one that jumps from the offset we were already serving is a step
+    pub fn set_offset(&mut self, offset: i64) {
+        self.state = if self.synced && secs(offset.wrapping_sub(self.offset)).abs() > STEP_THRESHOLD {
+            SourceState::Step
+        } else {
+            SourceState::Ready
+        };
+        self.offset = offset;
+    }
+
+    #[cfg(unix)]
+    /// we are a primary server, the system clock plus offset was ref_id's time at ref_ts
+    pub fn set_primary(&mut self, ref_id: [u8; 4], offset: i64, ref_ts: NtpTimestamp, root_dispersion: f64) {
+        self.offset = offset;
+        self.ref_ts = ref_ts;
+        self.stratum = 1;
+        self.ref_id = ref_id;
+        self.root_delay = 0.0;
+        self.root_dispersion = root_dispersion;
+        self.synced = true;
+        self.state = SourceState::Ready;
+    }
+
+    #[cfg(unix)]
+    /// our reference is gone, back to serving the system clock as configured in system
+    pub fn set_system_clock(&mut self, system: &Reference) {
+        self.offset = 0;
+        self.stratum = system.stratum;
+        self.ref_id = system.ref_id;
+        self.root_delay = system.root_delay;
+        self.root_dispersion = system.root_dispersion;
+        self.synced = false;
+    }
+}
+
+/// 2^-32 seconds to seconds
+pub fn secs(fixed: i64) -> f64 {
+    fixed as f64 / 4294967296.0
+}
+
+/// one measurement against the upstream server
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// 2^-32 seconds
+    offset: i64,
+    delay: i64,
+    /// our receive time of the reply
+    dest_ts: NtpTimestamp,
+    stratum: u8,
+    /// seconds, upstream's own root delay and dispersion plus this hop
+    root_delay: f64,
+    root_dispersion: f64,
+}
+
+/// sends a client request to server and waits for the matching reply, until the socket's read timeout
+pub fn exchange(socket: &UdpSocket, server: SocketAddr) -> Result<(NtpPacket, NtpPacket), NtpError> {
+    exchange_mode(socket, server, 3)
+}
+
+/// exchange with a mode 3 client request answered in mode 4, or a mode 1 symmetric active one answered in mode 2
+pub fn exchange_mode(socket: &UdpSocket, server: SocketAddr, mode: u8) -> Result<(NtpPacket, NtpPacket), NtpError> {
+    let reply_mode = if mode == 1 { 2 } else { 4 };
+    let request = NtpPacket {
+        remote_addr: server,
+        local_ts: NtpTimestamp::zero(),
+        leap: 0,
+        version: 4,
+        mode,
+        stratum: 0,
+        poll: 0,
+        precision: 0,
+        delay: NtpFracValue::zero(),
+        dispersion: NtpFracValue::zero(),
+        ref_id: 0,
+        ref_ts: NtpTimestamp::zero(),
+        orig_ts: NtpTimestamp::zero(),
+        rx_ts: NtpTimestamp::zero(),
+        tx_ts: NtpTimestamp::now()?,
+        extensions: Vec::new(),
+        mac: None,
+    };
+    request.send(socket)?;
+    let reply = loop {
+        let reply = NtpPacket::receive(socket, &SystemClock)?;
+        // anything else is stray or spoofed, keep waiting for the real one until the read times out
+        if reply.remote_addr == server && reply.mode == reply_mode && reply.orig_ts == request.tx_ts {
+            break reply;
+        }
+    };
+    Ok((request, reply))
+}
+
+/// 2^-32 seconds, the offset and round trip delay from the four timestamps: our transmit, their receive, their transmit, our receive
+pub fn offset_delay(request: &NtpPacket, reply: &NtpPacket) -> (i64, i64) {
+    let (t1, t2, t3, t4) = (request.tx_ts.ts, reply.rx_ts.ts, reply.tx_ts.ts, reply.local_ts.ts);
+    // wrapping differences so this keeps working across era boundaries
+    let (d1, d2) = (t2.wrapping_sub(t1) as i64, t3.wrapping_sub(t4) as i64);
+    let delay = t4.wrapping_sub(t1).wrapping_sub(t3.wrapping_sub(t2)) as i64;
+    (d1 / 2 + d2 / 2, delay)
+}
+
+/// one exchange, refusing replies that don't carry usable time
+fn query(socket: &UdpSocket, server: SocketAddr, mode: u8, precision: i8) -> Result<Sample, NtpError> {
+    let (request, reply) = exchange_mode(socket, server, mode)?;
+    if reply.leap == 3 || reply.stratum == 0 || reply.stratum > 15 || reply.tx_ts == NtpTimestamp::zero() {
+        return Err(NtpError::Unsynchronized(reply.stratum, reply.ref_id));
+    }
+    let (offset, delay) = offset_delay(&request, &reply);
+    Ok(Sample {
+        offset,
+        delay,
+        dest_ts: reply.local_ts,
+        stratum: reply.stratum,
+        root_delay: reply.delay.to_secs() + secs(delay).max(0.0),
+        // both clocks' precision and the drift over the round trip
+        root_dispersion: reply.dispersion.to_secs() + 2f64.powi(reply.precision as i32) + 2f64.powi(precision as i32) + PHI * secs(delay).max(0.0),
+    })
+}
+
+/// what we advertise as our reference id, the upstream IPv4 address, or the first 4 bytes of the MD5 of an IPv6 one
+fn ref_id(server: SocketAddr) -> [u8; 4] {
+    use md5::{Digest, Md5};
+    match server.ip() {
+        IpAddr::V4(ip) => ip.octets(),
+        IpAddr::V6(ip) => {
+            let digest = Md5::digest(ip.octets());
+            [digest[0], digest[1], digest[2], digest[3]]
+        }
+    }
+}
+
+/// an ephemeral port of the right family to talk to server from, giving up on a reply after 2 seconds
+pub fn client_socket(server: SocketAddr) -> std::io::Result<UdpSocket> {
+    let local: IpAddr = if server.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() };
+    let socket = UdpSocket::bind((local, 0))?;
+    socket.set_read_timeout(Some(Duration::from_secs(2)))?;
+    Ok(socket)
+}
+
+/// polls server every interval until shutdown, updating reference from the best of the recent good samples
+pub fn run(server: SocketAddr, interval: Duration, reference: Arc<Mutex<Reference>>, precision: i8, debug: bool) {
+    reference.lock().expect("reference poisoned").state = SourceState::Init;
+    let socket = match client_socket(server) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("could not open upstream socket: {}", e);
+            return;
+        }
+    };
+    let mut samples: VecDeque<Sample> = VecDeque::with_capacity(FILTER_SIZE);
+    while !signal::shutdown_requested() {
+        match query(&socket, server, 3, precision) {
+            Ok(sample) if !usable(&sample) => {
+                warn!("ignoring sample from upstream {}: offset {:.6}s delay {:.6}s", server, secs(sample.offset), secs(sample.delay));
+            }
+            Ok(sample) => {
+                if samples.len() == FILTER_SIZE {
+                    samples.pop_front();
+                }
+                samples.push_back(sample);
+                let best = samples.iter().min_by_key(|s| s.delay).copied().unwrap_or(sample);
+                if debug {
+                    debug!(
+                        "upstream {} offset {:.6}s delay {:.6}s, using offset {:.6}s",
+                        server,
+                        secs(sample.offset),
+                        secs(sample.delay),
+                        secs(best.offset)
+                    );
+                }
+                let mut reference = reference.lock().expect("reference poisoned");
+                reference.set_offset(best.offset);
+                if reference.state == SourceState::Step {
+                    warn!("upstream {} stepped the offset to {:.6}s", server, secs(best.offset));
+                }
+                reference.ref_ts = sample.dest_ts.offset(best.offset);
+                reference.stratum = (best.stratum + 1).min(15);
+                reference.ref_id = ref_id(server);
+                reference.root_delay = best.root_delay;
+                reference.root_dispersion = best.root_dispersion;
+                reference.synced = true;
+            }
+            Err(e) if e.is_transient() => warn!("no reply from upstream {}", server),
+            Err(e) => warn!("upstream {}: {}", server, e),
+        }
+        signal::sleep(interval);
+    }
+}
+
+/// not an outlier, a round trip shorter than MAX_DELAY and an offset smaller than MAX_OFFSET
+fn usable(sample: &Sample) -> bool {
+    let delay = secs(sample.delay);
+    (0.0..=MAX_DELAY).contains(&delay) && secs(sample.offset).abs() <= MAX_OFFSET
+}
+
+/// one --peer and what we've heard from it
+struct Peer {
+    addr: SocketAddr,
+    socket: UdpSocket,
+    samples: VecDeque<Sample>,
+    /// like ntpd's reach register, bit 0 is the last poll, set when it brought a usable sample
+    reach: u8,
+}
+
+impl Peer {
+    /// heard from in the last 4 polls
+    fn healthy(&self) -> bool {
+        self.reach & 0x0f != 0
+    }
+
+    /// 2^-32 seconds, the lowest delay offset of the recent samples
+    fn offset(&self) -> Option<i64> {
+        self.samples.iter().min_by_key(|s| s.delay).map(|s| s.offset)
+    }
+}
+
+/// 2^-32 seconds, the mean of our own clock and the peers' offsets from it, so peers doing the same meet in the middle
+fn blend(offsets: &[i64]) -> i64 {
+    let sum: i128 = offsets.iter().map(|&o| o as i128).sum();
+    (sum / (offsets.len() as i128 + 1)) as i64
+}
+
+/// polls every peer in symmetric active mode every interval until shutdown, setting the reference offset
+/// to the blend of the healthy ones, our stratum and refid stay as configured
+pub fn run_peers(addrs: Vec<SocketAddr>, interval: Duration, reference: Arc<Mutex<Reference>>, precision: i8, debug: bool) {
+    reference.lock().expect("reference poisoned").state = SourceState::Init;
+    let mut peers = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        match client_socket(addr) {
+            Ok(socket) => peers.push(Peer {
+                addr,
+                socket,
+                samples: VecDeque::with_capacity(FILTER_SIZE),
+                reach: 0,
+            }),
+            Err(e) => error!("could not open socket for peer {}: {}", addr, e),
+        }
+    }
+    while !signal::shutdown_requested() {
+        let mut dest_ts = None;
+        for peer in &mut peers {
+            peer.reach <<= 1;
+            match query(&peer.socket, peer.addr, 1, precision) {
+                Ok(sample) if !usable(&sample) => {
+                    warn!("ignoring sample from peer {}: offset {:.6}s delay {:.6}s", peer.addr, secs(sample.offset), secs(sample.delay));
+                }
+                Ok(sample) => {
+                    if debug {
+                        debug!("peer {} offset {:.6}s delay {:.6}s", peer.addr, secs(sample.offset), secs(sample.delay));
+                    }
+                    if peer.samples.len() == FILTER_SIZE {
+                        peer.samples.pop_front();
+                    }
+                    peer.samples.push_back(sample);
+                    peer.reach |= 1;
+                    dest_ts = Some(sample.dest_ts);
+                }
+                Err(e) if e.is_transient() => warn!("no reply from peer {}", peer.addr),
+                Err(e) => warn!("peer {}: {}", peer.addr, e),
+            }
+        }
+        let offsets: Vec<i64> = peers.iter().filter(|p| p.healthy()).filter_map(Peer::offset).collect();
+        if let (Some(dest_ts), false) = (dest_ts, offsets.is_empty()) {
+            let offset = blend(&offsets);
+            if debug {
+                debug!("{} of {} peers healthy, using offset {:.6}s", offsets.len(), peers.len(), secs(offset));
+            }
+            let mut reference = reference.lock().expect("reference poisoned");
+            reference.set_offset(offset);
+            if reference.state == SourceState::Step {
+                warn!("peers stepped the offset to {:.6}s", secs(offset));
+            }
+            reference.ref_ts = dest_ts.offset(offset);
+            reference.synced = true;
+        }
+        signal::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blend_counts_our_own_clock() {
+        assert_eq!(blend(&[100]), 50);
+        assert_eq!(blend(&[300, -300, 600]), 150);
+        assert_eq!(blend(&[i64::MAX, i64::MAX]), i64::MAX / 3 * 2);
+    }
+}